@@ -1,4 +1,5 @@
-use proofs::handle_message;
+use proofs::handle_request;
+use state::{AppState, PeerMap};
 use std::{
     collections::HashMap,
     io::Error as IoError,
@@ -6,17 +7,19 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use futures_channel::mpsc::{unbounded, UnboundedSender};
-use futures_util::{stream::TryStreamExt, StreamExt};
+use futures_channel::mpsc::unbounded;
+use futures_util::{future::{self, Either}, pin_mut, stream::TryStreamExt, StreamExt};
 
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::{self, protocol::Message};
+use tokio_tungstenite::tungstenite::protocol::Message;
 
 mod proofs;
 pub mod stacks;
+mod state;
+#[cfg(test)]
+mod tests;
 
 type IoResult<T> = std::io::Result<T>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>;
 //type PeerMap = Arc<Mutex<HashMap<SocketAddr, tokio::sync::mpsc::UnboundedSender<tungstenite::Message>>>>;
 
 #[tokio::main]
@@ -30,12 +33,13 @@ async fn main() -> Result<(), IoError> {
     println!("WebSocket server listening on: {}", addr);
 
     // Initialize routes with the shared state
-    let _http_state = ws_state.clone();
-    let routes = stacks::stacks_routes();
+    let app_state = AppState::from_env(ws_state.clone());
+    let routes = stacks::stacks_routes(app_state.clone());
+    tokio::spawn(stacks::jobs::prune_finished(Arc::downgrade(&app_state.jobs)));
 
     // Start the Warp server for HTTP endpoints concurrently with the WebSocket server
     tokio::select! {
-        _ = run_websocket_server(listener, ws_state.clone()) => {},
+        _ = run_websocket_server(listener, ws_state.clone(), app_state) => {},
         _ = warp::serve(routes).run(([127, 0, 0, 1], 3030)) => {},
     }
 
@@ -44,14 +48,14 @@ async fn main() -> Result<(), IoError> {
 
 
 // WebSocket server function to handle incoming connections
-async fn run_websocket_server(listener: TcpListener, state: PeerMap) -> IoResult<()> {
+async fn run_websocket_server(listener: TcpListener, state: PeerMap, app_state: AppState) -> IoResult<()> {
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_ws_connection(state.clone(), stream, addr));
+        tokio::spawn(handle_ws_connection(state.clone(), app_state.clone(), stream, addr));
     }
     Ok(())
 }
 
-async fn handle_ws_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr) {
+async fn handle_ws_connection(peer_map: PeerMap, app_state: AppState, raw_stream: TcpStream, addr: SocketAddr) {
     println!("Incoming TCP connection from: {}", addr);
 
     let ws_stream = tokio_tungstenite::accept_async(raw_stream)
@@ -59,37 +63,42 @@ async fn handle_ws_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: So
         .expect("Error during the websocket handshake occurred");
     println!("WebSocket connection established: {}", addr);
 
-    let (tx, _rx) = unbounded();
-    peer_map.lock().unwrap().insert(addr, tx);
-
-    let (_outgoing, incoming) = ws_stream.split();
-    //let outgoing = Arc::new(Mutex::new(outgoing));  // Wrap `outgoing` in Arc<Mutex<...>> here
-
-    // Process incoming messages and respond asynchronously
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        let msg_text = msg.to_text().unwrap().to_string();
-        //let peer_map_clone = peer_map.clone();
-        //let outgoing = Arc::clone(&outgoing);  // Clone the Arc for each message
-
-        async move {
-            // Handle the message asynchronously
-            match handle_message(&msg_text).await {
-                Ok(response_message) => {
-                    // Lock outgoing for this async block
-                    //let mut outgoing = outgoing.lock();
-                    // Send the response back to the client
-                    //outgoing.send(Message::Text(response_message)).await?;
-                    eprintln!("response_message: {:?}", response_message);
+    // Everything sent to this peer goes through its channel, so responses can be pushed from
+    // the tasks handling requests as soon as they are ready.
+    let (tx, rx) = unbounded();
+    peer_map.lock().unwrap().insert(addr, tx.clone());
+
+    let (outgoing, incoming) = ws_stream.split();
+
+    // Each request is handled in its own task, so a slow proof doesn't hold up the others;
+    // clients match responses to requests by their request_id.
+    let handle_incoming = incoming.try_for_each(|msg| {
+        if let Message::Text(msg_text) = msg {
+            let app_state = app_state.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let Some(envelope) = handle_request(&msg_text, &app_state, Some(addr)).await else {
+                    return;
+                };
+                match serde_json::to_string(&envelope) {
+                    Ok(response) => {
+                        // the peer may be gone by now, in which case there's no one to tell
+                        let _ = tx.unbounded_send(Message::Text(response));
+                    }
+                    Err(e) => eprintln!("Error serializing response: {}", e),
                 }
-                Err(e) => {
-                    eprintln!("Error handling message: {:?}", e);
-                }
-            }
-
-            // Explicitly annotate the return type to satisfy try_for_each
-            Ok::<(), tungstenite::Error>(())
+            });
         }
+        future::ok(())
     });
 
-    broadcast_incoming.await.expect("Error processing messages");
+    let forward_outgoing = rx.map(Ok).forward(outgoing);
+
+    pin_mut!(handle_incoming, forward_outgoing);
+    if let Either::Left((Err(e), _)) = future::select(handle_incoming, forward_outgoing).await {
+        eprintln!("Error processing messages from {}: {}", addr, e);
+    }
+
+    println!("{} disconnected", addr);
+    peer_map.lock().unwrap().remove(&addr);
 }
@@ -1,11 +1,12 @@
 use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use stacks_voting::{SignatureData, StacksVotingProofVerifier};
+use stacks_voting::{BallotInputs, SignatureData, StacksVotingProofVerifier, VerificationFailure};
 use vdf::{VdfProofGenerator, VdfProofVerifier};  use core::fmt;
 // Import serde_with for handling u128
 use std::result::Result;
-use crate::stacks::{proofs::generate_proof, utils::Transaction};
+use std::net::SocketAddr;
+use crate::{state::AppState, stacks::{jobs::{self, JobStatus, ProofJobRequest, Subscriber}, proofs, sip018::SignatureError, proposals::ProposalError, tally::Tally, storage::StorageError, history::BalanceHistory, utils::decode_hex32, ProofError}};
 
 pub mod vdf;
 pub mod stacks_voting;
@@ -23,15 +24,27 @@ impl From<serde_json::Error> for Error {
     }
 }
 impl From<warp::Rejection> for Error {
-    fn from(_rejection: warp::Rejection) -> Self {
-        Error::ProofGenerationError("Warp rejection occurred".to_string())
+    fn from(rejection: warp::Rejection) -> Self {
+        // keep the reason the request was rejected, so WebSocket clients get it too
+        let message = if let Some(e) = rejection.find::<ProofError>() {
+            e.to_string()
+        } else if let Some(e) = rejection.find::<SignatureError>() {
+            e.to_string()
+        } else if let Some(e) = rejection.find::<ProposalError>() {
+            e.to_string()
+        } else if let Some(e) = rejection.find::<StorageError>() {
+            e.to_string()
+        } else {
+            "Warp rejection occurred".to_string()
+        };
+        Error::ProofGenerationError(message)
     }
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::ProofGenerationError(msg) => write!(f, "Proof generation error: {}", msg),
-            _ => write!(f, "Proof generation error"),
+            Error::SerializationError(err) => write!(f, "Serialization error: {}", err),
         }
     }
 }
@@ -39,8 +52,16 @@ impl fmt::Display for Error {
 pub trait ProofGenerator {
     fn generate_proof(start: u128, n: usize) -> (Vec<u8>, u128);
 }
+// Stages a voting proof goes through once the transactions are known; generators report
+// each one as they enter it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProofStage {
+    BuildingTrace,
+    Proving,
+}
+
 pub trait VotingProofGenerator {
-    fn generate_proof(data: SignatureData, transactions: Vec<Transaction>) -> (Vec<u8>, u128);
+    fn generate_proof(data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error>;
 }
 
 
@@ -52,7 +73,7 @@ pub trait ProofVerifier {
 #[serde(tag = "message_type")]  // Top-level message type
 enum ApplicationMessage {
     ProofGeneration(ProofGenerationMessage),
-    ProofVerification(ProofVerificationMessage),
+    ProofVerification(Box<ProofVerificationMessage>),
     Other(String),  // Placeholder for future message types
 }
 
@@ -64,17 +85,41 @@ pub struct VerificationResponse {
     error: String
 }
 
+impl From<Result<(), VerificationFailure>> for VerificationResponse {
+    fn from(result: Result<(), VerificationFailure>) -> Self {
+        match result {
+            Ok(()) => VerificationResponse { ok: true, error: "None".to_string() },
+            Err(failure) => VerificationResponse { ok: false, error: failure.to_string() },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 
 pub enum ApplicationResponseMessage {
     ProofGenerationResponse(ProofResponse),
     ProofVerificationResponse(VerificationResponse),
+    // state of a proof job, sent when it is submitted and on every change after that
+    ProofJobUpdate(JobStatus),
+    // totals of a proposal, sent to every peer when a ballot is counted
+    TallyUpdate(Tally),
+    Error { message: String },
+}
+
+// Every WebSocket response is wrapped in an envelope echoing the `request_id` the client sent
+// with the request, so responses to concurrent requests can be told apart.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseEnvelope {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub response: ApplicationResponseMessage,
 }
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "proof_type")]
-
+#[allow(clippy::enum_variant_names)] // variant names double as the `proof_type` tags
 enum ProofGenerationMessage {
     VdfProof {
         #[serde_as(as = "DisplayFromStr")]
@@ -82,10 +127,28 @@ enum ProofGenerationMessage {
         n: usize,
     },
     StacksVotingProof {
-        signature_data: SignatureData
+        signature_data: Box<SignatureData>
+    },
+    StacksThresholdProof {
+        signature_data: Box<SignatureData>,
+        #[serde_as(as = "DisplayFromStr")]
+        threshold: u128,
+    },
+    StacksLockedProof {
+        signature_data: Box<SignatureData>,
+    },
+    StacksTokenProof {
+        signature_data: Box<SignatureData>,
+    },
+    StacksNftProof {
+        signature_data: Box<SignatureData>,
+        min_count: u64,
+    },
+    StacksQuadraticProof {
+        signature_data: Box<SignatureData>,
     },
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "proof_type")]  // Nested message type for proof generation
 pub enum ProofResponse {
     ProofError {
@@ -97,6 +160,73 @@ pub enum ProofResponse {
     },
     StacksVotingProof {
         result: String,
+        // hex encoded index block hash of the block the balance was read at
+        block_hash: String,
+        // hex encoded commitment to the ledger the balance was computed over
+        history: String,
+        // hex encoded commitment to the proposal and vote
+        commitment: String,
+        // hex encoded secret the commitment is blinded with; it is only ever sent to the voter,
+        // who needs it to open the commitment and to fetch the proof from their receipt
+        blinding: String,
+        // hex encoded nullifier the ballot was recorded under
+        nullifier: String,
+        proof: String
+    },
+    StacksThresholdProof {
+        threshold: String,
+        block_hash: String,
+        history: String,
+        commitment: String,
+        blinding: String,
+        nullifier: String,
+        proof: String
+    },
+    StacksLockedProof {
+        // micro-STX locked by stacking at the proof height
+        locked: String,
+        // burnchain height the STX unlock at
+        unlock_height: u64,
+        block_hash: String,
+        history: String,
+        commitment: String,
+        blinding: String,
+        nullifier: String,
+        proof: String
+    },
+    StacksTokenProof {
+        // balance in the token's base unit
+        balance: String,
+        asset_identifier: String,
+        // hex encoded hash of the asset identifier, as bound to the proof
+        asset_hash: String,
+        block_hash: String,
+        history: String,
+        commitment: String,
+        blinding: String,
+        nullifier: String,
+        proof: String
+    },
+    StacksNftProof {
+        // the voter owned at least this many tokens of the collection
+        min_count: u64,
+        asset_identifier: String,
+        asset_hash: String,
+        block_hash: String,
+        history: String,
+        commitment: String,
+        blinding: String,
+        nullifier: String,
+        proof: String
+    },
+    StacksQuadraticProof {
+        // integer square root of the micro-STX balance
+        weight: String,
+        block_hash: String,
+        history: String,
+        commitment: String,
+        blinding: String,
+        nullifier: String,
         proof: String
     },
 }
@@ -104,6 +234,7 @@ pub enum ProofResponse {
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "proof_type")]  // Nested message type for proof verification
+#[allow(clippy::enum_variant_names)]
 enum ProofVerificationMessage {
     VdfProof {
         #[serde_as(as = "DisplayFromStr")]
@@ -115,16 +246,117 @@ enum ProofVerificationMessage {
     },
     StacksVotingProof {
         #[serde_as(as = "DisplayFromStr")]
-        start: u128,
+        balance: u128,
         #[serde_as(as = "DisplayFromStr")]
-        result: u128,
+        height: u128,
+        // hex encoded index block hash of the block at the height
+        block_hash: String,
+        // hex encoded commitment to the ledger the balance was computed over
+        history: String,
+        proposal: String,
+        vote: String,
+        // hex encoded secret the ballot's commitment is blinded with
+        blinding: String,
+        nullifier: String,
+        #[serde(with = "serde_bytes")]
+        proof: Vec<u8>,
+    },
+    StacksThresholdProof {
+        #[serde_as(as = "DisplayFromStr")]
+        threshold: u128,
+        #[serde_as(as = "DisplayFromStr")]
+        height: u128,
+        block_hash: String,
+        history: String,
+        proposal: String,
+        vote: String,
+        blinding: String,
+        nullifier: String,
+        #[serde(with = "serde_bytes")]
+        proof: Vec<u8>,
+    },
+    StacksLockedProof {
+        #[serde_as(as = "DisplayFromStr")]
+        locked: u128,
+        unlock_height: u64,
+        #[serde_as(as = "DisplayFromStr")]
+        height: u128,
+        block_hash: String,
+        history: String,
+        proposal: String,
+        vote: String,
+        blinding: String,
+        nullifier: String,
+        #[serde(with = "serde_bytes")]
+        proof: Vec<u8>,
+    },
+    StacksTokenProof {
+        #[serde_as(as = "DisplayFromStr")]
+        balance: u128,
+        asset_identifier: String,
+        #[serde_as(as = "DisplayFromStr")]
+        height: u128,
+        block_hash: String,
+        history: String,
+        proposal: String,
+        vote: String,
+        blinding: String,
+        nullifier: String,
+        #[serde(with = "serde_bytes")]
+        proof: Vec<u8>,
+    },
+    StacksNftProof {
+        min_count: u64,
+        asset_identifier: String,
+        #[serde_as(as = "DisplayFromStr")]
+        height: u128,
+        block_hash: String,
+        history: String,
+        proposal: String,
+        vote: String,
+        blinding: String,
+        nullifier: String,
+        #[serde(with = "serde_bytes")]
+        proof: Vec<u8>,
+    },
+    StacksQuadraticProof {
+        #[serde_as(as = "DisplayFromStr")]
+        weight: u128,
+        #[serde_as(as = "DisplayFromStr")]
+        height: u128,
+        block_hash: String,
+        history: String,
+        proposal: String,
+        vote: String,
+        blinding: String,
+        nullifier: String,
         #[serde(with = "serde_bytes")]
         proof: Vec<u8>,
     },
 }
 
 
-pub async fn handle_message(msg: &str) -> Result<ApplicationResponseMessage, Error> {
+// Handles a WebSocket request from `peer`. Failures are turned into an Error response, so the
+// client always hears back about its request. Nothing is returned when a job was queued for
+// `peer`: the job queue has already pushed the acknowledgement, ahead of the job's progress.
+pub async fn handle_request(msg: &str, state: &AppState, peer: Option<SocketAddr>) -> Option<ResponseEnvelope> {
+    let request_id = serde_json::from_str::<serde_json::Value>(msg)
+        .ok()
+        .and_then(|value| value.get("request_id").cloned());
+    let subscriber = peer.map(|peer| Subscriber { peer, request_id: request_id.clone() });
+    let response = match handle_message(msg, state, subscriber).await {
+        Ok(response) => response,
+        Err(e) => ApplicationResponseMessage::Error { message: e.to_string() },
+    };
+    if peer.is_some() && matches!(response, ApplicationResponseMessage::ProofJobUpdate(_)) {
+        return None;
+    }
+    Some(ResponseEnvelope { request_id, response })
+}
+
+// Stacks proofs are generated by jobs: the response only acknowledges the job, and its
+// progress is pushed to the subscriber.
+pub async fn handle_message(msg: &str, state: &AppState, subscriber: Option<Subscriber>) -> Result<ApplicationResponseMessage, Error> {
     // Deserialize the incoming JSON into ApplicationMessage
     let app_message: ApplicationMessage = serde_json::from_str(msg)?;
 
@@ -145,20 +377,46 @@ pub async fn handle_message(msg: &str) -> Result<ApplicationResponseMessage, Err
                     return Ok(application_response);
                 }
                 ProofGenerationMessage::StacksVotingProof { signature_data } => {
-                    let signature_data_clone = signature_data.clone();
-                    let response = generate_proof(signature_data_clone)
-                        .await
-                        .map_err(|e| Error::from(e))?; // Convert warp::Rejection to proofs::Error
-                    //let response_json = serde_json::to_string(&response)?;
-                    //let mut sender = Box::pin(sender); 
-                    //sender.send(Message::Text(response_json)).await?;
-                    return Ok(response)
+                    let request = ProofJobRequest::Balance { signature_data: *signature_data };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?; // Convert warp::Rejection to proofs::Error
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
+                }
+                ProofGenerationMessage::StacksThresholdProof { signature_data, threshold } => {
+                    let request = ProofJobRequest::Threshold { signature_data: *signature_data, threshold };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?;
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
+                }
+                ProofGenerationMessage::StacksLockedProof { signature_data } => {
+                    let request = ProofJobRequest::Locked { signature_data: *signature_data };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?;
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
+                }
+                ProofGenerationMessage::StacksTokenProof { signature_data } => {
+                    let request = ProofJobRequest::Token { signature_data: *signature_data };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?;
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
+                }
+                ProofGenerationMessage::StacksNftProof { signature_data, min_count } => {
+                    let request = ProofJobRequest::Nft { signature_data: *signature_data, min_count };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?;
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
+                }
+                ProofGenerationMessage::StacksQuadraticProof { signature_data } => {
+                    let request = ProofJobRequest::Quadratic { signature_data: *signature_data };
+                    let status = jobs::submit(state, request, subscriber).await
+                        .map_err(Error::from)?;
+                    return Ok(ApplicationResponseMessage::ProofJobUpdate(status))
                 }
             }
         }
         ApplicationMessage::ProofVerification(proof_ver_msg) => {
             // Match on specific proof type for verification
-            match proof_ver_msg {
+            match *proof_ver_msg {
                 ProofVerificationMessage::VdfProof { start, result, proof } => {
                     let result = VdfProofVerifier::verify_proof(start, result, proof);
                     let response: VerificationResponse = VerificationResponse {
@@ -168,12 +426,75 @@ pub async fn handle_message(msg: &str) -> Result<ApplicationResponseMessage, Err
                     let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
                     return Ok(application_response);
                 }
-                ProofVerificationMessage::StacksVotingProof { start, result, proof } => {
-                    let result = StacksVotingProofVerifier::verify_proof(start, result, proof);
-                    let response: VerificationResponse = VerificationResponse {
-                        ok:result,            // Result from the proof generation
-                        error: "None".to_string(),
-                    };
+                ProofVerificationMessage::StacksVotingProof { balance, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_balance_proof(balance, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
+                    let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
+                    return Ok(application_response);
+                }
+                ProofVerificationMessage::StacksThresholdProof { threshold, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_threshold_proof(threshold, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
+                    let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
+                    return Ok(application_response);
+                }
+                ProofVerificationMessage::StacksLockedProof { locked, unlock_height, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_locked_proof(locked, unlock_height, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
+                    let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
+                    return Ok(application_response);
+                }
+                ProofVerificationMessage::StacksTokenProof { balance, asset_identifier, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_token_proof(balance, &asset_identifier, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
+                    let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
+                    return Ok(application_response);
+                }
+                ProofVerificationMessage::StacksNftProof { min_count, asset_identifier, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_nft_proof(min_count, &asset_identifier, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
+                    let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
+                    return Ok(application_response);
+                }
+                ProofVerificationMessage::StacksQuadraticProof { weight, height, block_hash, history, proposal, vote, blinding, nullifier, proof } => {
+                    let block_hash = decode_block_hash(&block_hash)?;
+                    let blinding = decode_blinding(&blinding)?;
+                    let nullifier = decode_nullifier(&nullifier)?;
+                    let history = decode_history(&history)?;
+                    let ballot = BallotInputs::from_parts(height, &block_hash, &proposal, &vote, &blinding, &nullifier);
+                    let result = proofs::check_history(state, &proposal, &nullifier, &history)
+                        .and_then(|()| StacksVotingProofVerifier::verify_quadratic_proof(weight, &history, ballot, &proof));
+                    let response: VerificationResponse = VerificationResponse::from(anchored(state, height, &block_hash, result).await);
                     let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofVerificationResponse(response);
                     return Ok(application_response);
                 }
@@ -181,7 +502,6 @@ pub async fn handle_message(msg: &str) -> Result<ApplicationResponseMessage, Err
         }
         ApplicationMessage::Other(description) => {
             println!("Received other message type: {}", description);
-            ()
         }
     }
 
@@ -190,6 +510,31 @@ pub async fn handle_message(msg: &str) -> Result<ApplicationResponseMessage, Err
         message,
     };
     let application_response: ApplicationResponseMessage = ApplicationResponseMessage::ProofGenerationResponse(response);
-    return Ok(application_response);
+    Ok(application_response)
+}
+
+fn decode_blinding(blinding: &str) -> Result<[u8; 32], Error> {
+    decode_hex32(blinding).ok_or_else(|| Error::ProofGenerationError("Invalid blinding".to_string()))
+}
+
+// Nullifiers travel as hex strings; the proof binds all 32 bytes.
+fn decode_nullifier(nullifier: &str) -> Result<[u8; 32], Error> {
+    decode_hex32(nullifier).ok_or_else(|| Error::ProofGenerationError("Invalid nullifier".to_string()))
+}
+
+// A proof that checks out still has to be anchored to the canonical block at its height.
+async fn anchored(state: &AppState, height: u128, block_hash: &[u8; 32], verified: Result<(), VerificationFailure>) -> Result<(), VerificationFailure> {
+    verified?;
+    let height = u64::try_from(height)
+        .map_err(|_| VerificationFailure::BadEncoding("block height does not fit in 64 bits".to_string()))?;
+    proofs::check_anchor(state, height, block_hash).await
+}
+
+fn decode_history(history: &str) -> Result<[u8; 32], Error> {
+    decode_hex32(history).ok_or_else(|| Error::ProofGenerationError("Invalid history commitment".to_string()))
+}
+
+fn decode_block_hash(block_hash: &str) -> Result<[u8; 32], Error> {
+    decode_hex32(block_hash).ok_or_else(|| Error::ProofGenerationError("Invalid block hash".to_string()))
 }
 
@@ -1,27 +1,33 @@
 use prover::WorkProver;
-use utils::pad_to_power_of_two;
-use winterfell::math::StarkField;
+use rescue::{CAPACITY_RANGE, CYCLE_LENGTH, LENGTH_INDEX, RATE, RATE_RANGE, STATE_WIDTH};
+use trace::{check_trace_info, debug_check_trace, is_cycle_end, range_offset, range_start, range_value, sponge_length, Column, DIGEST, HISTORY_LIMBS, RANGE_BITS, RANGE_CHECKS, TRACE_WIDTH};
+use utils::{digest_to_elements, entry_input, height_to_elements, padded_trace_length, salt_input, DIGEST_LIMBS, HEIGHT_LIMBS};
 use winterfell::{
     Air, AirContext, Assertion, EvaluationFrame, FieldExtension, Trace, TraceInfo, TransitionConstraintDegree
 };
 use winterfell::{
-    math::{fields::f128::BaseElement, FieldElement, ToElements},
+    math::{fields::f64::BaseElement, FieldElement, ToElements},
     ProofOptions, Prover, TraceTable,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::stacks::utils::Transaction;
+use crate::stacks::history::{BalanceHistory, LedgerEntry};
 
-use super::VotingProofGenerator;
+use super::{Error, ProofStage, VotingProofGenerator};
 mod prover;
+mod rescue;
+mod trace;
 mod verifier;
 mod utils;
 
+pub use utils::{asset_identifier_hash, ballot_commitment, history_commitment};
+pub use verifier::VerificationFailure;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Domain {
-    name: String,
-    version: String,
-    chain_id: u32,
+    pub name: String,
+    pub version: String,
+    pub chain_id: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -39,6 +45,8 @@ pub struct MessageInputs {
 pub struct SignatureData {
     pub  message_inputs: MessageInputs,
     pub public_key: String,
+    // SIP-018 hash of the domain and message inputs; recomputed by the server, so optional.
+    #[serde(default)]
     pub hash: String,
     pub signature: String,
     pub message: String,
@@ -53,56 +61,350 @@ pub struct StacksVotingProofGenrator;
 pub struct StacksVotingProofVerifier;
 
 impl VotingProofGenerator for StacksVotingProofGenrator {
-    fn generate_proof(signature_data: SignatureData, transactions:Vec<Transaction>) -> (Vec<u8>, u128) {
-        generate_stacks_voting_proof(signature_data, transactions)
+    fn generate_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+        generate_stacks_voting_proof(signature_data, nullifier, blinding, history, progress)
+    }
+}
+
+impl StacksVotingProofGenrator {
+    // Generates an eligibility proof which only discloses that the voter's balance is at
+    // least `threshold`; the balance itself stays private.
+    pub fn generate_threshold_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], threshold: u128, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<Vec<u8>, Error> {
+        generate_stacks_threshold_proof(signature_data, nullifier, blinding, threshold, history, progress)
+    }
+
+    // Generates a proof disclosing the voter's balance in a SIP-010 token, from a history
+    // built with `BalanceHistory::token_at_height`. The token is identified in the public
+    // inputs by the hash of its asset identifier. Returns the proof and the balance.
+    pub fn generate_token_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], asset_identifier: &str, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+        generate_stacks_token_proof(signature_data, nullifier, blinding, asset_identifier, history, progress)
+    }
+
+    // Generates a proof which only discloses that the voter owned at least `min_count` tokens
+    // of the SIP-009 collection `asset_identifier`, from a history built with
+    // `BalanceHistory::nft_count_at_height`. Neither the token ids nor the count are revealed.
+    pub fn generate_nft_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], asset_identifier: &str, min_count: u64, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<Vec<u8>, Error> {
+        generate_stacks_nft_proof(signature_data, nullifier, blinding, asset_identifier, min_count, history, progress)
+    }
+
+    // Generates a proof disclosing the STX the voter has locked by stacking at the ballot's
+    // height, and the burnchain height they unlock at. `burn_height` is the burnchain height
+    // of the ballot's block; a lock must still be in force there. Returns the proof, the
+    // locked amount and the unlock height.
+    pub fn generate_locked_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, burn_height: u64, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128, u64), Error> {
+        generate_stacks_locked_proof(signature_data, nullifier, blinding, history, burn_height, progress)
+    }
+
+    // Generates a proof disclosing the voter's quadratic voting weight, the integer square
+    // root of their balance, without revealing the balance. Returns the proof and the weight.
+    pub fn generate_quadratic_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+        generate_stacks_quadratic_proof(signature_data, nullifier, blinding, history, progress)
     }
 }
 
 // Define the proof1-specific proof generation function.
-fn generate_stacks_voting_proof(signature_data: SignatureData, transactions:Vec<Transaction>) -> (Vec<u8>, u128) {
-    // We'll just hard-code the parameters here for this example. We'll also just run the
-    // computation just for 1024 steps to save time during testing.
-    let x: BaseElement = BaseElement::new(signature_data.message_inputs.balance_at_height.into());
-    //let address = "your_stacks_address_here";
-    let h: usize = signature_data.message_inputs.block_proof_height.try_into().unwrap();
-
-    let padded_transactions:Vec<Transaction> = pad_to_power_of_two(transactions);
-    let trace: TraceTable<BaseElement> = build_do_work_trace(x, h, padded_transactions);
-    let result: BaseElement = trace.get(1, trace.length() - 1);
-
-    // Define proof options; these will be enough for ~96-bit security level.
-    let options = ProofOptions::new(
+fn generate_stacks_voting_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(history.entries(), &salt);
+    let result: BaseElement = trace.get(Column::Balance.index(), trace.length() - 1);
+
+    // The balance is disclosed, so the range checks only show it fits in RANGE_BITS bits.
+    let claim = Claim::Balance(result);
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+    let proof_bytes: Vec<u8> = proof.to_bytes();
+
+    Ok((proof_bytes, result.as_int().into()))
+
+}
+
+fn generate_stacks_threshold_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], threshold: u128, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<Vec<u8>, Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(history.entries(), &salt);
+    let balance: u128 = trace.get(Column::Balance.index(), trace.length() - 1).as_int().into();
+
+    // An honest prover cannot build a valid trace for a balance below the threshold, so we
+    // bail out early instead of producing a proof the verifier would reject.
+    if balance < threshold {
+        return Err(Error::ProofGenerationError("Balance does not meet the threshold".to_string()));
+    }
+    let claim = Claim::Threshold(BaseElement::new(threshold as u64));
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+
+    Ok(proof.to_bytes())
+}
+
+fn generate_stacks_token_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], asset_identifier: &str, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(history.entries(), &salt);
+    let balance: BaseElement = trace.get(Column::Balance.index(), trace.length() - 1);
+
+    let claim = Claim::Token { balance, asset: digest_to_elements(&asset_identifier_hash(asset_identifier)) };
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+
+    Ok((proof.to_bytes(), balance.as_int().into()))
+}
+
+// Owning NFTs is proven like a balance threshold, over the number of tokens owned.
+fn generate_stacks_nft_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], asset_identifier: &str, min_count: u64, history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<Vec<u8>, Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    if history.balance() < u128::from(min_count) {
+        return Err(Error::ProofGenerationError(format!("Fewer than {} tokens of {} owned", min_count, asset_identifier)));
+    }
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(history.entries(), &salt);
+
+    let claim = Claim::Holdings {
+        min_count: BaseElement::new(min_count),
+        asset: digest_to_elements(&asset_identifier_hash(asset_identifier)),
+    };
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+
+    Ok(proof.to_bytes())
+}
+
+// The locked amount is proven the same way as a balance, over a ledger of lock changes instead
+// of transactions. The ledger also carries each lock's unlock height, and the AIR checks the
+// one of the latest lock against the public inputs.
+fn generate_stacks_locked_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, burn_height: u64, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128, u64), Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    let lock = history.lock_at(burn_height)
+        .ok_or_else(|| Error::ProofGenerationError("No STX locked at the proof height".to_string()))?;
+    let unlock_height = lock.unlock_height;
+    let entries = history.lock_entries();
+    check_amounts(&entries)?;
+
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(&entries, &salt);
+    let locked: BaseElement = trace.get(Column::Balance.index(), trace.length() - 1);
+    debug_assert_eq!(u128::from(locked.as_int()), lock.amount);
+
+    let claim = Claim::Locked { amount: locked, unlock_height: BaseElement::new(unlock_height) };
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+
+    Ok((proof.to_bytes(), locked.as_int().into(), unlock_height))
+}
+
+// The weight is the integer square root of the balance. The two range checks pin the balance
+// between weight^2 and (weight + 1)^2 - 1, so the weight is the only one the balance admits.
+fn generate_stacks_quadratic_proof(signature_data: SignatureData, nullifier: [u8; 32], blinding: [u8; 32], history: &BalanceHistory, progress: &dyn Fn(ProofStage)) -> Result<(Vec<u8>, u128), Error> {
+    let (block_hash, salt) = check_history(&signature_data, history)?;
+    progress(ProofStage::BuildingTrace);
+    let mut trace: TraceTable<BaseElement> = build_do_work_trace(history.entries(), &salt);
+    let balance = trace.get(Column::Balance.index(), trace.length() - 1).as_int();
+    let weight = balance.isqrt();
+
+    let claim = Claim::Quadratic(BaseElement::new(weight));
+    fill_range_checks(&mut trace, &claim);
+
+    let ballot = BallotInputs::new(&signature_data.message_inputs, &blinding, &nullifier, &block_hash);
+    let prover = WorkProver::new(default_proof_options(), claim, ballot);
+    progress(ProofStage::Proving);
+    let proof = prover.prove(trace)
+        .map_err(|e| Error::ProofGenerationError(e.to_string()))?;
+
+    Ok((proof.to_bytes(), weight.into()))
+}
+
+// The history must be the one at the height the ballot is proven at, anchored to the block at
+// that height and blinded; returns the block's index hash and the salt. An account that never held STX at that height
+// has nothing to prove; its trace would also be all zeros, which doesn't have the constraint
+// degrees the AIR declares.
+fn check_history(signature_data: &SignatureData, history: &BalanceHistory) -> Result<([u8; 32], [u8; 32]), Error> {
+    if history.height() != signature_data.message_inputs.block_proof_height {
+        return Err(Error::ProofGenerationError(format!(
+            "Balance history is at height {}, the ballot is proven at {}",
+            history.height(),
+            signature_data.message_inputs.block_proof_height
+        )));
+    }
+    if history.entries().is_empty() {
+        return Err(Error::ProofGenerationError("No STX transactions at or below the proof height".to_string()));
+    }
+    check_amounts(history.entries())?;
+    if history.balance() >= 1u128 << RANGE_BITS {
+        return Err(Error::ProofGenerationError(format!("Balances must be below 2^{}", RANGE_BITS)));
+    }
+    let block_hash = history.block_hash()
+        .ok_or_else(|| Error::ProofGenerationError("Balance history is not anchored to a block".to_string()))?;
+    let salt = history.salt()
+        .ok_or_else(|| Error::ProofGenerationError("Balance history is not blinded".to_string()))?;
+    Ok((*block_hash, *salt))
+}
+
+// The trace only sums amounts in the field, so they must be small enough that no ledger can
+// wrap around the modulus; token amounts aren't bounded by the STX supply.
+fn check_amounts(entries: &[LedgerEntry]) -> Result<(), Error> {
+    match entries.iter().find(|entry| entry.debit.max(entry.credit) >= 1u128 << RANGE_BITS) {
+        Some(entry) => Err(Error::ProofGenerationError(format!("Amount moved by {} is not below 2^{}", entry.tx_id, RANGE_BITS))),
+        None => Ok(()),
+    }
+}
+
+// Define proof options; these will be enough for ~96-bit security level. The 64-bit field is
+// too small to draw the verifier's randomness from, so it comes from its quadratic extension.
+fn default_proof_options() -> ProofOptions {
+    ProofOptions::new(
         32, // number of queries
         8,  // blowup factor
         0,  // grinding factor
-        FieldExtension::None,
+        FieldExtension::Quadratic,
         8,  // FRI folding factor
         31, // FRI max remainder polynomial degree
-    );
+    )
+}
 
-    // Instantiate the prover and generate the proof.
-    let prover = WorkProver::new(options);
-    let proof = prover.prove(trace).unwrap();
-    let proof_bytes: Vec<u8> = proof.to_bytes();
 
-    (proof_bytes, result.as_int())
+// Air Implementation
+// ===========================================================================================
 
+// What the proof discloses about the balance reached after the last transaction.
+#[derive(Clone, Copy, Debug)]
+pub enum Claim {
+    // The exact balance.
+    Balance(BaseElement),
+    // Only that the balance is greater than or equal to the threshold.
+    Threshold(BaseElement),
+    // The exact amount locked by stacking, and the burnchain height it unlocks at.
+    Locked { amount: BaseElement, unlock_height: BaseElement },
+    // The exact balance in a SIP-010 token, identified by the limbs of its asset hash.
+    Token { balance: BaseElement, asset: [BaseElement; DIGEST_LIMBS] },
+    // Only that at least `min_count` tokens of a SIP-009 collection, identified by the limbs of
+    // its asset hash, are owned.
+    Holdings { min_count: BaseElement, asset: [BaseElement; DIGEST_LIMBS] },
+    // Only the quadratic voting weight: weight^2 <= balance < (weight + 1)^2. Weights must be
+    // below 2^(RANGE_BITS / 2), so both bounds fit in the range checks.
+    Quadratic(BaseElement),
 }
 
+impl Claim {
+    // The value the balance is range checked against; a disclosed balance is only checked
+    // for being non-negative.
+    fn lower_bound(&self) -> BaseElement {
+        match self {
+            Claim::Balance(_) | Claim::Locked { .. } | Claim::Token { .. } => BaseElement::ZERO,
+            Claim::Threshold(threshold) => *threshold,
+            Claim::Holdings { min_count, .. } => *min_count,
+            Claim::Quadratic(weight) => weight.square(),
+        }
+    }
 
-// Air Implementation
-// ===========================================================================================
+    // The value the balance is range checked against from above; any balance other than a
+    // quadratic one only has to fit in RANGE_BITS bits.
+    fn upper_bound(&self) -> BaseElement {
+        match self {
+            Claim::Quadratic(weight) => (*weight + BaseElement::ONE).square() - BaseElement::ONE,
+            _ => range_offset() - BaseElement::ONE,
+        }
+    }
+}
+
+// Public inputs describing the ballot the proof was generated for. None of them are read
+// from the trace; they are bound to the proof because all public inputs are absorbed into
+// the verifier's random coin, so a proof checked against different values is rejected.
+#[derive(Clone, Copy, Debug)]
+pub struct BallotInputs {
+    // the block height the balance is proven at, as 56-bit limbs
+    height: [BaseElement; HEIGHT_LIMBS],
+    // index block hash of the block at that height, as 56-bit limbs; it pins the proof to one
+    // chain state, so a proof over a reorged fork doesn't verify against the canonical block
+    block_hash: [BaseElement; DIGEST_LIMBS],
+    // blinded commitment to the proposal and the vote cast on it, as 56-bit limbs
+    commitment: [BaseElement; DIGEST_LIMBS],
+    // the voter's nullifier for that proposal, as 56-bit limbs
+    nullifier: [BaseElement; DIGEST_LIMBS],
+}
+
+impl BallotInputs {
+    pub fn new(message_inputs: &MessageInputs, blinding: &[u8; 32], nullifier: &[u8; 32], block_hash: &[u8; 32]) -> Self {
+        BallotInputs::from_parts(
+            message_inputs.block_proof_height.into(),
+            block_hash,
+            &message_inputs.proposal,
+            &message_inputs.vote,
+            blinding,
+            nullifier,
+        )
+    }
 
-// Public inputs for our computation will consist of the starting value and the end result.
+    // Used by verifiers, which only see the disclosed parts of the ballot.
+    pub fn from_parts(height: u128, block_hash: &[u8; 32], proposal: &str, vote: &str, blinding: &[u8; 32], nullifier: &[u8; 32]) -> Self {
+        BallotInputs {
+            height: height_to_elements(height),
+            block_hash: digest_to_elements(block_hash),
+            commitment: digest_to_elements(&ballot_commitment(proposal, vote, blinding)),
+            nullifier: digest_to_elements(nullifier),
+        }
+    }
+}
+
+// Public inputs for our computation are the claim about the final balance, the commitment to
+// the ledger it was computed over and the ballot.
 pub struct PublicInputs {
-    balance: BaseElement,
-    height: BaseElement,
+    claim: Claim,
+    history: [BaseElement; HISTORY_LIMBS],
+    ballot: BallotInputs,
 }
 
-// We need to describe how public inputs can be converted to field elements.
+// We need to describe how public inputs can be converted to field elements. The claim is
+// prefixed with a tag so a threshold proof can never be passed off as a balance proof, nor a
+// balance proof as a proof of locked STX.
 impl ToElements<BaseElement> for PublicInputs {
     fn to_elements(&self) -> Vec<BaseElement> {
-        vec![self.balance, self.height]
+        let mut elements = match self.claim {
+            Claim::Balance(balance) => vec![BaseElement::ZERO, balance],
+            Claim::Threshold(threshold) => vec![BaseElement::ONE, threshold],
+            Claim::Locked { amount, unlock_height } => vec![BaseElement::new(2), amount, unlock_height],
+            Claim::Token { balance, asset } => {
+                let mut elements = vec![BaseElement::new(3), balance];
+                elements.extend_from_slice(&asset);
+                elements
+            }
+            Claim::Holdings { min_count, asset } => {
+                let mut elements = vec![BaseElement::new(4), min_count];
+                elements.extend_from_slice(&asset);
+                elements
+            }
+            Claim::Quadratic(weight) => vec![BaseElement::new(5), weight],
+        };
+        elements.extend_from_slice(&self.ballot.height);
+        elements.extend_from_slice(&self.ballot.block_hash);
+        elements.extend_from_slice(&self.history);
+        elements.extend_from_slice(&self.ballot.commitment);
+        elements.extend_from_slice(&self.ballot.nullifier);
+        elements
     }
 }
 
@@ -111,8 +413,8 @@ impl ToElements<BaseElement> for PublicInputs {
 // internally by the Winterfell prover/verifier when interpreting this AIR.
 pub struct WorkAir {
     context: AirContext<BaseElement>,
-    start: BaseElement,
-    result: BaseElement,
+    claim: Claim,
+    history: [BaseElement; HISTORY_LIMBS],
 }
 
 impl Air for WorkAir {
@@ -123,30 +425,54 @@ impl Air for WorkAir {
     type GkrProof = ();
     type GkrVerifier = ();
 
-    // Here, we'll construct a new instance of our computation which is defined by 3
-    // parameters: starting value, number of steps, and the end result. Another way to
-    // think about it is that an instance of our computation is a specific invocation of
-    // the do_work() function.
+    // An instance of our computation is defined by the claim about the final balance and the
+    // ledger commitment, which the trace is asserted against. The ballot isn't read from the
+    // trace at all: its height, block hash, commitment and nullifier are bound to the proof
+    // only because all public inputs are absorbed into the verifier's random coin.
     fn new(trace_info: TraceInfo, pub_inputs: PublicInputs, options: ProofOptions) -> Self {
-        // our execution trace should have only one column.
-        assert_eq!(1, trace_info.width());
-
-        // Our computation requires a single transition constraint. The constraint itself
-        // is defined in the evaluate_transition() method below, but here we need to specify
-        // the expected degree of the constraint. If the expected and actual degrees of the
-        // constraints don't match, an error will be thrown in the debug mode, but in release
-        // mode, an invalid proof will be generated which will not be accepted by any verifier.
-        let degrees = vec![TransitionConstraintDegree::new(3)];
+        // our execution trace must follow the layout described in the trace module.
+        check_trace_info(&trace_info);
+
+        // The constraints gated by the range check masks have a degree which depends on the
+        // trace length, since the masks are periodic columns spanning the whole trace. If the
+        // expected and actual degrees of the constraints don't match, an error will be thrown
+        // in the debug mode, but in release mode, an invalid proof will be generated which
+        // will not be accepted by any verifier.
+        let trace_length = trace_info.length();
+        let mut degrees = vec![
+            TransitionConstraintDegree::with_cycles(2, vec![CYCLE_LENGTH]),
+            TransitionConstraintDegree::new(2),
+        ];
+        for _ in RANGE_CHECKS {
+            degrees.extend([
+                TransitionConstraintDegree::new(2),
+                TransitionConstraintDegree::with_cycles(1, vec![trace_length]),
+                TransitionConstraintDegree::new(1),
+                TransitionConstraintDegree::with_cycles(1, vec![trace_length]),
+            ]);
+        }
+        // the rounds raise the state to the 7th power and are switched on and off by the round
+        // mask; the round constants have a lower degree than the trace columns they are added to
+        degrees.extend((0..STATE_WIDTH).map(|_| TransitionConstraintDegree::with_cycles(7, vec![CYCLE_LENGTH])));
+        // only lock ledgers carry unlock heights; over any other ledger the constraint would be
+        // all zeros, which doesn't have the degree declared here
+        if matches!(pub_inputs.claim, Claim::Locked { .. }) {
+            degrees.push(TransitionConstraintDegree::with_cycles(2, vec![CYCLE_LENGTH]));
+        }
 
         // We also need to specify the exact number of assertions we will place against the
         // execution trace. This number must be the same as the number of items in a vector
         // returned from the get_assertions() method below.
-        let num_assertions = 2;
+        let num_assertions = CAPACITY_RANGE.len() + HISTORY_LIMBS + 1 + 2 * RANGE_CHECKS.len() + match pub_inputs.claim {
+            Claim::Locked { .. } => 3,
+            Claim::Balance(_) | Claim::Token { .. } => 1,
+            Claim::Threshold(_) | Claim::Holdings { .. } | Claim::Quadratic(_) => 0,
+        };
 
         WorkAir {
             context: AirContext::new(trace_info, degrees, num_assertions, options),
-            start: pub_inputs.balance,
-            result: pub_inputs.height,
+            claim: pub_inputs.claim,
+            history: pub_inputs.history,
         }
     }
 
@@ -157,29 +483,120 @@ impl Air for WorkAir {
     fn evaluate_transition<E: FieldElement + From<Self::BaseField>>(
         &self,
         frame: &EvaluationFrame<E>,
-        _periodic_values: &[E],
+        periodic_values: &[E],
         result: &mut [E],
     ) {
-        // First, we'll read the current state, and use it to compute the expected next state
-        let current_state = frame.current()[0];
-        let next_state = current_state.exp(3u32.into()) + E::from(42u32);
+        let current = frame.current();
+        let next = frame.next();
+        let range_mask = periodic_values[0];
+        let last_mask = periodic_values[1];
+        let round_mask = periodic_values[2];
+        let absorb_mask = E::ONE - round_mask;
+        let ark1 = &periodic_values[3..3 + STATE_WIDTH];
+        let inv_ark2 = &periodic_values[3 + STATE_WIDTH..3 + 2 * STATE_WIDTH];
+
+        // Entering a cycle applies its entry: balance_next = balance - stx_sent + stx_received
+        // for real entries, while for padding cycles the is_real flag zeroes out the delta. The
+        // balance is carried over within a cycle.
+        let next_is_real = Column::IsReal.of(next);
+        let delta = Column::Credit.of(next) - Column::Debit.of(next);
+        result[0] = Column::Balance.of(next) - (Column::Balance.of(current) + absorb_mask * next_is_real * delta);
+
+        // is_real must be either 0 or 1, otherwise it could be used to scale the delta.
+        let is_real = Column::IsReal.of(current);
+        result[1] = is_real * (is_real - E::ONE);
+
+        // each range check takes four constraints: its bits must be binary, and can only be
+        // set in the last RANGE_BITS + 1 rows; the accumulator shifts in one bit per row; on
+        // the last transition, the accumulated value minus the leading bit must equal
+        // balance - lower_bound, or upper_bound - balance. The bit rules are checked on the
+        // next row, the one the accumulator shifts in, since transition constraints never
+        // look at the last row on its own.
+        let bounds = [self.claim.lower_bound(), self.claim.upper_bound()];
+        let offset = E::from(range_offset());
+        for (i, ((bit_column, acc_column, upper), bound)) in RANGE_CHECKS.into_iter().zip(bounds).enumerate() {
+            let result = &mut result[2 + 4 * i..];
+            let bit = bit_column.of(next);
+            result[0] = bit * (bit - E::ONE);
+            result[1] = (E::ONE - range_mask) * bit;
+            result[2] = acc_column.of(next) - (acc_column.of(current).double() + bit);
+            let value = range_value(Column::Balance.of(next), E::from(bound), upper);
+            result[3] = last_mask * (value + offset - acc_column.of(next));
+        }
 
-        // Then, we'll subtract the expected next state from the actual next state; this will
-        // evaluate to zero if and only if the expected and actual states are the same.
-        result[0] = frame.next()[0] - next_state;
+        // the sponge applies a round on every transition within a cycle, and absorbs the id and
+        // amounts of a real entry when entering its cycle; the capacity is carried over.
+        let hash = Column::all_of(Column::HASH, current);
+        let hash_next = Column::all_of(Column::HASH, next);
+        let input = Column::input_of(next);
+        let mut round = [E::ZERO; STATE_WIDTH];
+        rescue::enforce_round(&hash, &hash_next, ark1, inv_ark2, &mut round);
+        for i in 0..STATE_WIDTH {
+            let absorbed = if RATE_RANGE.contains(&i) { next_is_real * input[i - RATE_RANGE.start] } else { E::ZERO };
+            let absorb = hash_next[i] - (hash[i] + absorbed);
+            result[2 + 4 * RANGE_CHECKS.len() + i] = round_mask * round[i] + absorb_mask * absorb;
+        }
+
+        // over a lock ledger, the unlock height is replaced by the one of each real entry when
+        // entering its cycle, and carried over everywhere else.
+        if matches!(self.claim, Claim::Locked { .. }) {
+            let unlock_height = Column::UnlockHeight.of(current);
+            let delta = next_is_real * (Column::Unlock.of(next) - unlock_height);
+            result[2 + 4 * RANGE_CHECKS.len() + STATE_WIDTH] = Column::UnlockHeight.of(next) - (unlock_height + absorb_mask * delta);
+        }
     }
 
     // Here, we'll define a set of assertions about the execution trace which must be
     // satisfied for the computation to be valid. Essentially, this ties computation's
     // execution trace to the public inputs.
     fn get_assertions(&self) -> Vec<Assertion<Self::BaseField>> {
-        // for our computation to be valid, value in column 0 at step 0 must be equal to the
-        // starting value, and at the last step it must be equal to the result.
+        // every account history starts from an empty balance and empty range check
+        // accumulators, and the range checks start with their leading bit set; when the
+        // balance is disclosed it must also match the last row, and a lock ledger must end on
+        // the disclosed unlock height.
         let last_step = self.trace_length() - 1;
-        vec![
-            Assertion::single(0, 0, self.start),
-            Assertion::single(0, last_step, self.result),
-        ]
+        let mut assertions = vec![Assertion::single(Column::Balance.index(), 0, BaseElement::ZERO)];
+        for (bit, acc, _) in RANGE_CHECKS {
+            assertions.push(Assertion::single(acc.index(), 0, BaseElement::ZERO));
+            assertions.push(Assertion::single(bit.index(), range_start(self.trace_length()), BaseElement::ONE));
+        }
+        // the sponge starts from the capacity `Rp64_256::hash_elements` starts from, and ends
+        // on the ledger commitment; the salt in the rate stays private.
+        for i in CAPACITY_RANGE {
+            let value = if i == LENGTH_INDEX { sponge_length(self.trace_length()) } else { BaseElement::ZERO };
+            assertions.push(Assertion::single(Column::HASH[i].index(), 0, value));
+        }
+        for (column, limb) in DIGEST.iter().zip(self.history.iter()) {
+            assertions.push(Assertion::single(column.index(), last_step, *limb));
+        }
+        match self.claim {
+            Claim::Locked { amount, unlock_height } => {
+                assertions.push(Assertion::single(Column::Balance.index(), last_step, amount));
+                assertions.push(Assertion::single(Column::UnlockHeight.index(), 0, BaseElement::ZERO));
+                assertions.push(Assertion::single(Column::UnlockHeight.index(), last_step, unlock_height));
+            }
+            Claim::Balance(balance) | Claim::Token { balance, .. } => {
+                assertions.push(Assertion::single(Column::Balance.index(), last_step, balance));
+            }
+            Claim::Threshold(_) | Claim::Holdings { .. } | Claim::Quadratic(_) => {}
+        }
+        assertions
+    }
+
+    // The range check masks: the first one selects the transitions into the last
+    // RANGE_BITS + 1 rows, the second one selects the last transition of the trace. They are
+    // followed by the sponge's round mask and round constants.
+    fn get_periodic_column_values(&self) -> Vec<Vec<Self::BaseField>> {
+        let trace_length = self.trace_length();
+        let mut range_mask = vec![BaseElement::ZERO; trace_length];
+        for value in range_mask.iter_mut().skip(range_start(trace_length) - 1) {
+            *value = BaseElement::ONE;
+        }
+        let mut last_mask = vec![BaseElement::ZERO; trace_length];
+        last_mask[trace_length - 2] = BaseElement::ONE;
+        let mut columns = vec![range_mask, last_mask];
+        columns.extend(rescue::periodic_columns());
+        columns
     }
 
     // This is just boilerplate which is used by the Winterfell prover/verifier to retrieve
@@ -193,101 +610,281 @@ impl Air for WorkAir {
 // Work function with trace recording
 // ===========================================================================================
 
-fn initialize_trace_table(balance: usize, transaction_length: usize) -> TraceTable<BaseElement> {
-    //let trace_width = 2; // Adjust based on the columns needed
-    let mut trace = TraceTable::new(balance, transaction_length); // 1 row initially, can expand as needed
-
-    trace.set(0, 0, BaseElement::new(transaction_length as u128));         // Balance column
-    //trace.set(1, 0, BaseElement::new(block_height.into()));    // Block height column
+fn initialize_trace_table(trace_length: usize) -> TraceTable<BaseElement> {
+    // one cycle per (padded) ledger entry
+    TraceTable::new(TRACE_WIDTH, trace_length)
+}
 
-    trace
+// Copies the id and amounts of a ledger entry into the current row; padding cycles past the
+// end of the ledger are marked as not real.
+fn load_entry(state: &mut [BaseElement], entry: Option<&LedgerEntry>) {
+    let (is_real, input) = match entry {
+        Some(entry) => (BaseElement::ONE, entry_input(entry)),
+        None => (BaseElement::ZERO, [BaseElement::ZERO; RATE]),
+    };
+    state[Column::IsReal.index()] = is_real;
+    for (column, value) in Column::INPUT.iter().zip(input.iter()) {
+        state[column.index()] = *value;
+    }
 }
 
-pub fn build_do_work_trace(balance: BaseElement, height: usize, transactions:Vec<Transaction>) -> TraceTable<BaseElement> {
-    let trace_width: usize = 2; // one column for balance the other for "is_real" indicator on tx (cf power of 2 constraint on length)
-    let trace_length = transactions.len();
-    let mut trace = initialize_trace_table(trace_width, trace_length.try_into().unwrap());
+pub fn build_do_work_trace(entries: &[LedgerEntry], salt: &[u8; 32]) -> TraceTable<BaseElement> {
+    let trace_length = padded_trace_length(entries.len());
+    let mut trace = initialize_trace_table(trace_length);
 
     trace.fill(
         |state| {
-            // Set the initial state to the starting balance
-            state[0] = balance;
-            state[1] = BaseElement::new(0);
+            // Set the initial state to an empty account and a sponge holding the salt, in the
+            // first cycle, which has no entry
+            state[Column::Balance.index()] = BaseElement::ZERO;
+            state[Column::UnlockHeight.index()] = BaseElement::ZERO;
+            load_entry(state, None);
+            for (bit, acc, _) in RANGE_CHECKS {
+                state[bit.index()] = BaseElement::ZERO;
+                state[acc.index()] = BaseElement::ZERO;
+            }
+            for column in Column::HASH {
+                state[column.index()] = BaseElement::ZERO;
+            }
+            state[Column::HASH[LENGTH_INDEX].index()] = sponge_length(trace_length);
+            for (column, value) in Column::HASH[RATE_RANGE].iter().zip(salt_input(salt)) {
+                state[column.index()] = value;
+            }
         },
         |step: usize, state| {
-            // Retrieve the current transaction if it exists for this step
-            let mut current_balance = state[0];
-            if step < transactions.len() {
-                let transaction = &transactions[step];
-                if transaction.tx.tx_id == "0" {
-                    // Maintain the current balance, mark as dummy
-                    state[0] = current_balance;
-                    state[1] = BaseElement::new(0);
-                } else {
-                    // Convert stx_sent and stx_received to BaseElement
-                    let stx_sent = BaseElement::new(transaction.stx_sent.parse::<u128>().unwrap());
-                    let stx_received = BaseElement::new(transaction.stx_received.parse::<u128>().unwrap());
-
-                    // Update balance based on the transaction
-                    current_balance = current_balance - stx_sent + stx_received;
-
-                    // Only update the state until we reach or exceed the target block height h
-                    if transaction.tx.block_height >= height as u64 {
-                        state[0] = current_balance;
-                    } else {
-                        // For heights below h, continue accumulating the balance
-                        state[0] = current_balance;
-                    }
-                    state[1] = BaseElement::new(1);
-                }
+            let mut hash = Column::all_of(Column::HASH, state);
+            if is_cycle_end(step) {
+                // Load the next cycle's entry, then apply and absorb it
+                load_entry(state, entries.get((step + 1) / CYCLE_LENGTH - 1));
+                let is_real = Column::IsReal.of(state);
+                let delta = Column::Credit.of(state) - Column::Debit.of(state);
+                state[Column::Balance.index()] += is_real * delta;
+                state[Column::UnlockHeight.index()] += is_real * (Column::Unlock.of(state) - Column::UnlockHeight.of(state));
+                rescue::absorb(&mut hash, &Column::input_of(state).map(|value| is_real * value));
             } else {
-                // If we’re beyond the transactions list, maintain the current balance
-                state[0] = current_balance;
-                state[1] = BaseElement::new(0);
+                rescue::apply_round(&mut hash, step % CYCLE_LENGTH);
+            }
+            for (column, value) in Column::HASH.iter().zip(hash.iter()) {
+                state[column.index()] = *value;
             }
         },
     );
+    debug_assert_eq!(TRACE_WIDTH, trace.width());
     trace
 }
-// pub fn build_balance_trace(
-//     initial_balance: u128, 
-//     target_block_height: u128, 
-//     target_balance: u128,
-// ) -> TraceTable<BaseElement> {
-//     let trace_width = 2;  // For tracking the balance and block height
-//     let num_steps = target_block_height as usize;  // Number of steps equals block height
-
-//     // Initialize the trace with a width and number of steps
-//     let mut trace = TraceTable::new(trace_width.try_into().unwrap(), num_steps);
-
-//     // Fill the trace with the initial balance and evolve it across the steps
-//     trace.fill(
-//         |state| {
-//             state[0] = BaseElement::new(initial_balance); // Set initial balance
-//             state[1] = BaseElement::new(0); // Start at block height 0
-//         },
-//         |step, state| {
-//             // Update the balance at each step (this is just an example logic)
-//             state[0] = state[0] + BaseElement::new(10);  // Simulate balance increase, adjust as needed
-
-//             // Update the block height at each step
-//             state[1] = BaseElement::new(step as u128);
-
-//             // Ensure balance is always >= target_balance
-//             if state[1] == BaseElement::new(target_block_height) {
-//                 assert!(state[0].as_int() >= target_balance as u128);  // Enforce the target balance condition
-//             }
-//         },
-//     );
-
-//     trace
-// }
-
-// fn do_work(start: BaseElement, n: usize) -> BaseElement {
-//    let mut result = start;
-//    for _ in 1..n {
-//        result = result.exp(3) + BaseElement::new(42);
-//    }
-//    result
-// }
 
+// Writes the bit decompositions of `2^RANGE_BITS + final balance - lower_bound` and
+// `2^RANGE_BITS + upper_bound - final balance` into the last RANGE_BITS + 1 rows of the trace.
+// The caller is responsible for making sure both differences fit in RANGE_BITS bits.
+fn fill_range_checks(trace: &mut TraceTable<BaseElement>, claim: &Claim) {
+    let trace_length = trace.length();
+    let balance = trace.get(Column::Balance.index(), trace_length - 1);
+    let bounds = [claim.lower_bound(), claim.upper_bound()];
+
+    for ((bit_column, acc_column, upper), bound) in RANGE_CHECKS.into_iter().zip(bounds) {
+        let value = (range_value(balance, bound, upper) + range_offset()).as_int();
+        let mut acc = BaseElement::ZERO;
+        for (i, step) in (range_start(trace_length)..trace_length).enumerate() {
+            let bit = BaseElement::new((value >> (RANGE_BITS - i)) & 1);
+            acc = acc.double() + bit;
+            trace.set(bit_column.index(), step, bit);
+            trace.set(acc_column.index(), step, acc);
+        }
+    }
+    debug_check_trace(trace, bounds[0], bounds[1]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::stacks::history::EntryKind;
+
+    use super::utils::history_from_bytes;
+    use super::*;
+
+    fn entry(tx_id: &str, debit: u128, credit: u128) -> LedgerEntry {
+        LedgerEntry { kind: EntryKind::Transaction, tx_id: tx_id.to_string(), block_height: 1, tx_index: 0, debit, credit, unlock_height: 0 }
+    }
+
+    fn lock(tx_id: &str, previous: u128, amount: u128, unlock_height: u64) -> LedgerEntry {
+        LedgerEntry { kind: EntryKind::Lock, tx_id: tx_id.to_string(), block_height: 1, tx_index: 0, debit: previous, credit: amount, unlock_height }
+    }
+
+    const SALT: [u8; 32] = [7; 32];
+
+    fn ballot() -> BallotInputs {
+        BallotInputs::from_parts(50, &[9; 32], "SIP-028", "for", &[3; 32], &[1; 32])
+    }
+
+    // Proves `claim` over `entries` whether or not it holds. Range checks that hold are filled
+    // in honestly; the others get a leading bit followed by zeros, and a last bit chosen to
+    // make the accumulator come out at the value the AIR expects. Returns None when the
+    // prover refuses the trace, which debug builds do when a constraint fails.
+    fn forge(entries: &[LedgerEntry], claim: Claim) -> Option<Vec<u8>> {
+        forge_with(entries, claim, |_| {})
+    }
+
+    // Like `forge`, with `tamper` editing the trace before the range checks are filled in.
+    fn forge_with(entries: &[LedgerEntry], claim: Claim, tamper: impl Fn(&mut TraceTable<BaseElement>)) -> Option<Vec<u8>> {
+        let mut trace = build_do_work_trace(entries, &SALT);
+        tamper(&mut trace);
+        let length = trace.length();
+        let balance = trace.get(Column::Balance.index(), length - 1);
+        let bounds = [claim.lower_bound(), claim.upper_bound()];
+        for ((bit_column, acc_column, upper), bound) in RANGE_CHECKS.into_iter().zip(bounds) {
+            let target = range_value(balance, bound, upper) + range_offset();
+            let value = target.as_int();
+            let holds = value >> RANGE_BITS == 1;
+            let mut acc = BaseElement::ZERO;
+            for (i, step) in (range_start(length)..length).enumerate() {
+                let bit = if holds {
+                    BaseElement::new((value >> (RANGE_BITS - i)) & 1)
+                } else if step == length - 1 {
+                    target - acc.double()
+                } else {
+                    BaseElement::from((i == 0) as u8)
+                };
+                acc = acc.double() + bit;
+                trace.set(bit_column.index(), step, bit);
+                trace.set(acc_column.index(), step, acc);
+            }
+        }
+        let prover = WorkProver::new(default_proof_options(), claim, ballot());
+        catch_unwind(AssertUnwindSafe(|| prover.prove(trace)))
+            .ok()
+            .and_then(Result::ok)
+            .map(|proof| proof.to_bytes())
+    }
+
+    #[test]
+    fn threshold_above_the_balance_is_rejected() {
+        let entries = [entry("a", 0, 100)];
+        let history = history_commitment(&entries, &SALT);
+        let verify = |threshold: u128, proof: &[u8]| {
+            StacksVotingProofVerifier::verify_threshold_proof(threshold, &history, ballot(), proof)
+        };
+
+        // the forger produces valid proofs of claims that hold
+        let proof = forge(&entries, Claim::Threshold(BaseElement::new(100))).unwrap();
+        assert_eq!(verify(100, &proof), Ok(()));
+        assert!(verify(101, &proof).is_err());
+
+        let forged = forge(&entries, Claim::Threshold(BaseElement::new(1_000_000)));
+        assert!(forged.is_none_or(|proof| verify(1_000_000, &proof).is_err()));
+
+        // thresholds past the range checks would wrap around the field
+        assert!(matches!(verify(1 << RANGE_BITS, &proof), Err(VerificationFailure::PublicInputMismatch(_))));
+    }
+
+    #[test]
+    fn ballot_is_bound_through_the_public_inputs() {
+        let entries = [entry("a", 0, 100)];
+        let history = history_commitment(&entries, &SALT);
+        let verify = |ballot: BallotInputs, proof: &[u8]| {
+            StacksVotingProofVerifier::verify_balance_proof(100, &history, ballot, proof)
+        };
+
+        let proof = forge(&entries, Claim::Balance(BaseElement::new(100))).unwrap();
+        assert_eq!(verify(ballot(), &proof), Ok(()));
+        for other in [
+            BallotInputs::from_parts(50, &[9; 32], "SIP-028", "against", &[3; 32], &[1; 32]),
+            BallotInputs::from_parts(50, &[9; 32], "SIP-029", "for", &[3; 32], &[1; 32]),
+            BallotInputs::from_parts(51, &[9; 32], "SIP-028", "for", &[3; 32], &[1; 32]),
+            BallotInputs::from_parts(50, &[8; 32], "SIP-028", "for", &[3; 32], &[1; 32]),
+            BallotInputs::from_parts(50, &[9; 32], "SIP-028", "for", &[3; 32], &[2; 32]),
+            BallotInputs::from_parts(50, &[9; 32], "SIP-028", "for", &[4; 32], &[1; 32]),
+        ] {
+            assert!(verify(other, &proof).is_err(), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn trace_sponge_is_rp64_256() {
+        let entries = [entry("a", 0, 100), entry(&"ab".repeat(32), 40, 0), entry("c", 0, 7)];
+        let trace = build_do_work_trace(&entries, &SALT);
+        let last = trace.length() - 1;
+        let digest = DIGEST.map(|column| trace.get(column.index(), last));
+        assert_eq!(Some(digest), history_from_bytes(&history_commitment(&entries, &SALT)));
+
+        // every entry is bound: its id and both amounts
+        let commitment = history_commitment(&entries, &SALT);
+        for changed in [entry("b", 0, 100), entry("a", 1, 100), entry("a", 0, 101)] {
+            assert_ne!(history_commitment(&[changed, entries[1].clone(), entries[2].clone()], &SALT), commitment);
+        }
+        assert_ne!(history_commitment(&entries[..2], &SALT), commitment);
+
+        // without the salt, the ledger alone doesn't give the commitment away
+        assert_ne!(history_commitment(&entries, &[0; 32]), commitment);
+        assert_ne!(history_commitment(&entries, &[8; 32]), commitment);
+    }
+
+    #[test]
+    fn nft_count_below_min_count_is_rejected() {
+        const COLLECTION: &str = "SP2J6ZY48GV1EZ5V2V5RB9MP66SW86PYKKNRV9EJ7.punks::punk";
+        let asset = digest_to_elements(&asset_identifier_hash(COLLECTION));
+        let holdings = |min_count: u64| Claim::Holdings { min_count: BaseElement::new(min_count), asset };
+        let verify = |min_count: u64, history: &[u8; 32], proof: &[u8]| {
+            StacksVotingProofVerifier::verify_nft_proof(min_count, COLLECTION, history, ballot(), proof)
+        };
+
+        let owned = [entry("mint-1", 0, 1), entry("mint-2", 0, 1)];
+        let history = history_commitment(&owned, &SALT);
+        let proof = forge(&owned, holdings(2)).unwrap();
+        assert_eq!(verify(2, &history, &proof), Ok(()));
+        assert!(verify(3, &history, &proof).is_err());
+        assert!(forge(&owned, holdings(3)).is_none_or(|proof| verify(3, &history, &proof).is_err()));
+
+        // minted and sold again, so none are owned
+        let sold = [entry("mint-1", 0, 1), entry("sale-1", 1, 0)];
+        let history = history_commitment(&sold, &SALT);
+        assert!(forge(&sold, holdings(1)).is_none_or(|proof| verify(1, &history, &proof).is_err()));
+        assert!(forge(&sold, holdings(1_000)).is_none_or(|proof| verify(1_000, &history, &proof).is_err()));
+    }
+
+    #[test]
+    fn unlock_height_is_the_latest_locks() {
+        // stacked, then extended without changing the amount
+        let entries = [lock("stack", 0, 500, 120), lock("extend", 500, 500, 140)];
+        let history = history_commitment(&entries, &SALT);
+        let locked = |unlock_height: u64| Claim::Locked { amount: BaseElement::new(500), unlock_height: BaseElement::new(unlock_height) };
+        let verify = |unlock_height: u64, proof: &[u8]| {
+            StacksVotingProofVerifier::verify_locked_proof(500, unlock_height, &history, ballot(), proof)
+        };
+
+        let proof = forge(&entries, locked(140)).unwrap();
+        assert_eq!(verify(140, &proof), Ok(()));
+        assert!(verify(120, &proof).is_err());
+
+        // a trace which keeps the unlock height of the first lock
+        let forged = forge_with(&entries, locked(120), |trace| {
+            for step in 0..trace.length() {
+                if trace.get(Column::UnlockHeight.index(), step) == BaseElement::new(140) {
+                    trace.set(Column::UnlockHeight.index(), step, BaseElement::new(120));
+                }
+            }
+        });
+        assert!(forged.is_none_or(|proof| verify(120, &proof).is_err()));
+    }
+
+    #[test]
+    fn quadratic_weight_must_be_the_square_root() {
+        // 27^2 = 729 <= 750 < 784 = 28^2
+        let entries = [entry("a", 0, 1_000), entry("b", 250, 0)];
+        let history = history_commitment(&entries, &SALT);
+        let verify = |weight: u128, proof: &[u8]| {
+            StacksVotingProofVerifier::verify_quadratic_proof(weight, &history, ballot(), proof)
+        };
+
+        let proof = forge(&entries, Claim::Quadratic(BaseElement::new(27))).unwrap();
+        assert_eq!(verify(27, &proof), Ok(()));
+        assert!(verify(26, &proof).is_err());
+        assert!(verify(28, &proof).is_err());
+
+        // too high breaks the lower range check, too low the upper one
+        for weight in [28, 1 << 20, 26, 0] {
+            let forged = forge(&entries, Claim::Quadratic(BaseElement::new(weight)));
+            assert!(forged.is_none_or(|proof| verify(weight.into(), &proof).is_err()), "weight {}", weight);
+        }
+    }
+}
@@ -1,26 +1,30 @@
 use winterfell::{
     crypto::{hashers::Blake3_256, DefaultRandomCoin},
-    math::{fields::f128::BaseElement, FieldElement},
+    math::{fields::f64::BaseElement, FieldElement},
     matrix::ColMatrix,
-    DefaultConstraintEvaluator, DefaultTraceLde, ProofOptions, Prover, StarkDomain, Trace,
-    TraceInfo, TracePolyTable, TraceTable,
+    DefaultConstraintEvaluator, DefaultTraceLde, ProofOptions, Prover, StarkDomain,
+    Trace, TraceInfo, TracePolyTable, TraceTable,
 };
 use winterfell::AuxRandElements;
 
-use super::{PublicInputs, WorkAir};
+use super::{trace::DIGEST, BallotInputs, Claim, PublicInputs, WorkAir};
 
 // We'll use BLAKE3 as the hash function during proof generation.
 type Blake3 = Blake3_256<BaseElement>;
 
 // Our prover needs to hold STARK protocol parameters which are specified via ProofOptions
-// struct.
+// struct, as well as the public inputs.
 pub struct WorkProver {
     options: ProofOptions,
+    claim: Claim,
+    ballot: BallotInputs,
 }
 
 impl WorkProver {
-    pub fn new(options: ProofOptions) -> Self {
-        Self { options }
+    // The claim decides what the proof discloses: the final balance, only that it meets a
+    // threshold, or the locked amount and its unlock height.
+    pub fn new(options: ProofOptions, claim: Claim, ballot: BallotInputs) -> Self {
+        Self { options, claim, ballot }
     }
 }
 
@@ -38,12 +42,15 @@ impl Prover for WorkProver {
     type ConstraintEvaluator<'a, E: FieldElement<BaseField = BaseElement>> =
         DefaultConstraintEvaluator<'a, WorkAir, E>;
 
-    // Our public inputs consist of the first and last value in the execution trace.
+    // Our public inputs consist of the claim about the balance in the last row of the
+    // execution trace, the ledger commitment next to it and the ballot the proof was
+    // generated for.
     fn get_pub_inputs(&self, trace: &Self::Trace) -> PublicInputs {
         let last_step = trace.length() - 1;
         PublicInputs {
-            balance: trace.get(0, 0),
-            height: trace.get(0, last_step),
+            claim: self.claim,
+            history: DIGEST.map(|column| trace.get(column.index(), last_step)),
+            ballot: self.ballot,
         }
     }
 
@@ -71,71 +78,3 @@ impl Prover for WorkProver {
         &self.options
     }
 }
-
-
-
-
-
-
-
-
-// Prover Implementation
-// ===========================================================================================
-
-// Our prover needs to hold STARK protocol parameters which are specified via ProofOptions
-// struct.
-// struct WorkProver {
-//     options: ProofOptions
-// }
-
-// impl WorkProver {
-//     pub fn new(options: ProofOptions) -> Self {
-//         Self { options }
-//     }
-// }
-
-// // When implementing the Prover trait we set the `Air` associated type to the AIR of the
-// // computation we defined previously, and set the `Trace` associated type to `TraceTable`
-// // struct as we don't need to define a custom trace for our computation. For other
-// // associated types, we'll use default implementation provided by Winterfell.
-// impl Prover for WorkProver {
-//     type BaseField = BaseElement;
-//     type Air = WorkAir;
-//     type Trace = TraceTable<Self::BaseField>;
-//     type HashFn = Blake3_256<Self::BaseField>;
-//     type RandomCoin = DefaultRandomCoin<Self::HashFn>;
-//     type TraceLde<E: FieldElement<BaseField = Self::BaseField>> = DefaultTraceLde<E, Self::HashFn>;
-//     type ConstraintEvaluator<'a, E: FieldElement<BaseField = Self::BaseField>> =
-//         DefaultConstraintEvaluator<'a, Self::Air, E>;
-
-//     // Our public inputs consist of the first and last value in the execution trace.
-//     fn get_pub_inputs(&self, trace: &Self::Trace) -> PublicInputs {
-//         let last_step = trace.length() - 1;
-//         PublicInputs {
-//             balance_at_height: trace.get(0, 0),
-//             block_proof_height: trace.get(0, last_step),
-//         }
-//     }
-
-//     fn options(&self) -> &ProofOptions {
-//         &self.options
-//     }
-
-//     fn new_trace_lde<E: FieldElement<BaseField = Self::BaseField>>(
-//         &self,
-//         trace_info: &TraceInfo,
-//         main_trace: &ColMatrix<Self::BaseField>,
-//         domain: &StarkDomain<Self::BaseField>,
-//     ) -> (Self::TraceLde<E>, TracePolyTable<E>) {
-//         DefaultTraceLde::new(trace_info, main_trace, domain)
-//     }
-
-//     fn new_evaluator<'a, E: FieldElement<BaseField = Self::BaseField>>(
-//         &self,
-//         air: &'a Self::Air,
-//         aux_rand_elements: Option<AuxRandElements<E>>,
-//         composition_coefficients: winterfell::ConstraintCompositionCoefficients<E>,
-//     ) -> Self::ConstraintEvaluator<'a, E> {
-//         DefaultConstraintEvaluator::new(air, aux_rand_elements, composition_coefficients)
-//     }
-// }
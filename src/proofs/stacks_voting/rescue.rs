@@ -0,0 +1,155 @@
+use std::ops::Range;
+
+use winterfell::crypto::hashers::Rp64_256;
+use winterfell::math::{fields::f64::BaseElement, FieldElement};
+
+// Rescue Prime
+// ===========================================================================================
+
+// The hash the trace uses to commit to the ledger: winter-crypto's Rp64_256, the Rescue Prime
+// instance over the 64-bit field with a state of 12 elements, a rate of 8, alpha = 7 and 7
+// rounds, specified in https://eprint.iacr.org/2020/1143. The sponge in the trace is
+// `Rp64_256::hash_elements`, so a commitment can be recomputed with the library alone. One
+// permutation takes one 8 row cycle of the trace, the last row of the cycle absorbing the next
+// input.
+
+pub const STATE_WIDTH: usize = Rp64_256::STATE_WIDTH;
+
+// The state elements inputs are added to; the others are the capacity.
+pub const RATE_RANGE: Range<usize> = Rp64_256::RATE_RANGE;
+
+pub const RATE: usize = RATE_RANGE.end - RATE_RANGE.start;
+
+pub const CAPACITY_RANGE: Range<usize> = Rp64_256::CAPACITY_RANGE;
+
+// The first capacity element, which `hash_elements` sets to the number of elements hashed.
+pub const LENGTH_INDEX: usize = CAPACITY_RANGE.start;
+
+// The state elements a digest is read from.
+pub const DIGEST_RANGE: Range<usize> = Rp64_256::DIGEST_RANGE;
+
+pub const NUM_ROUNDS: usize = Rp64_256::NUM_ROUNDS;
+
+// Rows taken by one permutation: one per round plus the absorbing row.
+pub const CYCLE_LENGTH: usize = NUM_ROUNDS + 1;
+
+// Applies round `round` of the permutation.
+pub fn apply_round(state: &mut [BaseElement; STATE_WIDTH], round: usize) {
+    Rp64_256::apply_round(state, round);
+}
+
+// Adds `input` to the rate part of the state.
+pub fn absorb(state: &mut [BaseElement; STATE_WIDTH], input: &[BaseElement; RATE]) {
+    for (x, value) in state[RATE_RANGE].iter_mut().zip(input.iter()) {
+        *x += *value;
+    }
+}
+
+fn multiply<E: FieldElement + From<BaseElement>>(matrix: &[[BaseElement; STATE_WIDTH]; STATE_WIDTH], vector: &[E]) -> [E; STATE_WIDTH] {
+    let mut result = [E::ZERO; STATE_WIDTH];
+    for (value, row) in result.iter_mut().zip(matrix.iter()) {
+        for (coefficient, element) in row.iter().zip(vector.iter()) {
+            *value += E::from(*coefficient) * *element;
+        }
+    }
+    result
+}
+
+fn pow7<E: FieldElement>(x: E) -> E {
+    let x2 = x.square();
+    x2.square() * x2 * x
+}
+
+// Evaluates a round between `current` and `next` into `result`; all zeros when `next` is
+// `current` after the round whose constants are given. The inverse S-box is avoided by undoing
+// the second half of the round on `next`, which keeps the constraints at degree 7.
+pub fn enforce_round<E: FieldElement + From<BaseElement>>(current: &[E], next: &[E], ark1: &[E], inv_ark2: &[E], result: &mut [E]) {
+    let powered: Vec<E> = current.iter().map(|x| pow7(*x)).collect();
+    let forward = multiply(&Rp64_256::MDS, &powered);
+    let backward = multiply(&Rp64_256::INV_MDS, next);
+    for i in 0..STATE_WIDTH {
+        result[i] = forward[i] + ark1[i] - pow7(backward[i] - inv_ark2[i]);
+    }
+}
+
+// Periodic columns for the AIR, one cycle long: a mask selecting the round rows, followed by
+// the ark1 and inv_mds * ark2 constants of each state element. The constants are zero on the
+// absorbing row.
+pub fn periodic_columns() -> Vec<Vec<BaseElement>> {
+    let inv_ark2 = Rp64_256::ARK2.map(|constants| multiply(&Rp64_256::INV_MDS, &constants));
+    let mut round_mask = vec![BaseElement::ONE; CYCLE_LENGTH];
+    round_mask[NUM_ROUNDS] = BaseElement::ZERO;
+    let mut columns = vec![round_mask];
+    for constants in [&Rp64_256::ARK1, &inv_ark2] {
+        for i in 0..STATE_WIDTH {
+            let mut column: Vec<BaseElement> = constants.iter().map(|round| round[i]).collect();
+            column.push(BaseElement::ZERO);
+            columns.push(column);
+        }
+    }
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rp64_256 applied to [0, 1, ..., 11], from the sage reference implementation; the same
+    // vector is checked by winter-crypto's own tests.
+    const PERMUTED: [u64; STATE_WIDTH] = [
+        11084501481526603421,
+        6291559951628160880,
+        13626645864671311919,
+        18397438323058963117,
+        7443014167353970324,
+        17930833023906771425,
+        4275355080008025761,
+        7676681476902901785,
+        3460534574143792217,
+        11912731278641497187,
+        8104899243369883110,
+        674509706691634438,
+    ];
+
+    fn round_constants(round: usize) -> (Vec<BaseElement>, Vec<BaseElement>) {
+        let columns = periodic_columns();
+        let ark1 = columns[1..1 + STATE_WIDTH].iter().map(|column| column[round]).collect();
+        let inv_ark2 = columns[1 + STATE_WIDTH..].iter().map(|column| column[round]).collect();
+        (ark1, inv_ark2)
+    }
+
+    #[test]
+    fn round_constraints_accept_the_reference_permutation() {
+        let mut state: [BaseElement; STATE_WIDTH] = std::array::from_fn(|i| BaseElement::new(i as u64));
+        for round in 0..NUM_ROUNDS {
+            let current = state;
+            apply_round(&mut state, round);
+            let (ark1, inv_ark2) = round_constants(round);
+            let mut result = [BaseElement::ZERO; STATE_WIDTH];
+            enforce_round(&current, &state, &ark1, &inv_ark2, &mut result);
+            assert_eq!(result, [BaseElement::ZERO; STATE_WIDTH], "round {}", round);
+        }
+        assert_eq!(state, PERMUTED.map(BaseElement::new));
+    }
+
+    #[test]
+    fn round_constraints_reject_a_wrong_state() {
+        let current: [BaseElement; STATE_WIDTH] = std::array::from_fn(|i| BaseElement::new(i as u64));
+        let (ark1, inv_ark2) = round_constants(0);
+        for i in 0..STATE_WIDTH {
+            let mut next = current;
+            apply_round(&mut next, 0);
+            next[i] += BaseElement::ONE;
+            let mut result = [BaseElement::ZERO; STATE_WIDTH];
+            enforce_round(&current, &next, &ark1, &inv_ark2, &mut result);
+            assert_ne!(result, [BaseElement::ZERO; STATE_WIDTH], "element {}", i);
+        }
+        // the constants of another round don't fit either
+        let mut next = current;
+        apply_round(&mut next, 0);
+        let (ark1, inv_ark2) = round_constants(1);
+        let mut result = [BaseElement::ZERO; STATE_WIDTH];
+        enforce_round(&current, &next, &ark1, &inv_ark2, &mut result);
+        assert_ne!(result, [BaseElement::ZERO; STATE_WIDTH]);
+    }
+}
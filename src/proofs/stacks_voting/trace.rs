@@ -0,0 +1,349 @@
+use winterfell::math::{fields::f64::BaseElement, FieldElement};
+use winterfell::{Trace, TraceInfo, TraceTable};
+
+use super::rescue::{self, CAPACITY_RANGE, CYCLE_LENGTH, DIGEST_RANGE, LENGTH_INDEX, NUM_ROUNDS, RATE, STATE_WIDTH};
+
+// Trace Schema
+// ===========================================================================================
+
+// Layout of the stacks_voting execution trace. Both the trace builder and the AIR read column
+// positions from here, so the two can't drift apart again.
+//
+// The trace is split into cycles of CYCLE_LENGTH rows. Cycle 0 is empty and cycle k + 1
+// carries ledger entry k: its id, as five 56-bit limbs, and the amounts it moves. The
+// transition into the first row of a cycle applies the cycle's amounts to the balance, so the
+// balance in the cycle's rows already includes its entry; the balance is carried over
+// everywhere else. Padding cycles (is_real = 0) leave the balance untouched. The unlock height
+// column follows the same pattern, taking the unlock height of each real entry, so the last
+// row holds the one of the latest lock.
+//
+// The hash columns run the Rp64_256 sponge over the ledger, one permutation per cycle. The first
+// row holds the initial state: the number of absorbed elements in the capacity, and a secret
+// salt in the rate, which the AIR leaves unconstrained so the commitment can't be recomputed
+// from the public ledger. The
+// transition into the first row of a cycle absorbs the entry's id and amounts, and the other
+// transitions apply one round each, so the digest part of the last row is the commitment to
+// the whole ledger, which the AIR asserts against the public inputs.
+//
+// The last RANGE_BITS + 1 rows also carry the bit decomposition of
+// `2^RANGE_BITS + balance - lower_bound`, most significant bit first, with a running
+// accumulator next to it. The leading bit is asserted to be 1, so the accumulator in the last
+// row proves that `balance - lower_bound` is in [0, 2^RANGE_BITS). The leading bit also keeps
+// the bit column from being all zeros, which Winterfell's debug degree checks reject. A second
+// pair of columns does the same for `upper_bound - balance`, bounding the balance from above.
+//
+// Nothing about the ballot is in the trace: the proposal and vote are only bound to the proof
+// through its public inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Balance,
+    IsReal,
+    Debit,
+    Credit,
+    Unlock,
+    UnlockHeight,
+    RangeBit,
+    RangeAcc,
+    UpperBit,
+    UpperAcc,
+    Id0,
+    Id1,
+    Id2,
+    Id3,
+    Id4,
+    Hash0,
+    Hash1,
+    Hash2,
+    Hash3,
+    Hash4,
+    Hash5,
+    Hash6,
+    Hash7,
+    Hash8,
+    Hash9,
+    Hash10,
+    Hash11,
+}
+
+impl Column {
+    // All columns, in trace order.
+    pub const ALL: [Column; 27] = [
+        Column::Balance,
+        Column::IsReal,
+        Column::Debit,
+        Column::Credit,
+        Column::Unlock,
+        Column::UnlockHeight,
+        Column::RangeBit,
+        Column::RangeAcc,
+        Column::UpperBit,
+        Column::UpperAcc,
+        Column::Id0,
+        Column::Id1,
+        Column::Id2,
+        Column::Id3,
+        Column::Id4,
+        Column::Hash0,
+        Column::Hash1,
+        Column::Hash2,
+        Column::Hash3,
+        Column::Hash4,
+        Column::Hash5,
+        Column::Hash6,
+        Column::Hash7,
+        Column::Hash8,
+        Column::Hash9,
+        Column::Hash10,
+        Column::Hash11,
+    ];
+
+    // The sponge state.
+    pub const HASH: [Column; STATE_WIDTH] = [
+        Column::Hash0,
+        Column::Hash1,
+        Column::Hash2,
+        Column::Hash3,
+        Column::Hash4,
+        Column::Hash5,
+        Column::Hash6,
+        Column::Hash7,
+        Column::Hash8,
+        Column::Hash9,
+        Column::Hash10,
+        Column::Hash11,
+    ];
+
+    // What a cycle absorbs: the entry's id limbs, its amounts and the unlock height of a lock.
+    pub const INPUT: [Column; RATE] = [
+        Column::Id0,
+        Column::Id1,
+        Column::Id2,
+        Column::Id3,
+        Column::Id4,
+        Column::Debit,
+        Column::Credit,
+        Column::Unlock,
+    ];
+
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Column::Balance => "balance",
+            Column::IsReal => "is_real",
+            Column::Debit => "stx_sent",
+            Column::Credit => "stx_received",
+            Column::Unlock => "unlock",
+            Column::UnlockHeight => "unlock_height",
+            Column::RangeBit => "range_bit",
+            Column::RangeAcc => "range_acc",
+            Column::UpperBit => "upper_bit",
+            Column::UpperAcc => "upper_acc",
+            Column::Id0 => "id_0",
+            Column::Id1 => "id_1",
+            Column::Id2 => "id_2",
+            Column::Id3 => "id_3",
+            Column::Id4 => "id_4",
+            Column::Hash0 => "hash_0",
+            Column::Hash1 => "hash_1",
+            Column::Hash2 => "hash_2",
+            Column::Hash3 => "hash_3",
+            Column::Hash4 => "hash_4",
+            Column::Hash5 => "hash_5",
+            Column::Hash6 => "hash_6",
+            Column::Hash7 => "hash_7",
+            Column::Hash8 => "hash_8",
+            Column::Hash9 => "hash_9",
+            Column::Hash10 => "hash_10",
+            Column::Hash11 => "hash_11",
+        }
+    }
+
+    // Reads this column out of a row of the trace or of an evaluation frame.
+    pub fn of<E: Copy>(self, row: &[E]) -> E {
+        row[self.index()]
+    }
+
+    // Reads several columns out of a row.
+    pub fn all_of<E: Copy, const N: usize>(columns: [Column; N], row: &[E]) -> [E; N] {
+        columns.map(|column| column.of(row))
+    }
+
+    // Reads what a cycle absorbs out of its first row.
+    pub fn input_of<E: Copy>(row: &[E]) -> [E; RATE] {
+        Column::all_of(Column::INPUT, row)
+    }
+}
+
+pub const TRACE_WIDTH: usize = Column::ALL.len();
+
+// Column::ALL must list every column exactly once, at its own index.
+const _: () = {
+    let mut i = 0;
+    while i < TRACE_WIDTH {
+        assert!(Column::ALL[i].index() == i);
+        i += 1;
+    }
+};
+
+// The ledger commitment is the digest part of the final sponge state.
+pub const HISTORY_LIMBS: usize = DIGEST_RANGE.end - DIGEST_RANGE.start;
+
+// The sponge columns the ledger commitment is read from.
+pub const DIGEST: [Column; HISTORY_LIMBS] = [Column::Hash4, Column::Hash5, Column::Hash6, Column::Hash7];
+
+// Balances are expressed in micro-STX and the total supply fits comfortably in 62 bits. The
+// range checks decompose values below 2^(RANGE_BITS + 1), which must stay below the field
+// modulus, just under 2^64, so a negative difference can't pass for a positive one.
+pub const RANGE_BITS: usize = 62;
+
+// The range check lives in the last RANGE_BITS + 1 rows and must not reach row 0, where the
+// accumulator is anchored to zero.
+pub const MIN_TRACE_LENGTH: usize = (2 * RANGE_BITS).next_power_of_two();
+
+// The number of elements the sponge absorbs over a trace of `trace_length` rows, one block per
+// cycle; it starts in the capacity, as `Rp64_256::hash_elements` puts it there.
+pub fn sponge_length(trace_length: usize) -> BaseElement {
+    BaseElement::new((trace_length / CYCLE_LENGTH * RATE) as u64)
+}
+
+// Whether the transition out of `step` enters a new cycle.
+pub fn is_cycle_end(step: usize) -> bool {
+    step % CYCLE_LENGTH == NUM_ROUNDS
+}
+
+// First row of the range check section.
+pub fn range_start(trace_length: usize) -> usize {
+    trace_length - RANGE_BITS - 1
+}
+
+// 2^RANGE_BITS, the weight of the leading bit of the range check.
+pub fn range_offset() -> BaseElement {
+    BaseElement::new(1 << RANGE_BITS)
+}
+
+// The range checks in the trace: the bit and accumulator columns, and whether the checked
+// value is `balance - bound` or `bound - balance`.
+pub const RANGE_CHECKS: [(Column, Column, bool); 2] = [
+    (Column::RangeBit, Column::RangeAcc, false),
+    (Column::UpperBit, Column::UpperAcc, true),
+];
+
+// The value a range check decomposes, before adding the leading bit.
+pub fn range_value<E: FieldElement>(balance: E, bound: E, upper: bool) -> E {
+    if upper {
+        bound - balance
+    } else {
+        balance - bound
+    }
+}
+
+// Checks that a trace handed to the AIR has the shape described above.
+pub fn check_trace_info(trace_info: &TraceInfo) {
+    assert_eq!(TRACE_WIDTH, trace_info.width(), "unexpected stacks_voting trace width");
+    assert!(
+        trace_info.length() >= MIN_TRACE_LENGTH,
+        "stacks_voting trace must have at least {} rows",
+        MIN_TRACE_LENGTH
+    );
+}
+
+// Debug Checks
+// ===========================================================================================
+
+// Replays the AIR rules against a filled trace and panics with the offending column and row.
+// The prover only reports failing constraints by index and only in debug builds, so this gives
+// a readable error for malformed histories before any proving work is done.
+pub fn debug_check_trace(trace: &TraceTable<BaseElement>, lower_bound: BaseElement, upper_bound: BaseElement) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    assert_eq!(TRACE_WIDTH, trace.width(), "unexpected stacks_voting trace width");
+    let length = trace.length();
+    assert!(length.is_power_of_two() && length >= MIN_TRACE_LENGTH, "bad trace length {}", length);
+
+    let cell = |column: Column, step: usize| trace.get(column.index(), step);
+    let fail = |column: Column, step: usize, what: &str| -> ! {
+        panic!("stacks_voting trace: column `{}` at row {}: {}", column.name(), step, what)
+    };
+
+    for column in [Column::Balance, Column::UnlockHeight] {
+        if cell(column, 0) != BaseElement::ZERO {
+            fail(column, 0, "history must start from an empty balance and no lock");
+        }
+    }
+    for (_, acc, _) in RANGE_CHECKS {
+        if cell(acc, 0) != BaseElement::ZERO {
+            fail(acc, 0, "accumulator must start from zero");
+        }
+    }
+    for i in CAPACITY_RANGE {
+        let expected = if i == LENGTH_INDEX { sponge_length(length) } else { BaseElement::ZERO };
+        if cell(Column::HASH[i], 0) != expected {
+            fail(Column::HASH[i], 0, "sponge must start from the initial capacity");
+        }
+    }
+    for step in 0..length {
+        let is_real = cell(Column::IsReal, step);
+        if is_real != BaseElement::ZERO && is_real != BaseElement::ONE {
+            fail(Column::IsReal, step, "flag is not binary");
+        }
+        if is_real == BaseElement::ZERO {
+            for column in [Column::Debit, Column::Credit, Column::Unlock] {
+                if cell(column, step) != BaseElement::ZERO {
+                    fail(column, step, "padding row moves funds");
+                }
+            }
+        }
+
+        for (bit_column, _, _) in RANGE_CHECKS {
+            let bit = cell(bit_column, step);
+            if bit != BaseElement::ZERO && (bit != BaseElement::ONE || step < range_start(length)) {
+                fail(bit_column, step, "bit is not binary or outside the range check rows");
+            }
+        }
+        if step + 1 < length {
+            let mut balance = cell(Column::Balance, step);
+            let mut unlock_height = cell(Column::UnlockHeight, step);
+            let mut hash = Column::HASH.map(|column| cell(column, step));
+            if is_cycle_end(step) {
+                let next_is_real = cell(Column::IsReal, step + 1);
+                balance += next_is_real * (cell(Column::Credit, step + 1) - cell(Column::Debit, step + 1));
+                unlock_height += next_is_real * (cell(Column::Unlock, step + 1) - unlock_height);
+                let row: Vec<BaseElement> = Column::ALL.iter().map(|column| cell(*column, step + 1)).collect();
+                rescue::absorb(&mut hash, &Column::input_of(&row).map(|value| next_is_real * value));
+            } else {
+                rescue::apply_round(&mut hash, step % CYCLE_LENGTH);
+            }
+            if cell(Column::Balance, step + 1) != balance {
+                fail(Column::Balance, step + 1, "balance does not follow the transaction");
+            }
+            if cell(Column::UnlockHeight, step + 1) != unlock_height {
+                fail(Column::UnlockHeight, step + 1, "unlock height does not follow the lock");
+            }
+            for (column, value) in Column::HASH.iter().zip(hash.iter()) {
+                if cell(*column, step + 1) != *value {
+                    fail(*column, step + 1, "sponge state does not follow the ledger");
+                }
+            }
+            for (bit, acc, _) in RANGE_CHECKS {
+                if cell(acc, step + 1) != cell(acc, step).double() + cell(bit, step + 1) {
+                    fail(acc, step + 1, "accumulator does not follow the bits");
+                }
+            }
+        }
+    }
+
+    let last = length - 1;
+    for ((bit, acc, upper), bound) in RANGE_CHECKS.into_iter().zip([lower_bound, upper_bound]) {
+        if cell(bit, range_start(length)) != BaseElement::ONE {
+            fail(bit, range_start(length), "leading bit is not set");
+        }
+        let expected = range_value(cell(Column::Balance, last), bound, upper) + range_offset();
+        if cell(acc, last) != expected {
+            fail(acc, last, &format!("balance is out of range of the bound ({})", expected.as_int()));
+        }
+    }
+}
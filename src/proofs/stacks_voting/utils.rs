@@ -1,29 +1,134 @@
-use crate::stacks::utils::{Transaction, TransactionDetails};
-
-pub fn pad_to_power_of_two(transactions: Vec<Transaction>) -> Vec<Transaction> {
-    let current_length = transactions.len();
-    let next_power_of_two = current_length.next_power_of_two();
-
-    let dummy_transaction = Transaction {
-        stx_received: "0".to_string(),
-        stx_sent: "0".to_string(),
-        tx: TransactionDetails {
-            tx_id: "0".to_string(),
-            burn_block_height: 0,
-            nonce: 0,
-            block_height: 0,
-            tx_index: 0,
-            tx_status: "".to_string(),
-            parent_block_hash: "".to_string(),
-            tx_type: "".to_string(),
-        },
-    };
-
-    // Create a new vector with the original transactions
-    let mut padded_transactions = transactions;
-
-    // Add dummy transactions to match the length to the next power of two
-    padded_transactions.extend((0..(next_power_of_two - current_length)).map(|_| dummy_transaction.clone()));
-
-    padded_transactions
+use sha2::{Digest as _, Sha256};
+use winterfell::crypto::{hashers::Rp64_256, Digest, ElementHasher};
+use winterfell::math::{fields::f64::BaseElement, FieldElement, StarkField};
+
+use crate::stacks::history::LedgerEntry;
+
+use super::rescue::{CYCLE_LENGTH, RATE};
+use super::trace::{HISTORY_LIMBS, MIN_TRACE_LENGTH};
+
+// Number of rows needed for `entries` ledger entries: one cycle per entry plus the empty first
+// cycle, rounded up to a power of two.
+pub fn padded_trace_length(entries: usize) -> usize {
+    ((entries + 1).next_power_of_two() * CYCLE_LENGTH).max(MIN_TRACE_LENGTH)
+}
+
+const ENTRY_DOMAIN: &[u8] = b"stxeco-zk-voting/entry";
+
+// The 32 byte id a ledger entry is committed to under: its transaction id. Miner rewards have
+// none, so their `miner-reward-<height>` label is hashed into one.
+pub fn entry_id(tx_id: &str) -> [u8; 32] {
+    hex::decode(tx_id.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .unwrap_or_else(|| {
+            let mut hasher = Sha256::new();
+            hasher.update(ENTRY_DOMAIN);
+            hasher.update(tx_id.as_bytes());
+            hasher.finalize().into()
+        })
+}
+
+// What the sponge absorbs for an entry: the limbs of its id, the amounts it moves and the
+// unlock height of a lock. Amounts the proofs can't handle, see `check_amounts`, are reduced
+// into the field so that every ledger still has a commitment.
+pub fn entry_input(entry: &LedgerEntry) -> [BaseElement; RATE] {
+    let id = digest_to_elements(&entry_id(&entry.tx_id));
+    let mut input = [BaseElement::ZERO; RATE];
+    input[..DIGEST_LIMBS].copy_from_slice(&id);
+    input[DIGEST_LIMBS] = amount(entry.debit);
+    input[DIGEST_LIMBS + 1] = amount(entry.credit);
+    input[DIGEST_LIMBS + 2] = BaseElement::new(entry.unlock_height);
+    input
+}
+
+fn amount(value: u128) -> BaseElement {
+    BaseElement::new((value % u128::from(BaseElement::MODULUS)) as u64)
+}
+
+// What the sponge starts from in its rate: the limbs of the salt blinding the commitment.
+pub fn salt_input(salt: &[u8; 32]) -> [BaseElement; RATE] {
+    let mut input = [BaseElement::ZERO; RATE];
+    input[..DIGEST_LIMBS].copy_from_slice(&digest_to_elements(salt));
+    input
+}
+
+// The commitment a proof over `entries` blinded with `salt` exposes. The trace runs
+// Rp64_256's sponge over one block of RATE elements per cycle: the salt for the first cycle,
+// then each entry's input, then empty blocks for the padding cycles, so the commitment is
+// `hash_elements` over the same blocks. Only someone who knows the salt can recompute it from
+// the ledger.
+pub fn history_commitment(entries: &[LedgerEntry], salt: &[u8; 32]) -> [u8; 32] {
+    let cycles = padded_trace_length(entries.len()) / CYCLE_LENGTH;
+    let mut elements = vec![BaseElement::ZERO; cycles * RATE];
+    elements[..RATE].copy_from_slice(&salt_input(salt));
+    for (block, entry) in elements.chunks_exact_mut(RATE).skip(1).zip(entries) {
+        block.copy_from_slice(&entry_input(entry));
+    }
+    Rp64_256::hash_elements(&elements).as_bytes()
+}
+
+// A history commitment travels as the bytes of an Rp64_256 digest: its four field elements,
+// 8 little-endian bytes each. None when a limb is not a canonical field element.
+pub fn history_from_bytes(bytes: &[u8; 32]) -> Option<[BaseElement; HISTORY_LIMBS]> {
+    let mut history = [BaseElement::ZERO; HISTORY_LIMBS];
+    for (element, chunk) in history.iter_mut().zip(bytes.chunks_exact(8)) {
+        *element = BaseElement::try_from(u64::from_le_bytes(chunk.try_into().unwrap())).ok()?;
+    }
+    Some(history)
+}
+
+const BALLOT_DOMAIN: &[u8] = b"stxeco-zk-voting/ballot";
+
+// Commits to the proposal and the vote cast on it. Both strings are length prefixed, so no two
+// different ballots share an encoding. A proposal only has a few votes, so the commitment is
+// blinded with a secret drawn for each ballot; without it, hashing every option would open it.
+pub fn ballot_commitment(proposal: &str, vote: &str, blinding: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(BALLOT_DOMAIN);
+    for field in [proposal, vote] {
+        hasher.update((field.len() as u32).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    hasher.update(blinding);
+    hasher.finalize().into()
+}
+
+const ASSET_DOMAIN: &[u8] = b"stxeco-zk-voting/asset";
+
+// Identifies the SIP-010 token a balance is proven in, e.g. `SP...dao-token::dao-token`.
+pub fn asset_identifier_hash(asset_identifier: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(ASSET_DOMAIN);
+    hasher.update(asset_identifier.as_bytes());
+    hasher.finalize().into()
+}
+
+// Bytes per limb when byte strings are used as public inputs; 7 bytes always fit below the
+// field modulus, so no two strings share limbs.
+const LIMB_BYTES: usize = 7;
+
+pub const DIGEST_LIMBS: usize = 32usize.div_ceil(LIMB_BYTES);
+
+pub const HEIGHT_LIMBS: usize = 16usize.div_ceil(LIMB_BYTES);
+
+fn to_limbs<const N: usize>(bytes: &[u8]) -> [BaseElement; N] {
+    let mut limbs = [BaseElement::ZERO; N];
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(LIMB_BYTES)) {
+        let mut value = [0u8; 8];
+        value[8 - chunk.len()..].copy_from_slice(chunk);
+        *limb = BaseElement::new(u64::from_be_bytes(value));
+    }
+    limbs
+}
+
+// Splits a 32 byte digest into 56-bit limbs, so it can be used as public inputs without
+// losing any bits.
+pub fn digest_to_elements(digest: &[u8; 32]) -> [BaseElement; DIGEST_LIMBS] {
+    to_limbs(digest)
+}
+
+// Splits a block height into 56-bit limbs, the same way.
+pub fn height_to_elements(height: u128) -> [BaseElement; HEIGHT_LIMBS] {
+    to_limbs(&height.to_be_bytes())
 }
@@ -1,31 +1,181 @@
-use crate::proofs::ProofVerifier;
+use std::fmt;
+
 use winterfell::{
-    crypto::{hashers::Blake3_256, DefaultRandomCoin}, math::fields::f128::BaseElement, Proof
+    crypto::{hashers::Blake3_256, DefaultRandomCoin}, math::fields::f64::BaseElement, Proof, VerifierError
 };
 
-use super::{PublicInputs, StacksVotingProofVerifier, WorkAir};
+use super::{asset_identifier_hash, digest_to_elements, trace::{HISTORY_LIMBS, RANGE_BITS}, utils::history_from_bytes, BallotInputs, Claim, PublicInputs, StacksVotingProofVerifier, WorkAir};
+
+// Why a proof was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationFailure {
+    // The proof (or one of the inputs describing it) can't be decoded.
+    BadEncoding(String),
+    // The proof was generated with parameters below the security level we accept.
+    InsufficientSecurity(String),
+    // The proof does not attest to the claimed public inputs. Winterfell can't tell a forged
+    // trace from a proof checked against other public inputs, so both end up here.
+    ConstraintFailure(String),
+    // The public inputs don't describe the ballot they were submitted with.
+    PublicInputMismatch(String),
+    // The block the proof is anchored to is not the canonical block at its height, e.g.
+    // because it was reorged away.
+    NotCanonical(String),
+}
+
+impl VerificationFailure {
+    // Stable identifier for API responses.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            VerificationFailure::BadEncoding(_) => "bad_encoding",
+            VerificationFailure::InsufficientSecurity(_) => "insufficient_security",
+            VerificationFailure::ConstraintFailure(_) => "constraint_failure",
+            VerificationFailure::PublicInputMismatch(_) => "public_input_mismatch",
+            VerificationFailure::NotCanonical(_) => "not_canonical",
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            VerificationFailure::BadEncoding(detail)
+            | VerificationFailure::InsufficientSecurity(detail)
+            | VerificationFailure::ConstraintFailure(detail)
+            | VerificationFailure::PublicInputMismatch(detail)
+            | VerificationFailure::NotCanonical(detail) => detail,
+        }
+    }
+}
+
+impl fmt::Display for VerificationFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.reason(), self.detail())
+    }
+}
+
+impl From<VerifierError> for VerificationFailure {
+    fn from(err: VerifierError) -> Self {
+        match err {
+            VerifierError::ProofDeserializationError(_)
+            | VerifierError::InconsistentBaseField
+            | VerifierError::UnsupportedFieldExtension(_) => VerificationFailure::BadEncoding(err.to_string()),
+            VerifierError::InsufficientConjecturedSecurity(_, _)
+            | VerifierError::InsufficientProvenSecurity(_, _)
+            | VerifierError::UnacceptableProofOptions => VerificationFailure::InsufficientSecurity(err.to_string()),
+            _ => VerificationFailure::ConstraintFailure(err.to_string()),
+        }
+    }
+}
+
+// Every proof exposes the commitment to the ledger its balance was computed over. Verifiers
+// holding a trusted commitment, see `history_commitment`, pass it in to check the proof was
+// computed over exactly that ledger.
+impl StacksVotingProofVerifier {
+    // Verifies a proof which discloses the voter's balance at the ballot's height.
+    pub fn verify_balance_proof(balance_at_height: u128, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Balance(bounded(balance_at_height, "balance")?),
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
+    }
+
+    // Verifies a proof which only discloses that the voter's balance at the ballot's height
+    // is at least `threshold`.
+    pub fn verify_threshold_proof(threshold: u128, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Threshold(bounded(threshold, "threshold")?),
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
+    }
+
+    // Verifies a proof which discloses the voter's balance in the SIP-010 token
+    // `asset_identifier` at the ballot's height.
+    pub fn verify_token_proof(balance: u128, asset_identifier: &str, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Token {
+                balance: bounded(balance, "balance")?,
+                asset: digest_to_elements(&asset_identifier_hash(asset_identifier)),
+            },
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
+    }
+
+    // Verifies a proof which only discloses that the voter owned at least `min_count` tokens of
+    // the SIP-009 collection `asset_identifier` at the ballot's height.
+    pub fn verify_nft_proof(min_count: u64, asset_identifier: &str, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Holdings {
+                min_count: bounded(min_count.into(), "min_count")?,
+                asset: digest_to_elements(&asset_identifier_hash(asset_identifier)),
+            },
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
+    }
 
-impl ProofVerifier for StacksVotingProofVerifier {
-    fn verify_proof(balance_at_height: u128, block_proof_height: u128, proof_in: Vec<u8>) -> bool {
-        // Your proof generation logic for `proof1`
-        // Call the `proof1` specific function (this is just an example)
-        verify_stacks_voting_proof(balance_at_height, block_proof_height, &proof_in)
+    // Verifies a proof which discloses the STX the voter had locked at the ballot's height,
+    // and the burnchain height they unlock at.
+    pub fn verify_locked_proof(locked: u128, unlock_height: u64, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Locked {
+                amount: bounded(locked, "locked amount")?,
+                unlock_height: BaseElement::try_from(unlock_height).map_err(VerificationFailure::PublicInputMismatch)?,
+            },
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
     }
+
+    // Verifies a proof which only discloses the voter's quadratic voting weight at the
+    // ballot's height, i.e. that weight^2 <= balance < (weight + 1)^2.
+    pub fn verify_quadratic_proof(weight: u128, history: &[u8; 32], ballot: BallotInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+        // larger weights have bounds which don't fit in the range checks, and with them the
+        // checks no longer pin the balance
+        if weight >= 1u128 << (RANGE_BITS / 2) {
+            return Err(VerificationFailure::PublicInputMismatch(format!("weight must be below 2^{}", RANGE_BITS / 2)));
+        }
+        let pub_inputs = PublicInputs {
+            history: decode_history(history)?,
+            claim: Claim::Quadratic(BaseElement::new(weight as u64)),
+            ballot,
+        };
+        verify_stacks_voting_proof(pub_inputs, proof_in)
+    }
+}
+
+fn decode_history(history: &[u8; 32]) -> Result<[BaseElement; HISTORY_LIMBS], VerificationFailure> {
+    history_from_bytes(history)
+        .ok_or_else(|| VerificationFailure::BadEncoding("history commitment is not four field elements".to_string()))
 }
 
-fn verify_stacks_voting_proof(balance_at_height_in: u128, block_proof_height_in: u128, proof_in: &[u8]) -> bool {
+// The values the range checks compare the balance with must fit in RANGE_BITS bits; larger
+// ones wrap around the field, and the checks no longer bound the balance.
+fn bounded(value: u128, name: &str) -> Result<BaseElement, VerificationFailure> {
+    if value >= 1u128 << RANGE_BITS {
+        return Err(VerificationFailure::PublicInputMismatch(format!("{} must be below 2^{}", name, RANGE_BITS)));
+    }
+    Ok(BaseElement::new(value as u64))
+}
+
+fn verify_stacks_voting_proof(pub_inputs: PublicInputs, proof_in: &[u8]) -> Result<(), VerificationFailure> {
+    let proof: Proof = Proof::from_bytes(proof_in)
+        .map_err(|e| VerificationFailure::BadEncoding(e.to_string()))?;
     // The verifier will accept proofs with parameters which guarantee 95 bits or more of
     // conjectured security
-    let balance_at_height: BaseElement = BaseElement::new(balance_at_height_in);
-    let block_proof_height: BaseElement = BaseElement::new(block_proof_height_in);
-    let proof: Proof = Proof::from_bytes(proof_in).unwrap();
     let min_opts = winterfell::AcceptableOptions::MinConjecturedSecurity(95);
 
     // Verify the proof. The number of steps and options are encoded in the proof itself,
     // so we don't need to pass them explicitly to the verifier.
-    let pub_inputs = PublicInputs { balance: balance_at_height, height: block_proof_height };
     winterfell::verify::<WorkAir,
                                 Blake3_256<BaseElement>,
                                 DefaultRandomCoin<Blake3_256<BaseElement>>
-                                >(proof, pub_inputs, &min_opts).is_ok()
-}
\ No newline at end of file
+                                >(proof, pub_inputs, &min_opts)?;
+    Ok(())
+}
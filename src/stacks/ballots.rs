@@ -0,0 +1,142 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Ballots
+//
+// A ballot's vote is the string the voter signs and the proof commits to, so every choice
+// must have exactly one encoding. Single choice ballots vote with the name of the option, as
+// for/against ballots always did. Approval and ranked choice ballots list options by their
+// index in the proposal's option list, as decimals separated by commas without spaces:
+// approval ballots in ascending order, e.g. `0,2`, ranked choice ballots most preferred
+// first, e.g. `2,0,1`. Ranked choice ballots don't have to rank every option. Votes in any
+// other spelling are rejected, so the same choice can't be committed to twice.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BallotFormat {
+    // one option
+    #[default]
+    SingleChoice,
+    // any non-empty subset of the options, each counted in full
+    Approval,
+    // options in order of preference, counted by instant-runoff
+    RankedChoice,
+}
+
+// A vote decoded against the proposal's options; options are referred to by their index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ballot {
+    SingleChoice(usize),
+    Approval(Vec<usize>),
+    RankedChoice(Vec<usize>),
+}
+
+impl Ballot {
+    pub fn parse(format: BallotFormat, vote: &str, options: &[String]) -> Result<Ballot, BallotError> {
+        match format {
+            BallotFormat::SingleChoice => options.iter()
+                .position(|option| option == vote)
+                .map(Ballot::SingleChoice)
+                .ok_or_else(|| BallotError::UnknownOption(vote.to_string())),
+            BallotFormat::Approval => {
+                let approved = parse_indices(vote, options)?;
+                if approved.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    return Err(BallotError::Malformed { vote: vote.to_string(), reason: "approved options must be in ascending order" });
+                }
+                Ok(Ballot::Approval(approved))
+            }
+            BallotFormat::RankedChoice => {
+                let ranking = parse_indices(vote, options)?;
+                if ranking.iter().enumerate().any(|(i, option)| ranking[..i].contains(option)) {
+                    return Err(BallotError::Malformed { vote: vote.to_string(), reason: "an option is ranked twice" });
+                }
+                Ok(Ballot::RankedChoice(ranking))
+            }
+        }
+    }
+
+    // The options the ballot names; a ranking is in order of preference.
+    pub fn options(&self) -> &[usize] {
+        match self {
+            Ballot::SingleChoice(option) => std::slice::from_ref(option),
+            Ballot::Approval(options) | Ballot::RankedChoice(options) => options,
+        }
+    }
+}
+
+fn parse_indices(vote: &str, options: &[String]) -> Result<Vec<usize>, BallotError> {
+    if vote.is_empty() {
+        return Err(BallotError::Malformed { vote: vote.to_string(), reason: "no option is chosen" });
+    }
+    let indices = vote.split(',')
+        .map(|index| index.parse::<usize>().ok().filter(|parsed| parsed.to_string() == index))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or_else(|| BallotError::Malformed { vote: vote.to_string(), reason: "options are not comma separated indices" })?;
+    if let Some(index) = indices.iter().find(|&&index| index >= options.len()) {
+        return Err(BallotError::UnknownOption(index.to_string()));
+    }
+    Ok(indices)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BallotError {
+    // The vote names an option the proposal doesn't have.
+    UnknownOption(String),
+    // The vote isn't in the canonical encoding of the proposal's ballot format.
+    Malformed { vote: String, reason: &'static str },
+}
+
+impl fmt::Display for BallotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BallotError::UnknownOption(vote) => write!(f, "Vote {} is not an option of the proposal", vote),
+            BallotError::Malformed { vote, reason } => write!(f, "Vote {} is malformed: {}", vote, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Vec<String> {
+        ["grants", "liquidity", "reserve"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn canonical_votes_parse() {
+        let options = options();
+        assert_eq!(Ballot::parse(BallotFormat::SingleChoice, "reserve", &options), Ok(Ballot::SingleChoice(2)));
+        assert_eq!(Ballot::parse(BallotFormat::Approval, "1", &options), Ok(Ballot::Approval(vec![1])));
+        assert_eq!(Ballot::parse(BallotFormat::Approval, "0,2", &options), Ok(Ballot::Approval(vec![0, 2])));
+        assert_eq!(Ballot::parse(BallotFormat::RankedChoice, "2,0,1", &options), Ok(Ballot::RankedChoice(vec![2, 0, 1])));
+        // rankings don't have to be complete
+        assert_eq!(Ballot::parse(BallotFormat::RankedChoice, "1", &options), Ok(Ballot::RankedChoice(vec![1])));
+    }
+
+    #[test]
+    fn other_spellings_are_rejected() {
+        let options = options();
+        for format in [BallotFormat::Approval, BallotFormat::RankedChoice] {
+            for vote in ["", "0,02", "00", "1, 0", " 1", "1 ", "+1", "1,", ",1", "1,,2", "0x1", "-0", "grants"] {
+                assert!(
+                    matches!(Ballot::parse(format, vote, &options), Err(BallotError::Malformed { .. })),
+                    "{:?} {:?}", format, vote
+                );
+            }
+            assert_eq!(Ballot::parse(format, "0,3", &options), Err(BallotError::UnknownOption("3".to_string())));
+        }
+        // the same approvals in another order, or twice
+        for vote in ["2,0", "0,0"] {
+            assert!(matches!(Ballot::parse(BallotFormat::Approval, vote, &options), Err(BallotError::Malformed { .. })), "{}", vote);
+        }
+        assert!(matches!(Ballot::parse(BallotFormat::RankedChoice, "2,0,2", &options), Err(BallotError::Malformed { .. })));
+        for vote in ["Reserve", "reserve ", "2", ""] {
+            assert_eq!(
+                Ballot::parse(BallotFormat::SingleChoice, vote, &options),
+                Err(BallotError::UnknownOption(vote.to_string()))
+            );
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::Filter;
+
+use crate::{proofs::stacks_voting::ballot_commitment, state::AppState};
+
+use super::{
+    nullifiers::Nullifier,
+    storage::{Storage, StorageError},
+    utils::decode_hex32,
+    NotFound, ProofError,
+};
+
+// Ballot batches
+//
+// Every ballot a proof job produces is added to the batch of its proposal. A batch is committed
+// to by a Merkle tree over its ballots, in the order they were cast, so its root can be
+// published on-chain and each voter handed a receipt: their ballot and the path proving it is
+// under the root.
+//
+//   leaf = SHA256(0x00 || SHA256(proof) || nullifier || commitment)
+//   node = SHA256(0x01 || left || right)
+//
+// The prefixes keep a leaf from passing for a node. A node without a sibling is carried up to
+// the next level unchanged rather than paired with a copy of itself, so repeating the last
+// ballot changes the root. Only SHA256 is involved, which Clarity contracts can check
+// receipts with.
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub type Hash = [u8; 32];
+
+// A ballot as committed to by the batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BallotRecord {
+    pub proof_hash: Hash,
+    pub nullifier: Nullifier,
+    // blinded commitment to the proposal and vote
+    pub commitment: Hash,
+}
+
+impl BallotRecord {
+    pub fn new(proof: &[u8], nullifier: Nullifier, commitment: Hash) -> Self {
+        BallotRecord {
+            proof_hash: Sha256::digest(proof).into(),
+            nullifier,
+            commitment,
+        }
+    }
+
+    pub fn leaf(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_PREFIX]);
+        hasher.update(self.proof_hash);
+        hasher.update(self.nullifier);
+        hasher.update(self.commitment);
+        hasher.finalize().into()
+    }
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Which side of the path's node a sibling is on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PathStep {
+    // hex encoded sibling hash
+    pub sibling: String,
+    pub side: Side,
+}
+
+// Levels of the tree over `leaves`, from the leaves up to the root.
+fn levels(leaves: Vec<Hash>) -> Vec<Vec<Hash>> {
+    let mut levels = vec![leaves];
+    while levels.last().is_some_and(|level| level.len() > 1) {
+        let level = levels.last().unwrap();
+        let parents = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(parents);
+    }
+    levels
+}
+
+// Path from leaf `index` to the root; levels where the node has no sibling are skipped.
+fn path(levels: &[Vec<Hash>], mut index: usize) -> Vec<PathStep> {
+    let mut path = Vec::new();
+    for level in &levels[..levels.len() - 1] {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            path.push(PathStep {
+                sibling: hex::encode(hash),
+                side: if sibling < index { Side::Left } else { Side::Right },
+            });
+        }
+        index /= 2;
+    }
+    path
+}
+
+// Recomputes the root from a leaf and its path, as a voter holding a receipt would.
+pub fn verify_inclusion(root: &Hash, leaf: &Hash, path: &[PathStep]) -> bool {
+    let mut hash = *leaf;
+    for step in path {
+        let sibling = match hex::decode(&step.sibling).ok().and_then(|bytes| Hash::try_from(bytes).ok()) {
+            Some(sibling) => sibling,
+            None => return false,
+        };
+        hash = match step.side {
+            Side::Left => node(&sibling, &hash),
+            Side::Right => node(&hash, &sibling),
+        };
+    }
+    hash == *root
+}
+
+// A voter's proof that their ballot is in a batch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BallotReceipt {
+    pub index: usize,
+    // hex encoded ballot fields and leaf
+    pub proof_hash: String,
+    pub nullifier: String,
+    pub commitment: String,
+    pub leaf: String,
+    pub path: Vec<PathStep>,
+}
+
+// A batch as it would be published: its root, and a receipt for each ballot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Batch {
+    pub proposal: String,
+    pub size: usize,
+    // hex encoded Merkle root
+    pub root: String,
+    pub ballots: Vec<BallotReceipt>,
+}
+
+// Batches over the ballots in storage.
+pub struct BallotBatches {
+    storage: Arc<dyn Storage>,
+}
+
+impl BallotBatches {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        BallotBatches { storage }
+    }
+
+    // The batch over the first `size` ballots of the proposal, or all of them. A published
+    // root covers the ballots cast until then, and later ballots don't change its paths.
+    pub fn batch(&self, proposal: &str, size: Option<usize>) -> Result<Option<Batch>, StorageError> {
+        let ballots = self.storage.ballots(proposal)?;
+        let size = size.unwrap_or(ballots.len());
+        if size == 0 || size > ballots.len() {
+            return Ok(None);
+        }
+        let ballots = &ballots[..size];
+        let levels = levels(ballots.iter().map(BallotRecord::leaf).collect());
+        let receipts = ballots
+            .iter()
+            .enumerate()
+            .map(|(index, ballot)| BallotReceipt {
+                index,
+                proof_hash: hex::encode(ballot.proof_hash),
+                nullifier: hex::encode(ballot.nullifier),
+                commitment: hex::encode(ballot.commitment),
+                leaf: hex::encode(levels[0][index]),
+                path: path(&levels, index),
+            })
+            .collect();
+        Ok(Some(Batch {
+            proposal: proposal.to_string(),
+            size,
+            root: hex::encode(levels.last().unwrap()[0]),
+            ballots: receipts,
+        }))
+    }
+}
+
+// Proposals are free text, so they are passed as query parameters rather than path segments.
+#[derive(Deserialize, Debug)]
+pub struct BatchQuery {
+    pub proposal: String,
+    pub size: Option<usize>,
+    // hex encoded nullifier, to fetch a single receipt
+    pub nullifier: Option<String>,
+    // hex encoded blinding the voter was sent with their proof, to fetch the proof too
+    pub blinding: Option<String>,
+}
+
+// A single voter's receipt, with the root it leads to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceiptResponse {
+    pub proposal: String,
+    pub size: usize,
+    pub root: String,
+    pub receipt: BallotReceipt,
+    // the base64 encoded proof the receipt's proof hash is over, so it can be validated again;
+    // only returned with the ballot's blinding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+}
+
+pub fn batches_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("batch")
+        .and(warp::get())
+        .and(warp::query::<BatchQuery>())
+        .and_then(move |query: BatchQuery| {
+            let state = state.clone();
+            async move { get_batch(&state, query) }
+        })
+}
+
+fn get_batch(state: &AppState, query: BatchQuery) -> Result<warp::reply::Json, warp::Rejection> {
+    let batch = state.batches.batch(&query.proposal, query.size)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(|| warp::reject::custom(NotFound("Batch")))?;
+    match query.nullifier {
+        None => Ok(warp::reply::json(&batch)),
+        Some(nullifier) => {
+            let nullifier = nullifier.trim_start_matches("0x").to_lowercase();
+            let receipt = batch.ballots.into_iter()
+                .find(|receipt| receipt.nullifier == nullifier)
+                .ok_or_else(|| warp::reject::custom(NotFound("Ballot")))?;
+            let nullifier = decode_hex32(&receipt.nullifier).ok_or_else(|| warp::reject::custom(NotFound("Ballot")))?;
+            let proof = match query.blinding {
+                Some(blinding) => Some(owned_proof(state, &batch.proposal, &nullifier, &blinding, &receipt.commitment)?),
+                None => None,
+            };
+            Ok(warp::reply::json(&ReceiptResponse {
+                proposal: batch.proposal,
+                size: batch.size,
+                root: batch.root,
+                receipt,
+                proof,
+            }))
+        }
+    }
+}
+
+// The proof discloses the balance the voter proved, so it is only handed to whoever can open
+// the ballot's commitment: the voter, who alone was sent its blinding.
+fn owned_proof(state: &AppState, proposal: &str, nullifier: &Nullifier, blinding: &str, commitment: &str) -> Result<String, warp::Rejection> {
+    let blinding = decode_hex32(blinding).ok_or_else(|| warp::reject::custom(ProofError::new("Invalid blinding")))?;
+    let (vote, proof) = state.storage.proof(proposal, nullifier)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(|| warp::reject::custom(StorageError::Corrupt("ballot without a proof".to_string())))?;
+    if hex::encode(ballot_commitment(proposal, &vote, &blinding)) != commitment {
+        return Err(warp::reject::custom(ProofError::forbidden("Blinding does not open the ballot's commitment")));
+    }
+    Ok(general_purpose::STANDARD.encode(proof))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stacks::storage::{MemoryStorage, StoredBallot};
+
+    fn record(n: u8) -> BallotRecord {
+        BallotRecord::new(&[n], [n; 32], [n.wrapping_add(100); 32])
+    }
+
+    // Batches over `count` ballots cast on proposal "p".
+    fn batches(count: u8) -> BallotBatches {
+        let storage = Arc::new(MemoryStorage::new());
+        for n in 0..count {
+            let ballot = StoredBallot {
+                proposal: "p".to_string(),
+                vote: "for".to_string(),
+                power: 1,
+                record: record(n),
+                history: [0; 32],
+                proof: vec![n],
+            };
+            assert!(storage.cast(&ballot, None).unwrap());
+        }
+        BallotBatches::new(storage)
+    }
+
+    fn decode(hash: &str) -> Hash {
+        Hash::try_from(hex::decode(hash).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn every_receipt_leads_to_the_root() {
+        for count in [2, 3, 5] {
+            let batch = batches(count).batch("p", None).unwrap().unwrap();
+            assert_eq!((batch.size, batch.ballots.len()), (count as usize, count as usize));
+            let root = decode(&batch.root);
+            for (n, receipt) in batch.ballots.iter().enumerate() {
+                assert_eq!(receipt.index, n);
+                assert_eq!(decode(&receipt.leaf), record(n as u8).leaf());
+                assert!(verify_inclusion(&root, &decode(&receipt.leaf), &receipt.path), "ballot {} of {}", n, count);
+            }
+        }
+    }
+
+    #[test]
+    fn roots_carry_unpaired_nodes_up() {
+        let leaves: Vec<Hash> = (0..5).map(|n| record(n).leaf()).collect();
+        let root = |count: u8| decode(&batches(count).batch("p", None).unwrap().unwrap().root);
+
+        assert_eq!(root(2), node(&leaves[0], &leaves[1]));
+        assert_eq!(root(3), node(&node(&leaves[0], &leaves[1]), &leaves[2]));
+        let four = node(&node(&leaves[0], &leaves[1]), &node(&leaves[2], &leaves[3]));
+        assert_eq!(root(5), node(&four, &leaves[4]));
+        // the fifth ballot has no sibling until the top
+        let fifth = &batches(5).batch("p", None).unwrap().unwrap().ballots[4];
+        assert_eq!(fifth.path, vec![PathStep { sibling: hex::encode(four), side: Side::Left }]);
+    }
+
+    #[test]
+    fn earlier_batches_keep_their_roots() {
+        let batches = batches(5);
+        for size in 1..=5 {
+            let prefix = batches.batch("p", Some(size)).unwrap().unwrap();
+            let alone = self::batches(size as u8).batch("p", None).unwrap().unwrap();
+            assert_eq!(prefix.root, alone.root, "size {}", size);
+        }
+        assert!(batches.batch("p", Some(0)).unwrap().is_none());
+        assert!(batches.batch("p", Some(6)).unwrap().is_none());
+        assert!(batches.batch("q", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_receipts_fail() {
+        let batch = batches(5).batch("p", None).unwrap().unwrap();
+        let root = decode(&batch.root);
+        let receipt = &batch.ballots[2];
+        let leaf = decode(&receipt.leaf);
+        assert!(verify_inclusion(&root, &leaf, &receipt.path));
+
+        let mut sibling = receipt.path.clone();
+        let mut bytes = decode(&sibling[0].sibling);
+        bytes[0] ^= 1;
+        sibling[0].sibling = hex::encode(bytes);
+        assert!(!verify_inclusion(&root, &leaf, &sibling));
+
+        let mut side = receipt.path.clone();
+        side[1].side = match side[1].side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        };
+        assert!(!verify_inclusion(&root, &leaf, &side));
+
+        assert!(!verify_inclusion(&root, &leaf, &receipt.path[1..]));
+        assert!(!verify_inclusion(&root, &record(7).leaf(), &receipt.path));
+        assert!(!verify_inclusion(&root, &leaf, &batch.ballots[3].path));
+
+        let mut malformed = receipt.path.clone();
+        malformed[0].sibling = "not hex".to_string();
+        assert!(!verify_inclusion(&root, &leaf, &malformed));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, fmt};
+
+// Clarity values and their consensus serialization, limited to the types used in signed
+// voting messages. The serialization is the same one used by stacks.js (`serializeCV`), so a
+// front-end building the same value gets byte-identical output.
+
+const TYPE_UINT: u8 = 0x01;
+const TYPE_TUPLE: u8 = 0x0c;
+const TYPE_STRING_ASCII: u8 = 0x0d;
+
+// Clarity names (tuple keys) are at most 128 characters long.
+const MAX_NAME_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClarityValue {
+    UInt(u128),
+    StringAscii(String),
+    // A BTreeMap keeps the keys sorted, which is the order Clarity serializes them in.
+    Tuple(BTreeMap<String, ClarityValue>),
+}
+
+#[derive(Debug)]
+pub enum ClarityError {
+    // string-ascii values only accept printable ASCII characters.
+    NonAsciiString(String),
+    // Tuple keys must be valid Clarity names.
+    InvalidName(String),
+}
+
+impl fmt::Display for ClarityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClarityError::NonAsciiString(field) => write!(f, "Non ASCII characters in {}", field),
+            ClarityError::InvalidName(name) => write!(f, "Invalid Clarity name {}", name),
+        }
+    }
+}
+
+impl ClarityValue {
+    // Builds a string-ascii value; `field` is only used to report which input was rejected.
+    pub fn string_ascii(field: &str, value: &str) -> Result<Self, ClarityError> {
+        if !value.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+            return Err(ClarityError::NonAsciiString(field.to_string()));
+        }
+        Ok(ClarityValue::StringAscii(value.to_string()))
+    }
+
+    pub fn tuple(entries: Vec<(&str, ClarityValue)>) -> Result<Self, ClarityError> {
+        let mut tuple = BTreeMap::new();
+        for (name, value) in entries {
+            if name.is_empty() || name.len() > MAX_NAME_LENGTH || !name.is_ascii() {
+                return Err(ClarityError::InvalidName(name.to_string()));
+            }
+            tuple.insert(name.to_string(), value);
+        }
+        Ok(ClarityValue::Tuple(tuple))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.serialize_into(&mut bytes);
+        bytes
+    }
+
+    fn serialize_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            ClarityValue::UInt(value) => {
+                bytes.push(TYPE_UINT);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            ClarityValue::StringAscii(value) => {
+                bytes.push(TYPE_STRING_ASCII);
+                bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(value.as_bytes());
+            }
+            ClarityValue::Tuple(entries) => {
+                bytes.push(TYPE_TUPLE);
+                bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for (name, value) in entries {
+                    bytes.push(name.len() as u8);
+                    bytes.extend_from_slice(name.as_bytes());
+                    value.serialize_into(bytes);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use std::{collections::HashSet, fmt};
+
+use super::{
+    source::ReportedBalance,
+    utils::{FtBalanceEvent, MinerReward, NftOwnershipEvent, StxBalanceEvent, Transaction, TransactionDetails},
+};
+
+// Balance history
+//
+// Turns the transactions fetched for an address into the ledger the proof is built from: only
+// transactions that actually changed the balance as of `block_proof_height` are kept, in the
+// order they were applied on chain. Besides transfers, the balance moves with fees (charged
+// even when a transaction aborts), mints, burns and matured miner rewards. Stacking locks
+// don't change the balance, they are recorded alongside it.
+//
+// Histories of a SIP-010 token balance, or of the number of SIP-009 tokens owned, are built the
+// same way from the asset's events.
+
+// What moved the balance. Miner rewards are credited before the block's transactions run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    MinerReward,
+    Transaction,
+    // a change of the locked amount, see BalanceHistory::lock_entries
+    Lock,
+}
+
+// The STX moved by one transaction or reward, as applied by one row of the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    pub kind: EntryKind,
+    // the transaction id, or `miner-reward-<height>` for rewards
+    pub tx_id: String,
+    pub block_height: u64,
+    pub tx_index: u64,
+    pub debit: u128,
+    pub credit: u128,
+    // the burnchain height a lock unlocks at; zero for entries which aren't locks
+    pub unlock_height: u64,
+}
+
+// STX locked by stacking, unlocking at the burnchain `unlock_height`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StxLock {
+    pub tx_id: String,
+    pub block_height: u64,
+    pub tx_index: u64,
+    pub burn_block_height: u64,
+    pub amount: u128,
+    pub unlock_height: u64,
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    // An amount isn't a non-negative integer number of micro-STX.
+    InvalidAmount { tx_id: String, field: &'static str },
+    // Replaying the history drives the balance below zero, so transactions are missing.
+    NegativeBalance { tx_id: String },
+    // The source didn't fetch the token or NFT events of a successful transaction.
+    MissingEvents { tx_id: String, kind: &'static str },
+    // The computed balance isn't the one the node reports at the same height.
+    Unreconciled { computed: u128, reported: u128, detail: String },
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::InvalidAmount { tx_id, field } => write!(f, "Invalid {} in transaction {}", field, tx_id),
+            HistoryError::NegativeBalance { tx_id } => {
+                write!(f, "Balance goes negative at transaction {}, the history is incomplete", tx_id)
+            }
+            HistoryError::MissingEvents { tx_id, kind } => write!(f, "{} events of transaction {} weren't fetched", kind, tx_id),
+            HistoryError::Unreconciled { computed, reported, detail } => {
+                write!(f, "Computed balance {} differs from the reported balance {}", computed, reported)?;
+                if !detail.is_empty() {
+                    write!(f, " ({})", detail)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BalanceHistory {
+    height: u64,
+    entries: Vec<LedgerEntry>,
+    locks: Vec<StxLock>,
+    balance: u128,
+    // fees and miner rewards are kept apart to tell where a reconciliation mismatch comes from
+    fees: u128,
+    miner_rewards: u128,
+    // index block hash of the block at `height`, once known
+    block_hash: Option<[u8; 32]>,
+    // secret mixed into the commitment to the ledger, once chosen
+    salt: Option<[u8; 32]>,
+}
+
+impl BalanceHistory {
+    // Keeps the mined, canonical and anchored transactions at or below `height` that moved the
+    // STX of `address`, plus the miner rewards credited at or below `height`, sorted by block and
+    // position in the block, and replays them to get the balance at that height. A transaction
+    // listed twice (e.g. across pages) counts once.
+    pub fn at_height(address: &str, transactions: &[Transaction], miner_rewards: &[MinerReward], height: u64) -> Result<Self, HistoryError> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        let mut locks = Vec::new();
+        let mut fees: u128 = 0;
+        for transaction in transactions {
+            let tx = &transaction.tx;
+            if !applied_at(tx, height) || !seen.insert(tx.tx_id.as_str()) {
+                continue;
+            }
+            let mut entry = LedgerEntry {
+                kind: EntryKind::Transaction,
+                tx_id: tx.tx_id.clone(),
+                block_height: tx.block_height,
+                tx_index: tx.tx_index,
+                debit: 0,
+                credit: 0,
+                unlock_height: 0,
+            };
+            match &transaction.stx_events {
+                Some(stx_events) => {
+                    if tx.fee_payer() == address {
+                        let fee = parse_amount(&tx.tx_id, "fee_rate", &tx.fee_rate)?;
+                        entry.debit += fee;
+                        fees += fee;
+                    }
+                    // aborted transactions had their events rolled back
+                    if tx.tx_status == "success" {
+                        for event in stx_events {
+                            apply_event(address, transaction, event, &mut entry, &mut locks)?;
+                        }
+                    }
+                }
+                // without the events, rely on the totals, which include the fee
+                None => {
+                    entry.debit = parse_amount(&tx.tx_id, "stx_sent", &transaction.stx_sent)?;
+                    entry.credit = parse_amount(&tx.tx_id, "stx_received", &transaction.stx_received)?;
+                }
+            }
+            // transactions that didn't move STX leave the balance alone
+            if entry.debit != 0 || entry.credit != 0 {
+                entries.push(entry);
+            }
+        }
+
+        let mut rewarded = HashSet::new();
+        let mut total_rewards: u128 = 0;
+        for reward in miner_rewards {
+            if reward.block_height > height || !rewarded.insert(reward.block_height) {
+                continue;
+            }
+            let tx_id = format!("miner-reward-{}", reward.block_height);
+            let credit = parse_amount(&tx_id, "amount", &reward.amount)?;
+            total_rewards += credit;
+            entries.push(LedgerEntry {
+                kind: EntryKind::MinerReward,
+                tx_id,
+                block_height: reward.block_height,
+                tx_index: 0,
+                debit: 0,
+                credit,
+                unlock_height: 0,
+            });
+        }
+        entries.sort_by_key(|entry| (entry.block_height, entry.kind, entry.tx_index));
+        locks.sort_by_key(|lock: &StxLock| (lock.block_height, lock.tx_index));
+
+        let balance = replay(&entries)?;
+        Ok(BalanceHistory { height, entries, locks, balance, fees, miner_rewards: total_rewards, block_hash: None, salt: None })
+    }
+
+    // The balance of `address` in the SIP-010 token `asset_identifier` at `height`, from the
+    // token events of the same transactions `at_height` keeps. Token transfers don't pay fees
+    // in the token, and aborted transactions had their events rolled back.
+    pub fn token_at_height(address: &str, asset_identifier: &str, transactions: &[Transaction], height: u64) -> Result<Self, HistoryError> {
+        asset_history(transactions, height, |transaction, entry| {
+            let tx_id = &transaction.tx.tx_id;
+            let ft_events = transaction.ft_events.as_ref()
+                .ok_or_else(|| HistoryError::MissingEvents { tx_id: tx_id.clone(), kind: "Token" })?;
+            for event in ft_events.iter().filter(|event| event.asset_identifier() == asset_identifier) {
+                apply_ft_event(address, tx_id, event, entry)?;
+            }
+            Ok(())
+        })
+    }
+
+    // How many tokens of the SIP-009 collection `asset_identifier` `address` owns at `height`:
+    // each token received counts as a credit of one, each token sent or burnt as a debit.
+    pub fn nft_count_at_height(address: &str, asset_identifier: &str, transactions: &[Transaction], height: u64) -> Result<Self, HistoryError> {
+        asset_history(transactions, height, |transaction, entry| {
+            let tx_id = &transaction.tx.tx_id;
+            let nft_events = transaction.nft_events.as_ref()
+                .ok_or_else(|| HistoryError::MissingEvents { tx_id: tx_id.clone(), kind: "NFT" })?;
+            for event in nft_events.iter().filter(|event| event.asset_identifier() == asset_identifier) {
+                apply_nft_event(address, event, entry);
+            }
+            Ok(())
+        })
+    }
+
+    // Checks the computed balance against the one the node reports at the same height.
+    pub fn reconcile(&self, reported: &ReportedBalance) -> Result<(), HistoryError> {
+        if self.balance == reported.balance {
+            return Ok(());
+        }
+        let mut hints = Vec::new();
+        if self.miner_rewards != reported.total_miner_rewards_received {
+            hints.push(format!("miner rewards {} vs {}", self.miner_rewards, reported.total_miner_rewards_received));
+        }
+        if self.fees != 0 && self.fees != reported.total_fees_sent {
+            hints.push(format!("fees {} vs {}", self.fees, reported.total_fees_sent));
+        }
+        Err(HistoryError::Unreconciled {
+            computed: self.balance,
+            reported: reported.balance,
+            detail: hints.join(", "),
+        })
+    }
+
+    // Ties the history to the canonical block at its height, so proofs built from it commit
+    // to one chain state rather than to a height that a fork could reuse.
+    pub fn anchored(mut self, index_block_hash: [u8; 32]) -> Self {
+        self.block_hash = Some(index_block_hash);
+        self
+    }
+
+    // Blinds the commitment proofs built from the history make to its ledger. The ledger is
+    // public chain data, so without a secret salt anyone could recompute the commitment of
+    // every account and match it against a ballot's.
+    pub fn blinded(mut self, salt: [u8; 32]) -> Self {
+        self.salt = Some(salt);
+        self
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn block_hash(&self) -> Option<&[u8; 32]> {
+        self.block_hash.as_ref()
+    }
+
+    pub fn salt(&self) -> Option<&[u8; 32]> {
+        self.salt.as_ref()
+    }
+
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    pub fn locks(&self) -> &[StxLock] {
+        &self.locks
+    }
+
+    // The lock in force at `burn_height`, the burnchain height of the history's block. Each
+    // `stack-stx`, `delegate-stack-stx`, `stack-increase` or `stack-extend` emits a lock event
+    // carrying the whole locked amount and unlock height, so the latest one describes the lock.
+    pub fn lock_at(&self, burn_height: u64) -> Option<&StxLock> {
+        self.locks.last().filter(|lock| lock.unlock_height > burn_height)
+    }
+
+    // The changes of the lock as ledger entries: each lock replaces the previous one, whether
+    // or not it had expired, so replaying them ends on the amount and unlock height of the
+    // latest lock. A `stack-extend` only moves the unlock height, and still gets an entry.
+    pub fn lock_entries(&self) -> Vec<LedgerEntry> {
+        let mut entries = Vec::new();
+        let mut locked = 0;
+        let mut unlock_height = 0;
+        for lock in &self.locks {
+            if locked != lock.amount || unlock_height != lock.unlock_height {
+                entries.push(LedgerEntry {
+                    kind: EntryKind::Lock,
+                    tx_id: lock.tx_id.clone(),
+                    block_height: lock.block_height,
+                    tx_index: lock.tx_index,
+                    debit: locked,
+                    credit: lock.amount,
+                    unlock_height: lock.unlock_height,
+                });
+            }
+            locked = lock.amount;
+            unlock_height = lock.unlock_height;
+        }
+        entries
+    }
+
+    // Balance at `height`, in micro-STX.
+    pub fn balance(&self) -> u128 {
+        self.balance
+    }
+}
+
+// Whether the transaction is part of the chain's state at `height`.
+fn applied_at(tx: &TransactionDetails, height: u64) -> bool {
+    tx.is_mined() && tx.canonical && !tx.is_unanchored && tx.block_height <= height
+}
+
+// Builds the history of an asset whose movements `apply` reads off each successful
+// transaction applied at `height`.
+fn asset_history(
+    transactions: &[Transaction],
+    height: u64,
+    apply: impl Fn(&Transaction, &mut LedgerEntry) -> Result<(), HistoryError>,
+) -> Result<BalanceHistory, HistoryError> {
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for transaction in transactions {
+        let tx = &transaction.tx;
+        if !applied_at(tx, height) || tx.tx_status != "success" || !seen.insert(tx.tx_id.as_str()) {
+            continue;
+        }
+        let mut entry = LedgerEntry {
+            kind: EntryKind::Transaction,
+            tx_id: tx.tx_id.clone(),
+            block_height: tx.block_height,
+            tx_index: tx.tx_index,
+            debit: 0,
+            credit: 0,
+            unlock_height: 0,
+        };
+        apply(transaction, &mut entry)?;
+        if entry.debit != 0 || entry.credit != 0 {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by_key(|entry| (entry.block_height, entry.tx_index));
+
+    let balance = replay(&entries)?;
+    Ok(BalanceHistory { height, entries, locks: Vec::new(), balance, fees: 0, miner_rewards: 0, block_hash: None, salt: None })
+}
+
+// Applies the entries in order, failing if the balance would go negative.
+fn replay(entries: &[LedgerEntry]) -> Result<u128, HistoryError> {
+    let mut balance: u128 = 0;
+    for entry in entries {
+        balance = balance
+            .checked_add(entry.credit)
+            .and_then(|balance| balance.checked_sub(entry.debit))
+            .ok_or_else(|| HistoryError::NegativeBalance { tx_id: entry.tx_id.clone() })?;
+    }
+    Ok(balance)
+}
+
+// Adds a token event's effect on `address` to the transaction's entry.
+fn apply_ft_event(address: &str, tx_id: &str, event: &FtBalanceEvent, entry: &mut LedgerEntry) -> Result<(), HistoryError> {
+    match event {
+        FtBalanceEvent::Transfer { sender, recipient, amount, .. } => {
+            let amount = parse_amount(tx_id, "amount", amount)?;
+            if sender == address {
+                entry.debit += amount;
+            }
+            if recipient == address {
+                entry.credit += amount;
+            }
+        }
+        FtBalanceEvent::Mint { recipient, amount, .. } if recipient == address => {
+            entry.credit += parse_amount(tx_id, "amount", amount)?;
+        }
+        FtBalanceEvent::Burn { sender, amount, .. } if sender == address => {
+            entry.debit += parse_amount(tx_id, "amount", amount)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Adds an NFT event's effect on the number of tokens `address` owns to the transaction's entry.
+fn apply_nft_event(address: &str, event: &NftOwnershipEvent, entry: &mut LedgerEntry) {
+    match event {
+        NftOwnershipEvent::Transfer { sender, recipient, .. } => {
+            if sender == address {
+                entry.debit += 1;
+            }
+            if recipient == address {
+                entry.credit += 1;
+            }
+        }
+        NftOwnershipEvent::Mint { recipient, .. } if recipient == address => entry.credit += 1,
+        NftOwnershipEvent::Burn { sender, .. } if sender == address => entry.debit += 1,
+        _ => {}
+    }
+}
+
+// Adds an event's effect on `address` to the transaction's entry.
+fn apply_event(
+    address: &str,
+    transaction: &Transaction,
+    event: &StxBalanceEvent,
+    entry: &mut LedgerEntry,
+    locks: &mut Vec<StxLock>,
+) -> Result<(), HistoryError> {
+    let tx_id = &transaction.tx.tx_id;
+    match event {
+        StxBalanceEvent::Transfer { sender, recipient, amount } => {
+            let amount = parse_amount(tx_id, "amount", amount)?;
+            if sender == address {
+                entry.debit += amount;
+            }
+            if recipient == address {
+                entry.credit += amount;
+            }
+        }
+        StxBalanceEvent::Mint { recipient, amount } if recipient == address => {
+            entry.credit += parse_amount(tx_id, "amount", amount)?;
+        }
+        StxBalanceEvent::Burn { sender, amount } if sender == address => {
+            entry.debit += parse_amount(tx_id, "amount", amount)?;
+        }
+        StxBalanceEvent::Lock { locked_address, locked_amount, unlock_height } if locked_address == address => {
+            locks.push(StxLock {
+                tx_id: tx_id.clone(),
+                block_height: transaction.tx.block_height,
+                tx_index: transaction.tx.tx_index,
+                burn_block_height: transaction.tx.burn_block_height,
+                amount: parse_amount(tx_id, "locked_amount", locked_amount)?,
+                unlock_height: *unlock_height,
+            });
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_amount(tx_id: &str, field: &'static str, amount: &str) -> Result<u128, HistoryError> {
+    amount.parse::<u128>().map_err(|_| HistoryError::InvalidAmount {
+        tx_id: tx_id.to_string(),
+        field,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::transaction;
+
+    fn reported(balance: u128, total_miner_rewards_received: u128) -> ReportedBalance {
+        ReportedBalance {
+            balance,
+            locked: 0,
+            total_sent: 0,
+            total_received: 0,
+            total_fees_sent: 0,
+            total_miner_rewards_received,
+        }
+    }
+
+    #[test]
+    fn histories_stop_at_their_height() {
+        let transactions = [
+            transaction("0x01", 0, 1000, 10),
+            transaction("0x02", 250, 0, 20),
+            transaction("0x03", 0, 50, 30),
+            // listed twice by the source
+            transaction("0x01", 0, 1000, 10),
+        ];
+        let rewards = [MinerReward { block_height: 15, amount: "40".to_string() }];
+        let history = BalanceHistory::at_height("SP1", &transactions, &rewards, 25).unwrap();
+        assert_eq!(history.balance(), 790);
+        assert_eq!(history.entries().len(), 3);
+        assert!(history.reconcile(&reported(790, 40)).is_ok());
+    }
+
+    #[test]
+    fn unreconciled_balances_say_what_differs() {
+        let rewards = [MinerReward { block_height: 15, amount: "40".to_string() }];
+        let history = BalanceHistory::at_height("SP1", &[transaction("0x01", 0, 1000, 10)], &rewards, 25).unwrap();
+        let error = history.reconcile(&reported(1100, 100)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Computed balance 1040 differs from the reported balance 1100 (miner rewards 40 vs 100)"
+        );
+    }
+}
@@ -0,0 +1,689 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::sync::{watch, Semaphore};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use warp::reject::Rejection;
+
+use crate::{
+    proofs::{
+        stacks_voting::{asset_identifier_hash, ballot_commitment, history_commitment, SignatureData, StacksVotingProofGenrator},
+        ApplicationResponseMessage, ProofResponse, ProofStage, ResponseEnvelope, VotingProofGenerator,
+    },
+    state::{AppState, PeerMap},
+};
+
+use super::{
+    batches::BallotRecord,
+    history::BalanceHistory,
+    nullifiers::Nullifier,
+    proposals,
+    sip018::verify_signature_data,
+    source::BlockInfo,
+    storage::StoredBallot,
+    utils::public_key_to_stacks_address,
+    ProofError,
+};
+
+// Proof jobs
+//
+// Proofs are generated in the background: a request is checked up front, then turned into a
+// job which waits for one of a bounded number of workers, fetches the voter's transactions and
+// builds and proves the trace on a blocking thread. Jobs can be polled by id over HTTP, and
+// WebSocket clients get every state change pushed to them.
+
+// How long finished jobs can still be polled.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+// How often finished jobs past their retention are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub type JobId = String;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    FetchingTransactions,
+    BuildingTrace,
+    Proving,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed)
+    }
+}
+
+impl From<ProofStage> for JobState {
+    fn from(stage: ProofStage) -> Self {
+        match stage {
+            ProofStage::BuildingTrace => JobState::BuildingTrace,
+            ProofStage::Proving => JobState::Proving,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStatus {
+    pub job_id: JobId,
+    pub state: JobState,
+    // the generated proof, once the job is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ProofResponse>,
+    // why the job failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// The proof a job generates.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "claim", rename_all = "lowercase")]
+pub enum ProofJobRequest {
+    Balance {
+        signature_data: SignatureData,
+    },
+    Threshold {
+        signature_data: SignatureData,
+        #[serde_as(as = "DisplayFromStr")]
+        threshold: u128,
+    },
+    // voting power from STX locked by stacking, for PoX-weighted votes
+    Locked {
+        signature_data: SignatureData,
+    },
+    // balance in the configured governance token
+    Token {
+        signature_data: SignatureData,
+    },
+    // ownership of at least `min_count` tokens of the configured NFT collection
+    Nft {
+        signature_data: SignatureData,
+        min_count: u64,
+    },
+    // quadratic voting weight, the square root of the STX balance, without revealing it
+    Quadratic {
+        signature_data: SignatureData,
+    },
+}
+
+impl ProofJobRequest {
+    pub fn signature_data(&self) -> &SignatureData {
+        match self {
+            ProofJobRequest::Balance { signature_data }
+            | ProofJobRequest::Threshold { signature_data, .. }
+            | ProofJobRequest::Locked { signature_data }
+            | ProofJobRequest::Token { signature_data }
+            | ProofJobRequest::Nft { signature_data, .. }
+            | ProofJobRequest::Quadratic { signature_data } => signature_data,
+        }
+    }
+}
+
+// A WebSocket peer following a job, and the id of the request that started it.
+#[derive(Clone, Debug)]
+pub struct Subscriber {
+    pub peer: SocketAddr,
+    pub request_id: Option<serde_json::Value>,
+}
+
+struct Job {
+    status: watch::Sender<JobStatus>,
+    subscriber: Option<Subscriber>,
+    finished_at: Option<Instant>,
+}
+
+pub struct JobQueue {
+    jobs: Mutex<HashMap<JobId, Job>>,
+    workers: Arc<Semaphore>,
+    peers: PeerMap,
+}
+
+impl JobQueue {
+    pub fn new(workers: usize, peers: PeerMap) -> Self {
+        JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            peers,
+        }
+    }
+
+    pub fn status(&self, job_id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(job_id).map(|job| job.status.borrow().clone())
+    }
+
+    // Waits for the job to finish and returns its final status.
+    pub async fn wait(&self, job_id: &str) -> Option<JobStatus> {
+        let mut receiver = {
+            let jobs = self.jobs.lock().unwrap();
+            jobs.get(job_id)?.status.subscribe()
+        };
+        let status = receiver.wait_for(|status| status.state.is_finished()).await.ok()?;
+        Some(status.clone())
+    }
+
+    // Drops the finished jobs that can no longer be polled.
+    fn prune(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| job.finished_at.is_none_or(|at| at.elapsed() < JOB_RETENTION));
+    }
+
+    // Registers a queued job. A subscriber is sent the queued status right away, so it
+    // reaches the peer ahead of any progress of the job.
+    fn create(&self, subscriber: Option<Subscriber>) -> JobStatus {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let status = JobStatus {
+            job_id: hex::encode(id),
+            state: JobState::Queued,
+            result: None,
+            error: None,
+        };
+
+        let (sender, _) = watch::channel(status.clone());
+        let job = Job { status: sender, subscriber: subscriber.clone(), finished_at: None };
+        self.jobs.lock().unwrap().insert(status.job_id.clone(), job);
+        if let Some(subscriber) = subscriber {
+            self.push(&subscriber, status.clone());
+        }
+        status
+    }
+
+    fn update(&self, job_id: &str, state: JobState, result: Option<ProofResponse>, error: Option<String>) {
+        let (status, subscriber) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(job) = jobs.get_mut(job_id) else { return };
+            job.status.send_modify(|status| {
+                status.state = state;
+                status.result = result;
+                status.error = error;
+            });
+            if state.is_finished() {
+                job.finished_at = Some(Instant::now());
+            }
+            let status = job.status.borrow().clone();
+            (status, job.subscriber.clone())
+        };
+        if let Some(subscriber) = subscriber {
+            self.push(&subscriber, status);
+        }
+    }
+
+    fn push(&self, subscriber: &Subscriber, status: JobStatus) {
+        let envelope = ResponseEnvelope {
+            request_id: subscriber.request_id.clone(),
+            response: ApplicationResponseMessage::ProofJobUpdate(status),
+        };
+        let message = match serde_json::to_string(&envelope) {
+            Ok(message) => message,
+            Err(e) => return eprintln!("Error serializing job update: {}", e),
+        };
+        // the peer may have disconnected, in which case the update can only be polled
+        if let Some(peer) = self.peers.lock().unwrap().get(&subscriber.peer) {
+            let _ = peer.unbounded_send(Message::Text(message));
+        }
+    }
+}
+
+// Drops expired jobs every PRUNE_INTERVAL, so they don't pile up while no new jobs are
+// submitted. Stops once the queue is dropped.
+pub async fn prune_finished(jobs: Weak<JobQueue>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match jobs.upgrade() {
+            Some(jobs) => jobs.prune(),
+            None => return,
+        }
+    }
+}
+
+// Checks the ballot and queues a job generating its proof. Requests with a bad signature, for
+// a ballot that was already cast or that its proposal doesn't accept are rejected right away.
+pub async fn submit(state: &AppState, request: ProofJobRequest, subscriber: Option<Subscriber>) -> Result<JobStatus, Rejection> {
+    if matches!(request, ProofJobRequest::Token { .. }) && state.governance_asset.is_none() {
+        return Err(warp::reject::custom(ProofError::new("No governance token is configured")));
+    }
+    if matches!(request, ProofJobRequest::Nft { .. }) && state.governance_collection.is_none() {
+        return Err(warp::reject::custom(ProofError::new("No governance NFT collection is configured")));
+    }
+    let nullifier = check_ballot(state, request.signature_data())?;
+    proposals::check_ballot(state, &request).await.map_err(warp::reject::custom)?;
+    let status = state.jobs.create(subscriber);
+
+    let job_id = status.job_id.clone();
+    let state = state.clone();
+    tokio::spawn(async move {
+        match run_job(&state, &job_id, request, nullifier).await {
+            Ok(response) => state.jobs.update(&job_id, JobState::Done, Some(response), None),
+            Err(e) => state.jobs.update(&job_id, JobState::Failed, None, Some(e)),
+        }
+    });
+    Ok(status)
+}
+
+async fn run_job(state: &AppState, job_id: &str, request: ProofJobRequest, nullifier: Nullifier) -> Result<ProofResponse, String> {
+    let _worker = state.jobs.workers.clone().acquire_owned().await
+        .map_err(|e| e.to_string())?;
+
+    state.jobs.update(job_id, JobState::FetchingTransactions, None, None);
+    let history = match request {
+        ProofJobRequest::Token { ref signature_data } => load_token_history(state, signature_data).await?,
+        ProofJobRequest::Nft { ref signature_data, .. } => load_nft_history(state, signature_data).await?,
+        _ => load_voter_history(state, request.signature_data()).await?,
+    };
+    // Pin the history to the block the ballot names, so a verifier can check it is canonical.
+    let block = anchor_block(state, request.signature_data()).await?;
+    let block_hash = block.index_block_hash_bytes()
+        .ok_or_else(|| format!("Block {} has an invalid index block hash", block.height))?;
+    // The salt stays on the server, so the commitment the proof exposes can't be matched to
+    // the voter's public ledger.
+    let mut salt = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let history = history.anchored(block_hash).blinded(salt);
+    let burn_height = block.burn_block_height;
+
+    // Building and proving the trace is CPU bound, so it must stay off the async runtime.
+    let proving_state = state.clone();
+    let proving_job_id = job_id.to_string();
+    let (ballot, response) = tokio::task::spawn_blocking(move || {
+        let progress = |stage: ProofStage| proving_state.jobs.update(&proving_job_id, stage.into(), None, None);
+        prove(&proving_state, request, nullifier, &history, burn_height, &progress)
+    })
+    .await
+    .map_err(|e| format!("Proof generation task failed: {}", e))??;
+
+    // Two ballots for the same proposal can be proven concurrently, so only the first one to
+    // get here counts.
+    let proposal = state.proposals.get(&ballot.proposal).map_err(|e| e.to_string())?;
+    if !state.tallies.cast(proposal.as_ref(), &ballot).map_err(|e| e.to_string())? {
+        return Err("Ballot already cast for this proposal".to_string());
+    }
+    Ok(response)
+}
+
+// Returns the proven ballot together with the proof response.
+fn prove(
+    state: &AppState,
+    request: ProofJobRequest,
+    nullifier: Nullifier,
+    history: &BalanceHistory,
+    burn_height: u64,
+    progress: &dyn Fn(ProofStage),
+) -> Result<(StoredBallot, ProofResponse), String> {
+    let message_inputs = &request.signature_data().message_inputs;
+    let proposal = message_inputs.proposal.clone();
+    let vote = message_inputs.vote.clone();
+    // The commitment is published in the proposal's batch, so it is blinded with a secret only
+    // the voter is sent back.
+    let mut blinding = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut blinding);
+    let ballot = ballot_commitment(&message_inputs.proposal, &message_inputs.vote, &blinding);
+    let commitment = hex::encode(ballot);
+    let block_hash = history.block_hash().map(hex::encode).ok_or("Balance history is not anchored to a block")?;
+    let ledger = match request {
+        ProofJobRequest::Locked { .. } => history.lock_entries(),
+        _ => history.entries().to_vec(),
+    };
+    let salt = history.salt().ok_or("Balance history is not blinded")?;
+    let history_commitment = history_commitment(&ledger, salt);
+    let history_root = hex::encode(history_commitment);
+
+    let (response, proof, power) = match request {
+        ProofJobRequest::Balance { signature_data } => {
+            let (proof, result) = StacksVotingProofGenrator::generate_proof(signature_data, nullifier, blinding, history, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksVotingProof {
+                result: result.to_string(),
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, result)
+        }
+        ProofJobRequest::Threshold { signature_data, threshold } => {
+            let proof = StacksVotingProofGenrator::generate_threshold_proof(signature_data, nullifier, blinding, threshold, history, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksThresholdProof {
+                threshold: threshold.to_string(),
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, threshold)
+        }
+        ProofJobRequest::Quadratic { signature_data } => {
+            let (proof, weight) = StacksVotingProofGenrator::generate_quadratic_proof(signature_data, nullifier, blinding, history, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksQuadraticProof {
+                weight: weight.to_string(),
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, weight)
+        }
+        ProofJobRequest::Locked { signature_data } => {
+            let (proof, locked, unlock_height) = StacksVotingProofGenrator::generate_locked_proof(signature_data, nullifier, blinding, history, burn_height, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksLockedProof {
+                locked: locked.to_string(),
+                unlock_height,
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, locked)
+        }
+        ProofJobRequest::Token { signature_data } => {
+            let asset_identifier = state.governance_asset.as_deref().ok_or("No governance token is configured")?;
+            let (proof, balance) = StacksVotingProofGenrator::generate_token_proof(signature_data, nullifier, blinding, asset_identifier, history, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksTokenProof {
+                balance: balance.to_string(),
+                asset_identifier: asset_identifier.to_string(),
+                asset_hash: hex::encode(asset_identifier_hash(asset_identifier)),
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, balance)
+        }
+        ProofJobRequest::Nft { signature_data, min_count } => {
+            let asset_identifier = state.governance_collection.as_deref().ok_or("No governance NFT collection is configured")?;
+            let proof = StacksVotingProofGenrator::generate_nft_proof(signature_data, nullifier, blinding, asset_identifier, min_count, history, progress)
+                .map_err(|e| e.to_string())?;
+            (ProofResponse::StacksNftProof {
+                min_count,
+                asset_identifier: asset_identifier.to_string(),
+                asset_hash: hex::encode(asset_identifier_hash(asset_identifier)),
+                block_hash,
+                history: history_root,
+                commitment,
+                blinding: hex::encode(blinding),
+                nullifier: hex::encode(nullifier),
+                proof: general_purpose::STANDARD.encode(&proof),
+            }, proof, min_count.into())
+        }
+    };
+    let record = BallotRecord::new(&proof, nullifier, ballot);
+    Ok((StoredBallot { proposal, vote, power, record, history: history_commitment, proof }, response))
+}
+
+// Checks the SIP-018 signature on the ballot and derives the voter's nullifier for its
+// proposal. Ballots whose nullifier was already spent are rejected before any work is done.
+fn check_ballot(state: &AppState, signature_data: &SignatureData) -> Result<Nullifier, Rejection> {
+    verify_signature_data(signature_data, state.network.network.chain_id()).map_err(warp::reject::custom)?;
+
+    let proposal = &signature_data.message_inputs.proposal;
+    let nullifier = state.nullifiers.nullifier(&signature_data.public_key, proposal)
+        .map_err(|e| warp::reject::custom(ProofError::new(&e)))?;
+    if state.storage.is_spent(proposal, &nullifier).map_err(warp::reject::custom)? {
+        return Err(warp::reject::custom(ProofError::conflict("Ballot already cast for this proposal")));
+    }
+    Ok(nullifier)
+}
+
+// Resolves the voter's address from the signing key.
+fn voter_address(state: &AppState, signature_data: &SignatureData) -> Result<String, String> {
+    public_key_to_stacks_address(signature_data.public_key.clone(), state.network.network.single_sig_version())
+        .map_err(|e| format!("Address conversion error: {:?}", e))
+}
+
+// Fetches the voter's history and computes its balance at the ballot's height. When the
+// source reports balances, the computed one has to match: a proof over a history we misread
+// would attest to the wrong balance.
+async fn load_voter_history(state: &AppState, signature_data: &SignatureData) -> Result<BalanceHistory, String> {
+    let height = signature_data.message_inputs.block_proof_height;
+    let stacks_address = voter_address(state, signature_data)?;
+
+    let transactions = state.transactions.fetch_transactions(&stacks_address).await
+        .map_err(|e| format!("Transaction fetch error: {}", e))?;
+    let miner_rewards = state.transactions.fetch_miner_rewards(&stacks_address, height).await
+        .map_err(|e| format!("Miner reward fetch error: {}", e))?;
+
+    let history = BalanceHistory::at_height(&stacks_address, &transactions, &miner_rewards, height)
+        .map_err(|e| e.to_string())?;
+    let reported = state.transactions.fetch_balance(&stacks_address, height).await
+        .map_err(|e| format!("Balance fetch error: {}", e))?;
+    if let Some(reported) = reported {
+        history.reconcile(&reported).map_err(|e| e.to_string())?;
+    }
+    Ok(history)
+}
+
+// Fetches the voter's history and computes its balance in the governance token at the
+// ballot's height.
+async fn load_token_history(state: &AppState, signature_data: &SignatureData) -> Result<BalanceHistory, String> {
+    let asset_identifier = state.governance_asset.as_deref().ok_or("No governance token is configured")?;
+    let stacks_address = voter_address(state, signature_data)?;
+
+    let transactions = state.transactions.fetch_transactions(&stacks_address).await
+        .map_err(|e| format!("Transaction fetch error: {}", e))?;
+    BalanceHistory::token_at_height(&stacks_address, asset_identifier, &transactions, signature_data.message_inputs.block_proof_height)
+        .map_err(|e| e.to_string())
+}
+
+// Fetches the voter's history and counts the tokens of the governance collection it owned at
+// the ballot's height.
+async fn load_nft_history(state: &AppState, signature_data: &SignatureData) -> Result<BalanceHistory, String> {
+    let asset_identifier = state.governance_collection.as_deref().ok_or("No governance NFT collection is configured")?;
+    let stacks_address = voter_address(state, signature_data)?;
+
+    let transactions = state.transactions.fetch_transactions(&stacks_address).await
+        .map_err(|e| format!("Transaction fetch error: {}", e))?;
+    BalanceHistory::nft_count_at_height(&stacks_address, asset_identifier, &transactions, signature_data.message_inputs.block_proof_height)
+        .map_err(|e| e.to_string())
+}
+
+// The canonical block at the ballot's height. Its index block hash anchors the proof, and its
+// burnchain height tells whether a lock is still in force.
+async fn anchor_block(state: &AppState, signature_data: &SignatureData) -> Result<BlockInfo, String> {
+    let height = signature_data.message_inputs.block_proof_height;
+    let block = state.transactions.fetch_block(height).await
+        .map_err(|e| format!("Block fetch error: {}", e))?
+        .ok_or_else(|| format!("Block {} is unknown", height))?;
+    if !block.canonical {
+        return Err(format!("Block {} is not on the canonical chain", height));
+    }
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stacks::{proposals::ProposalError, sip018::SignatureError, source::ReportedBalance},
+        tests::{address, ballot, server, transaction, END, SNAPSHOT},
+    };
+
+    async fn prove(state: &AppState, signature_data: SignatureData) -> Result<JobStatus, Rejection> {
+        let job = submit(state, ProofJobRequest::Balance { signature_data }, None).await?;
+        Ok(state.jobs.wait(&job.job_id).await.unwrap())
+    }
+
+    fn votes(state: &AppState) -> u64 {
+        let proposal = state.proposals.get("SIP-028").unwrap().unwrap();
+        state.tallies.tally(&proposal).unwrap().ballots
+    }
+
+    #[tokio::test]
+    async fn ballots_are_proven_and_cast_with_a_blinded_history() {
+        let (state, _) = server(55);
+        let signature_data = ballot(7, "for", SNAPSHOT);
+        let status = prove(&state, signature_data.clone()).await.unwrap();
+        assert_eq!(status.state, JobState::Done, "{:?}", status.error);
+        let Some(ProofResponse::StacksVotingProof { result, block_hash, history, .. }) = status.result else {
+            panic!("{:?}", status.result);
+        };
+        assert_eq!(result, "750");
+        assert_eq!(block_hash, hex::encode([SNAPSHOT as u8; 32]));
+
+        let nullifier = state.nullifiers.nullifier(&signature_data.public_key, "SIP-028").unwrap();
+        assert!(state.storage.is_spent("SIP-028", &nullifier).unwrap());
+        assert_eq!(state.storage.history("SIP-028", &nullifier).unwrap().map(hex::encode), Some(history.clone()));
+        // holding the same ledger isn't enough to recompute the commitment
+        let ledger = BalanceHistory::at_height(&address(&signature_data), &[
+            transaction("0x01", 0, 1000, 10),
+            transaction("0x02", 250, 0, 20),
+        ], &[], SNAPSHOT).unwrap();
+        assert_ne!(history, hex::encode(history_commitment(ledger.entries(), &[0; 32])));
+        assert_eq!(votes(&state), 1);
+    }
+
+    #[tokio::test]
+    async fn bad_signatures_are_rejected() {
+        let (state, _) = server(55);
+        let mut tampered = ballot(7, "for", SNAPSHOT);
+        tampered.message_inputs.vote = "against".to_string();
+        let rejection = prove(&state, tampered).await.unwrap_err();
+        assert!(matches!(rejection.find::<SignatureError>(), Some(SignatureError::HashMismatch { .. })), "{:?}", rejection);
+
+        let mut impostor = ballot(7, "for", SNAPSHOT);
+        impostor.public_key = ballot(8, "for", SNAPSHOT).public_key;
+        let rejection = prove(&state, impostor).await.unwrap_err();
+        assert!(matches!(rejection.find::<SignatureError>(), Some(SignatureError::PublicKeyMismatch)), "{:?}", rejection);
+        assert_eq!(votes(&state), 0);
+    }
+
+    #[tokio::test]
+    async fn reused_nullifiers_are_rejected() {
+        let (state, _) = server(55);
+        assert_eq!(prove(&state, ballot(7, "for", SNAPSHOT)).await.unwrap().state, JobState::Done);
+
+        // the nullifier is the voter's, whatever the vote
+        let rejection = prove(&state, ballot(7, "against", SNAPSHOT)).await.unwrap_err();
+        let error = rejection.find::<ProofError>().unwrap();
+        assert_eq!((error.status, error.to_string().as_str()), (warp::http::StatusCode::CONFLICT, "Ballot already cast for this proposal"));
+        assert_eq!(votes(&state), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_ballots_are_cast_once() {
+        let (state, _) = server(55);
+        // both are queued before either spends the nullifier
+        let first = submit(&state, ProofJobRequest::Balance { signature_data: ballot(7, "for", SNAPSHOT) }, None).await.unwrap();
+        let second = submit(&state, ProofJobRequest::Balance { signature_data: ballot(7, "against", SNAPSHOT) }, None).await.unwrap();
+
+        let mut states = Vec::new();
+        for job in [first, second] {
+            let status = state.jobs.wait(&job.job_id).await.unwrap();
+            states.push((status.state, status.error));
+        }
+        states.sort_by_key(|(state, _)| *state == JobState::Failed);
+        assert_eq!(states, vec![
+            (JobState::Done, None),
+            (JobState::Failed, Some("Ballot already cast for this proposal".to_string())),
+        ]);
+        assert_eq!(votes(&state), 1);
+    }
+
+    #[tokio::test]
+    async fn ballots_outside_the_window_are_rejected() {
+        let (state, _) = server(END + 1);
+        let rejection = prove(&state, ballot(7, "for", SNAPSHOT)).await.unwrap_err();
+        assert!(
+            matches!(rejection.find::<ProposalError>(), Some(ProposalError::Closed { tip_height, .. }) if *tip_height == END + 1),
+            "{:?}", rejection
+        );
+    }
+
+    #[tokio::test]
+    async fn ballots_for_another_snapshot_are_rejected() {
+        let (state, _) = server(55);
+        let rejection = prove(&state, ballot(7, "for", SNAPSHOT - 1)).await.unwrap_err();
+        assert!(
+            matches!(rejection.find::<ProposalError>(), Some(ProposalError::SnapshotMismatch { expected: SNAPSHOT, actual }) if *actual == SNAPSHOT - 1),
+            "{:?}", rejection
+        );
+    }
+
+    #[tokio::test]
+    async fn unreconciled_balances_fail_the_job() {
+        let (state, source) = server(55);
+        let signature_data = ballot(7, "for", SNAPSHOT);
+        source.insert_balance(&address(&signature_data), SNAPSHOT, ReportedBalance {
+            balance: 700,
+            locked: 0,
+            total_sent: 250,
+            total_received: 950,
+            total_fees_sent: 0,
+            total_miner_rewards_received: 0,
+        });
+
+        let status = prove(&state, signature_data.clone()).await.unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("Computed balance 750 differs from the reported balance 700"));
+        let nullifier = state.nullifiers.nullifier(&signature_data.public_key, "SIP-028").unwrap();
+        assert!(!state.storage.is_spent("SIP-028", &nullifier).unwrap());
+    }
+
+    #[tokio::test]
+    async fn subscribers_hear_of_the_job_before_its_progress() {
+        let (state, _) = server(55);
+        let peer: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+        let (sender, mut receiver) = futures_channel::mpsc::unbounded();
+        state.jobs.peers.lock().unwrap().insert(peer, sender);
+
+        let message = serde_json::json!({
+            "request_id": "r1",
+            "message_type": "ProofGeneration",
+            "proof_type": "StacksVotingProof",
+            "signature_data": ballot(7, "for", SNAPSHOT),
+        });
+        // the acknowledgement is pushed by the queue rather than returned
+        assert!(crate::proofs::handle_request(&message.to_string(), &state, Some(peer)).await.is_none());
+
+        let mut states = Vec::new();
+        while states.last() != Some(&JobState::Done) {
+            let Some(Message::Text(text)) = futures_util::StreamExt::next(&mut receiver).await else { panic!() };
+            let envelope: ResponseEnvelope = serde_json::from_str(&text).unwrap();
+            // tally updates are broadcast to every peer
+            if let ApplicationResponseMessage::ProofJobUpdate(status) = envelope.response {
+                assert_eq!(envelope.request_id, Some("r1".into()));
+                states.push(status.state);
+            }
+        }
+        assert_eq!(states.first(), Some(&JobState::Queued));
+        assert_eq!(states.iter().filter(|state| **state == JobState::Queued).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_jobs_are_pruned() {
+        let (state, _) = server(55);
+        let queued = state.jobs.create(None).job_id;
+        let recent = state.jobs.create(None).job_id;
+        let expired = state.jobs.create(None).job_id;
+        state.jobs.update(&recent, JobState::Failed, None, Some("recent".to_string()));
+        state.jobs.update(&expired, JobState::Failed, None, Some("expired".to_string()));
+        state.jobs.jobs.lock().unwrap().get_mut(&expired).unwrap().finished_at =
+            Some(Instant::now() - JOB_RETENTION - Duration::from_secs(1));
+
+        state.jobs.prune();
+        assert!(state.jobs.status(&queued).is_some());
+        assert!(state.jobs.status(&recent).is_some());
+        assert!(state.jobs.status(&expired).is_none());
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+use crate::{proofs::stacks_voting::{Domain, MessageInputs}, state::AppState};
+
+use super::{sip018::{default_domain, encode_message}, ProofError};
+
+// Lets front-ends fetch the canonical SIP-018 encoding of a ballot, so what the wallet signs is
+// byte-for-byte what the server checks.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncodeMessageRequest {
+    pub message_inputs: MessageInputs,
+    pub domain: Option<Domain>,
+}
+
+pub fn messages_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let chain_id = state.network.network.chain_id();
+    warp::path!("message" / "encode")
+        .and(warp::post())
+        .and(warp::body::json::<EncodeMessageRequest>())
+        .and_then(move |request| encode(request, chain_id))
+}
+
+pub async fn encode(request: EncodeMessageRequest, chain_id: u32) -> Result<impl warp::Reply, warp::Rejection> {
+    let domain = request.domain.unwrap_or_else(|| default_domain(chain_id));
+    match encode_message(&domain, &request.message_inputs) {
+        Ok(encoded) => Ok(warp::reply::json(&encoded)),
+        Err(e) => Err(warp::reject::custom(ProofError::new(&e.to_string()))),
+    }
+}
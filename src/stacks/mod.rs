@@ -1,28 +1,150 @@
-use warp::Filter;
-use warp::reject::Reject;
-use std::fmt;
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, reject::{Reject, Rejection}, Filter, Reply};
+use std::{convert::Infallible, fmt};
 
+use crate::state::AppState;
+
+use self::{proposals::ProposalError, sip018::SignatureError, source::SourceError, storage::StorageError};
+
+mod messages;
 mod transactions;
+pub mod ballots;
+pub mod batches;
+pub mod clarity;
+pub mod history;
+pub mod jobs;
+pub mod network;
+pub mod nullifiers;
+pub mod proposals;
 pub mod proofs;
+pub mod source;
+pub mod sip018;
+pub mod storage;
+pub mod tally;
 pub mod utils;
 
 // Combines all Stacks-related routes
-pub fn stacks_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+pub fn stacks_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
     warp::path("stacks").and(
-        transactions::transactions_routes()
-            .or(proofs::proofs_routes())
+        transactions::transactions_routes(state.clone())
+            .or(proofs::proofs_routes(state.clone()))
+            .or(messages::messages_routes(state.clone()))
+            .or(batches::batches_routes(state.clone()))
+            .or(proposals::proposals_routes(state.clone()))
+            .or(tally::tally_routes(state.clone()))
+            .or(network::network_routes(state))
     )
+    .recover(handle_rejection)
+}
+
+// Body of every error response.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+// Answers a rejected request with a status telling what went wrong, and the reason as JSON.
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, error) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found".to_string())
+    } else if let Some(e) = rejection.find::<NotFound>() {
+        (StatusCode::NOT_FOUND, e.to_string())
+    } else if let Some(e) = rejection.find::<ProposalError>() {
+        (proposal_status(e), e.to_string())
+    } else if let Some(e) = rejection.find::<SignatureError>() {
+        (signature_status(e), e.to_string())
+    } else if let Some(e) = rejection.find::<ProofError>() {
+        (e.status, e.to_string())
+    } else if let Some(e) = rejection.find::<SourceError>() {
+        (StatusCode::BAD_GATEWAY, e.to_string())
+    } else if let Some(e) = rejection.find::<StorageError>() {
+        eprintln!("{}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Storage error".to_string())
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else {
+        eprintln!("Unhandled rejection: {:?}", rejection);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string())
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&ErrorResponse { error }), status))
+}
+
+fn proposal_status(error: &ProposalError) -> StatusCode {
+    match error {
+        ProposalError::Unknown(_) => StatusCode::NOT_FOUND,
+        ProposalError::AlreadyExists(_) | ProposalError::Closed { .. } => StatusCode::CONFLICT,
+        ProposalError::Unauthorized => StatusCode::UNAUTHORIZED,
+        ProposalError::UnknownTip(_) => StatusCode::SERVICE_UNAVAILABLE,
+        ProposalError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        ProposalError::Invalid(_)
+        | ProposalError::SnapshotMismatch { .. }
+        | ProposalError::VotingEndMismatch { .. }
+        | ProposalError::InvalidVote(_)
+        | ProposalError::Ineligible(_) => StatusCode::BAD_REQUEST,
+    }
 }
 
+fn signature_status(error: &SignatureError) -> StatusCode {
+    match error {
+        SignatureError::InvalidSignature | SignatureError::PublicKeyMismatch => StatusCode::UNAUTHORIZED,
+        SignatureError::InvalidEncoding(_)
+        | SignatureError::Encoding(_)
+        | SignatureError::HashMismatch { .. }
+        | SignatureError::ChainIdMismatch { .. } => StatusCode::BAD_REQUEST,
+    }
+}
+
+// Something the request names doesn't exist. Routes reject with it rather than with warp's
+// `not_found`, which loses to the 405 of a route on the same path taking another method.
+#[derive(Debug)]
+pub struct NotFound(pub &'static str);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} not found", self.0)
+    }
+}
+
+impl Reject for NotFound {}
+
 #[derive(Debug)]
 pub struct ProofError {
     message: String,
+    // the status of the error response
+    status: StatusCode,
 }
 
 impl ProofError {
     fn new(msg: &str) -> Self {
         ProofError {
             message: msg.to_string(),
+            status: StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // A request that clashes with what the server already holds, e.g. a second ballot.
+    fn conflict(msg: &str) -> Self {
+        ProofError {
+            status: StatusCode::CONFLICT,
+            ..ProofError::new(msg)
+        }
+    }
+
+    // A request for something only the ballot's voter may see, e.g. its proof.
+    fn forbidden(msg: &str) -> Self {
+        ProofError {
+            status: StatusCode::FORBIDDEN,
+            ..ProofError::new(msg)
         }
     }
 }
@@ -35,3 +157,119 @@ impl fmt::Display for ProofError {
 
 // Make `ProofError` implement `Reject`
 impl Reject for ProofError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::stacks::{
+        network::{Network, NetworkConfig},
+        nullifiers::NullifierRegistry,
+        source::MemorySource,
+        storage::MemoryStorage,
+    };
+
+    fn state() -> AppState {
+        AppState::new(
+            NetworkConfig::new(Network::Mainnet),
+            NullifierRegistry::new([1; 32]),
+            Arc::new(MemorySource::new()),
+            Arc::new(MemoryStorage::new()),
+            1,
+            Default::default(),
+        )
+        .with_admin_key("admin")
+    }
+
+    fn proposal() -> serde_json::Value {
+        json!({
+            "id": "SIP-028",
+            "snapshot_height": 50,
+            "start_height": 50,
+            "end_height": 60,
+            "options": ["for", "against"],
+            "eligibility": { "claim": "balance" },
+        })
+    }
+
+    async fn post_proposal(state: &AppState, authorization: Option<&str>, proposal: &serde_json::Value) -> (StatusCode, String) {
+        let mut request = warp::test::request().method("POST").path("/stacks/proposals").json(proposal);
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        failure(request.reply(&stacks_routes(state.clone())).await)
+    }
+
+    // The status of a response, and its error if it has one.
+    fn failure(response: warp::http::Response<warp::hyper::body::Bytes>) -> (StatusCode, String) {
+        let error = serde_json::from_slice::<ErrorResponse>(response.body())
+            .map(|body| body.error)
+            .unwrap_or_default();
+        (response.status(), error)
+    }
+
+    #[tokio::test]
+    async fn registering_proposals_requires_the_admin_key() {
+        let state = state();
+        for authorization in [None, Some("Bearer wrong"), Some("Bearer admi"), Some("admin")] {
+            let (status, error) = post_proposal(&state, authorization, &proposal()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", authorization);
+            assert_eq!(error, ProposalError::Unauthorized.to_string());
+        }
+        assert!(state.proposals.list().unwrap().is_empty());
+
+        assert_eq!(post_proposal(&state, Some("Bearer admin"), &proposal()).await.0, StatusCode::CREATED);
+
+        // a server without a key takes no proposals at all
+        let mut keyless = state.clone();
+        keyless.admin_key = None;
+        assert_eq!(post_proposal(&keyless, Some("Bearer admin"), &proposal()).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejections_are_answered_with_their_status() {
+        let state = state();
+        let admin = Some("Bearer admin");
+        assert_eq!(post_proposal(&state, admin, &proposal()).await.0, StatusCode::CREATED);
+
+        let (status, error) = post_proposal(&state, admin, &proposal()).await;
+        assert_eq!((status, error.as_str()), (StatusCode::CONFLICT, "Proposal SIP-028 already exists"));
+
+        let mut invalid = proposal();
+        invalid["options"] = json!([]);
+        let (status, error) = post_proposal(&state, admin, &invalid).await;
+        assert_eq!((status, error.as_str()), (StatusCode::BAD_REQUEST, "Invalid proposal: there are no vote options"));
+
+        let (status, _) = post_proposal(&state, admin, &json!({ "id": "SIP-029" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let routes = stacks_routes(state.clone());
+        let response = warp::test::request().path("/stacks/proposals?id=SIP-029").reply(&routes).await;
+        assert_eq!(failure(response), (StatusCode::NOT_FOUND, "Unknown proposal SIP-029".to_string()));
+
+        let response = warp::test::request().path("/stacks/proof/jobs/unknown").reply(&routes).await;
+        assert_eq!(failure(response), (StatusCode::NOT_FOUND, "Job not found".to_string()));
+
+        let ballot = json!({
+            "message_inputs": {
+                "message": "I vote in favour",
+                "vote": "for",
+                "proposal": "SIP-028",
+                "balance_at_height": 0,
+                "block_proof_height": 50,
+                "voting_end_height": 60,
+            },
+            "public_key": "not hex",
+            "hash": "",
+            "signature": "",
+            "message": "",
+        });
+        let response = warp::test::request().method("POST").path("/stacks/proof/generate").json(&ballot).reply(&routes).await;
+        let (status, error) = failure(response);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error.starts_with("Invalid encoding"), "{}", error);
+    }
+}
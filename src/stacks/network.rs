@@ -0,0 +1,143 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+use crate::state::AppState;
+
+// Network selection
+//
+// Everything that differs between Stacks networks is derived from one setting, so the API we
+// read histories from, the addresses we derive from signing keys and the chain id ballots are
+// signed for can't disagree with each other.
+
+// Environment variables picking the network, and optionally overriding its API endpoint.
+pub const STACKS_NETWORK_ENV: &str = "STACKS_NETWORK";
+pub const STACKS_API_URL_ENV: &str = "STACKS_API_URL";
+
+const CHAIN_ID_MAINNET: u32 = 0x00000001;
+const CHAIN_ID_TESTNET: u32 = 0x80000000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    // a local Clarinet devnet or mocknet; it uses the testnet address versions and chain id
+    Devnet,
+}
+
+impl Network {
+    pub fn default_api_url(self) -> &'static str {
+        match self {
+            Network::Mainnet => "https://api.hiro.so",
+            Network::Testnet => "https://api.testnet.hiro.so",
+            Network::Devnet => "http://localhost:3999",
+        }
+    }
+
+    // c32 version byte of single-sig (P2PKH) addresses: SP... on mainnet, ST... elsewhere.
+    pub fn single_sig_version(self) -> u8 {
+        match self {
+            Network::Mainnet => 22,
+            Network::Testnet | Network::Devnet => 26,
+        }
+    }
+
+    // c32 version byte of multi-sig (P2SH) addresses: SM... on mainnet, SN... elsewhere.
+    pub fn multi_sig_version(self) -> u8 {
+        match self {
+            Network::Mainnet => 20,
+            Network::Testnet | Network::Devnet => 21,
+        }
+    }
+
+    // Chain id of the SIP-018 domain ballots are signed for.
+    pub fn chain_id(self) -> u32 {
+        match self {
+            Network::Mainnet => CHAIN_ID_MAINNET,
+            Network::Testnet | Network::Devnet => CHAIN_ID_TESTNET,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Devnet => write!(f, "devnet"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        match network.to_ascii_lowercase().as_str() {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "devnet" | "mocknet" => Ok(Network::Devnet),
+            other => Err(format!("Unknown Stacks network {}", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkConfig {
+    pub network: Network,
+    // root of the Stacks API, without a trailing slash
+    pub api_url: String,
+}
+
+impl NetworkConfig {
+    pub fn new(network: Network) -> Self {
+        NetworkConfig {
+            network,
+            api_url: network.default_api_url().to_string(),
+        }
+    }
+
+    // Mainnet unless STACKS_NETWORK says otherwise. An unknown network is a configuration
+    // error we don't want to paper over by silently falling back to mainnet.
+    pub fn from_env() -> Self {
+        let network = match std::env::var(STACKS_NETWORK_ENV) {
+            Ok(network) => network.parse().unwrap_or_else(|e| panic!("{}: {}", STACKS_NETWORK_ENV, e)),
+            Err(_) => Network::Mainnet,
+        };
+        let mut config = NetworkConfig::new(network);
+        if let Ok(api_url) = std::env::var(STACKS_API_URL_ENV) {
+            config.api_url = api_url.trim_end_matches('/').to_string();
+        }
+        config
+    }
+}
+
+// What front-ends need to build ballots for this server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetworkInfo {
+    pub network: Network,
+    pub api_url: String,
+    pub chain_id: u32,
+    pub single_sig_version: u8,
+    pub multi_sig_version: u8,
+}
+
+impl From<&NetworkConfig> for NetworkInfo {
+    fn from(config: &NetworkConfig) -> Self {
+        NetworkInfo {
+            network: config.network,
+            api_url: config.api_url.clone(),
+            chain_id: config.network.chain_id(),
+            single_sig_version: config.network.single_sig_version(),
+            multi_sig_version: config.network.multi_sig_version(),
+        }
+    }
+}
+
+pub fn network_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("network")
+        .and(warp::get())
+        .map(move || warp::reply::json(&NetworkInfo::from(&state.network)))
+}
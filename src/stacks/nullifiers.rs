@@ -0,0 +1,148 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secp256k1::PublicKey;
+use sha2::{Digest, Sha256};
+
+use super::storage::Storage;
+
+// Ballot nullifiers
+//
+// Every voter gets one nullifier per proposal:
+//
+//   secret    = HMAC-SHA256(server key, compressed public key)
+//   nullifier = SHA256("stxeco-zk-voting/nullifier" || secret || proposal)
+//
+// The nullifier is deterministic, so a second ballot from the same key for the same proposal
+// is detected, but without the server key it can't be linked back to a public key or address.
+// Spent nullifiers are kept in storage, and so is the server key: with another key a voter's
+// nullifiers would no longer match the spent ones.
+
+const NULLIFIER_DOMAIN: &[u8] = b"stxeco-zk-voting/nullifier";
+
+// Environment variable holding the hex encoded server key. Without it the key is generated on
+// the first start and kept in storage.
+pub const NULLIFIER_SECRET_ENV: &str = "NULLIFIER_SECRET";
+
+pub type Nullifier = [u8; 32];
+
+pub struct NullifierRegistry {
+    key: [u8; 32],
+}
+
+impl NullifierRegistry {
+    pub fn new(key: [u8; 32]) -> Self {
+        NullifierRegistry { key }
+    }
+
+    // Uses the server key kept in `storage`, storing `configured` or a random key if there is
+    // none yet. A configured key has to be the stored one, or voters could vote again.
+    pub fn load(storage: &dyn Storage, configured: Option<[u8; 32]>) -> Result<Self, String> {
+        let generated = configured.unwrap_or_else(|| {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        });
+        let key = storage.nullifier_key(generated).map_err(|e| e.to_string())?;
+        if configured.is_some_and(|configured| configured != key) {
+            return Err(format!("{} differs from the key the stored nullifiers were derived with", NULLIFIER_SECRET_ENV));
+        }
+        Ok(NullifierRegistry::new(key))
+    }
+
+    // Reads the server key from the environment, falling back to the stored one. A malformed
+    // key is refused rather than replaced.
+    pub fn from_env(storage: &dyn Storage) -> Result<Self, String> {
+        let configured = match std::env::var(NULLIFIER_SECRET_ENV) {
+            Ok(secret) => Some(
+                hex::decode(secret.trim())
+                    .ok()
+                    .and_then(|secret| <[u8; 32]>::try_from(secret).ok())
+                    .ok_or_else(|| format!("{} is not a 32 byte hex value", NULLIFIER_SECRET_ENV))?,
+            ),
+            Err(_) => None,
+        };
+        NullifierRegistry::load(storage, configured)
+    }
+
+    // Derives the voter's nullifier for a proposal from their hex encoded public key.
+    pub fn nullifier(&self, public_key_hex: &str, proposal: &str) -> Result<Nullifier, String> {
+        let public_key = hex::decode(public_key_hex.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+            .ok_or_else(|| "Invalid public key".to_string())?;
+
+        // Normalise to the compressed encoding so both encodings of a key share a nullifier.
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&public_key.serialize());
+        let secret = mac.finalize().into_bytes();
+
+        let mut hasher = Sha256::new();
+        hasher.update(NULLIFIER_DOMAIN);
+        hasher.update(secret);
+        hasher.update(proposal.as_bytes());
+        Ok(hasher.finalize().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::{Secp256k1, SecretKey};
+
+    use super::*;
+    use crate::stacks::{
+        batches::BallotRecord,
+        storage::{SqliteStorage, StoredBallot},
+    };
+
+    fn public_key() -> String {
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize())
+    }
+
+    fn ballot(nullifier: Nullifier) -> StoredBallot {
+        StoredBallot {
+            proposal: "p".to_string(),
+            vote: "for".to_string(),
+            power: 10,
+            record: BallotRecord::new(&[1], nullifier, [0; 32]),
+            history: [0; 32],
+            proof: vec![1],
+        }
+    }
+
+    #[test]
+    fn nullifiers_survive_a_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("votes.db");
+        let nullifier = {
+            let storage = SqliteStorage::open(&path).unwrap();
+            let nullifier = NullifierRegistry::load(&storage, None).unwrap().nullifier(&public_key(), "p").unwrap();
+            assert!(storage.cast(&ballot(nullifier), None).unwrap());
+            nullifier
+        };
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let registry = NullifierRegistry::load(&storage, None).unwrap();
+        assert_eq!(registry.nullifier(&public_key(), "p").unwrap(), nullifier);
+        // so a second ballot from the same key is still rejected
+        assert!(storage.is_spent("p", &nullifier).unwrap());
+        assert!(!storage.cast(&ballot(nullifier), None).unwrap());
+    }
+
+    #[test]
+    fn configured_keys_must_match_the_stored_one() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("votes.db");
+        let storage = SqliteStorage::open(&path).unwrap();
+        let first = NullifierRegistry::load(&storage, Some([1; 32])).unwrap();
+        assert_eq!(
+            NullifierRegistry::load(&storage, Some([2; 32])).err().as_deref(),
+            Some("NULLIFIER_SECRET differs from the key the stored nullifiers were derived with")
+        );
+        let again = NullifierRegistry::load(&storage, Some([1; 32])).unwrap();
+        let stored = NullifierRegistry::load(&storage, None).unwrap();
+        let nullifier = first.nullifier(&public_key(), "p").unwrap();
+        assert_eq!(again.nullifier(&public_key(), "p").unwrap(), nullifier);
+        assert_eq!(stored.nullifier(&public_key(), "p").unwrap(), nullifier);
+    }
+}
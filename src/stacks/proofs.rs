@@ -1,55 +1,498 @@
 use base64::{engine::general_purpose, Engine};
 use warp::{reject::Rejection, Filter};
 
-use crate::{proofs::{stacks_voting::{SignatureData, StacksVotingProofGenrator}, ApplicationResponseMessage, ProofResponse, VotingProofGenerator}, stacks::{transactions::TransactionFetchError, utils::public_key_to_stacks_address, ProofError}};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 
-use super::utils::fetch_all_transactions;
+use crate::{proofs::{stacks_voting::{ballot_commitment, BallotInputs, MessageInputs, SignatureData, StacksVotingProofVerifier, VerificationFailure}, ApplicationResponseMessage}, stacks::ProofError, state::AppState};
 
-pub fn proofs_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+use super::{jobs::{self, ProofJobRequest}, nullifiers::Nullifier, utils::decode_hex32, NotFound};
+
+pub fn proofs_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let state = warp::any().map(move || state.clone());
     warp::path("proof")
         .and(
             warp::path("generate")
                 .and(warp::post())
+                .and(state.clone())
                 .and(warp::body::json::<SignatureData>())
-                .and_then(|signature_data: SignatureData| async move {
-                    match generate_proof(signature_data).await {
+                .and_then(|state: AppState, signature_data: SignatureData| async move {
+                    match generate_proof(&state, signature_data).await {
                         Ok(response) => Ok(warp::reply::json(&response)),
                         Err(e) => Err(e), // Pass along rejections
                     }
                 })
             .or(
-                warp::path("validate")
+                warp::path("threshold")
+                    .and(warp::post())
+                    .and(state.clone())
+                    .and(warp::body::json::<ThresholdProofRequest>())
+                    .and_then(|state: AppState, request: ThresholdProofRequest| async move {
+                        match generate_threshold_proof(&state, request.signature_data, request.threshold).await {
+                            Ok(response) => Ok(warp::reply::json(&response)),
+                            Err(e) => Err(e),
+                        }
+                    })
+            )
+            .or(
+                warp::path("locked")
+                    .and(warp::post())
+                    .and(state.clone())
+                    .and(warp::body::json::<SignatureData>())
+                    .and_then(|state: AppState, signature_data: SignatureData| async move {
+                        match generate_locked_proof(&state, signature_data).await {
+                            Ok(response) => Ok(warp::reply::json(&response)),
+                            Err(e) => Err(e),
+                        }
+                    })
+            )
+            .or(
+                warp::path("token")
+                    .and(warp::post())
+                    .and(state.clone())
+                    .and(warp::body::json::<SignatureData>())
+                    .and_then(|state: AppState, signature_data: SignatureData| async move {
+                        match generate_token_proof(&state, signature_data).await {
+                            Ok(response) => Ok(warp::reply::json(&response)),
+                            Err(e) => Err(e),
+                        }
+                    })
+            )
+            .or(
+                warp::path("nft")
+                    .and(warp::post())
+                    .and(state.clone())
+                    .and(warp::body::json::<NftProofRequest>())
+                    .and_then(|state: AppState, request: NftProofRequest| async move {
+                        match generate_nft_proof(&state, request.signature_data, request.min_count).await {
+                            Ok(response) => Ok(warp::reply::json(&response)),
+                            Err(e) => Err(e),
+                        }
+                    })
+            )
+            .or(
+                warp::path("quadratic")
                     .and(warp::post())
+                    .and(state.clone())
                     .and(warp::body::json::<SignatureData>())
-                    .and_then(validate_proof)
+                    .and_then(|state: AppState, signature_data: SignatureData| async move {
+                        match generate_quadratic_proof(&state, signature_data).await {
+                            Ok(response) => Ok(warp::reply::json(&response)),
+                            Err(e) => Err(e),
+                        }
+                    })
+            )
+            .or(
+                warp::path!("jobs")
+                    .and(warp::post())
+                    .and(state.clone())
+                    .and(warp::body::json::<ProofJobRequest>())
+                    .and_then(|state: AppState, request: ProofJobRequest| async move {
+                        jobs::submit(&state, request, None).await.map(|status| warp::reply::json(&status))
+                    })
+            )
+            .or(
+                warp::path!("jobs" / String)
+                    .and(warp::get())
+                    .and(state.clone())
+                    .and_then(|job_id: String, state: AppState| async move {
+                        match state.jobs.status(&job_id) {
+                            Some(status) => Ok(warp::reply::json(&status)),
+                            None => Err(warp::reject::custom(NotFound("Job"))),
+                        }
+                    })
+            )
+            .or(
+                warp::path("validate")
+                    .and(warp::post())
+                    .and(state)
+                    .and(warp::body::json::<ValidateProofRequest>())
+                    .and_then(|state: AppState, request: ValidateProofRequest| async move {
+                        Ok::<_, Rejection>(warp::reply::json(&validate_proof(&state, &request).await))
+                    })
             )
         )
 }
 
-pub async fn generate_proof(signature_data: SignatureData) -> Result<ApplicationResponseMessage, Rejection> {
-    let public_key = signature_data.public_key.clone();
-    
-    let stacks_address = public_key_to_stacks_address(public_key)
-        .map_err(|e| warp::reject::custom(ProofError::new(&format!("Address conversion error: {:?}", e))))?;
+// Body of a threshold proof request: the signed ballot plus the minimum balance the voter
+// wants to prove, in micro-STX.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThresholdProofRequest {
+    pub signature_data: SignatureData,
+    #[serde_as(as = "DisplayFromStr")]
+    pub threshold: u128,
+}
+
+// Body of an NFT ownership proof request: the signed ballot plus the number of tokens of the
+// governance collection the voter wants to prove owning.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NftProofRequest {
+    pub signature_data: SignatureData,
+    pub min_count: u64,
+}
+
+// Which claim a proof makes about the voter's balance.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClaimKind {
+    // `value` is the balance itself
+    Balance,
+    // `value` is a lower bound on the balance
+    Threshold,
+    // `value` is the amount locked by stacking, which unlocks at `unlock_height`
+    Locked,
+    // `value` is the balance in the token `asset_identifier`
+    Token,
+    // `value` is a lower bound on the number of tokens of the collection `asset_identifier`
+    Nft,
+    // `value` is the integer square root of the balance
+    Quadratic,
+}
+
+// The public inputs a proof was generated for, as returned by the generate endpoints.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProofPublicInputs {
+    pub claim: ClaimKind,
+    #[serde_as(as = "DisplayFromStr")]
+    pub value: u128,
+    pub height: u64,
+    // hex encoded index block hash of the block at `height`
+    pub block_hash: String,
+    // hex encoded commitment to the ledger the value was computed over
+    pub history: String,
+    // burnchain unlock height, for locked claims
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlock_height: Option<u64>,
+    // SIP-010 or SIP-009 asset identifier, for token and NFT claims
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset_identifier: Option<String>,
+    // hex encoded commitment to the proposal and vote
+    pub commitment: String,
+    // hex encoded secret the commitment is blinded with, which only the voter was given
+    pub blinding: String,
+    // hex encoded voter nullifier
+    pub nullifier: String,
+}
+
+// Body of a validation request: a base64 encoded proof, its public inputs and the ballot the
+// proof is claimed to be for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidateProofRequest {
+    pub proof: String,
+    pub public_inputs: ProofPublicInputs,
+    pub ballot: MessageInputs,
+}
+
+// Outcome of a validation request. `reason` is one of bad_encoding, insufficient_security,
+// constraint_failure, public_input_mismatch or not_canonical.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationVerdict {
+    pub valid: bool,
+    pub reason: Option<String>,
+    pub detail: Option<String>,
+}
 
-    let transactions = fetch_all_transactions(&stacks_address).await
-        .map_err(|e| warp::reject::custom(ProofError::new(&format!("Transaction fetch error: {}", e))))?;
+impl From<Result<(), VerificationFailure>> for ValidationVerdict {
+    fn from(result: Result<(), VerificationFailure>) -> Self {
+        match result {
+            Ok(()) => ValidationVerdict { valid: true, reason: None, detail: None },
+            Err(failure) => ValidationVerdict {
+                valid: false,
+                reason: Some(failure.reason().to_string()),
+                detail: Some(failure.detail().to_string()),
+            },
+        }
+    }
+}
 
-    let (proof, result) = StacksVotingProofGenrator::generate_proof(signature_data, transactions);
+// Runs a proof job and waits for it, for clients that want the proof in the response.
+async fn generate_and_wait(state: &AppState, request: ProofJobRequest) -> Result<ApplicationResponseMessage, Rejection> {
+    let job = jobs::submit(state, request, None).await?;
+    let status = state.jobs.wait(&job.job_id).await
+        .ok_or_else(|| warp::reject::custom(ProofError::new("Proof job disappeared")))?;
+    match status.result {
+        Some(response) => Ok(ApplicationResponseMessage::ProofGenerationResponse(response)),
+        None => Err(warp::reject::custom(ProofError::new(
+            &status.error.unwrap_or_else(|| "Proof generation failed".to_string()),
+        ))),
+    }
+}
 
-    let b64_proof = general_purpose::STANDARD.encode(proof);
+pub async fn generate_proof(state: &AppState, signature_data: SignatureData) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Balance { signature_data }).await
+}
 
-    // Prepare response
-    let response = ProofResponse::StacksVotingProof {
-        result: result.to_string(),
-        proof: b64_proof,
+pub async fn generate_threshold_proof(state: &AppState, signature_data: SignatureData, threshold: u128) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Threshold { signature_data, threshold }).await
+}
+
+pub async fn generate_locked_proof(state: &AppState, signature_data: SignatureData) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Locked { signature_data }).await
+}
+
+pub async fn generate_token_proof(state: &AppState, signature_data: SignatureData) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Token { signature_data }).await
+}
+
+pub async fn generate_nft_proof(state: &AppState, signature_data: SignatureData, min_count: u64) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Nft { signature_data, min_count }).await
+}
+
+pub async fn generate_quadratic_proof(state: &AppState, signature_data: SignatureData) -> Result<ApplicationResponseMessage, Rejection> {
+    generate_and_wait(state, ProofJobRequest::Quadratic { signature_data }).await
+}
+
+pub async fn validate_proof(state: &AppState, request: &ValidateProofRequest) -> ValidationVerdict {
+    let result = match check_proof(state, request) {
+        Ok(block_hash) => check_anchor(state, request.public_inputs.height, &block_hash).await,
+        Err(failure) => Err(failure),
+    };
+    result.into()
+}
+
+// A proof only speaks for the chain state it was generated against: the block it is anchored
+// to must still be the canonical block at its height.
+pub async fn check_anchor(state: &AppState, height: u64, block_hash: &[u8; 32]) -> Result<(), VerificationFailure> {
+    let block = state.transactions.fetch_block(height).await
+        .map_err(|e| VerificationFailure::NotCanonical(format!("block {} could not be fetched: {}", height, e)))?
+        .ok_or_else(|| VerificationFailure::NotCanonical(format!("block {} is unknown", height)))?;
+    if !block.canonical || block.index_block_hash_bytes().as_ref() != Some(block_hash) {
+        return Err(VerificationFailure::NotCanonical(format!(
+            "{} is not the canonical block at height {}",
+            hex::encode(block_hash), height
+        )));
+    }
+    Ok(())
+}
+
+// The history commitment is blinded with a salt only the server had, so the one a ballot was
+// proven over can't be recomputed; it must be the one stored when the ballot was cast.
+pub fn check_history(state: &AppState, proposal: &str, nullifier: &Nullifier, history: &[u8; 32]) -> Result<(), VerificationFailure> {
+    let stored = state.storage.history(proposal, nullifier)
+        .map_err(|e| VerificationFailure::PublicInputMismatch(format!("the ballot's history could not be read: {}", e)))?
+        .ok_or_else(|| VerificationFailure::PublicInputMismatch("no ballot was cast under this nullifier".to_string()))?;
+    if &stored != history {
+        return Err(VerificationFailure::PublicInputMismatch(
+            "history does not match the one the ballot was cast with".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Checks the proof against its public inputs, returning the block hash it is anchored to.
+fn check_proof(state: &AppState, request: &ValidateProofRequest) -> Result<[u8; 32], VerificationFailure> {
+    let public_inputs = &request.public_inputs;
+    let ballot = &request.ballot;
+
+    let proof = general_purpose::STANDARD.decode(&request.proof)
+        .map_err(|_| VerificationFailure::BadEncoding("proof is not valid base64".to_string()))?;
+    let nullifier = decode_hex32(&public_inputs.nullifier)
+        .ok_or_else(|| VerificationFailure::BadEncoding("nullifier is not a 32 byte hex value".to_string()))?;
+    let commitment = decode_hex32(&public_inputs.commitment)
+        .ok_or_else(|| VerificationFailure::BadEncoding("commitment is not a 32 byte hex value".to_string()))?;
+    let blinding = decode_hex32(&public_inputs.blinding)
+        .ok_or_else(|| VerificationFailure::BadEncoding("blinding is not a 32 byte hex value".to_string()))?;
+    let block_hash = decode_hex32(&public_inputs.block_hash)
+        .ok_or_else(|| VerificationFailure::BadEncoding("block_hash is not a 32 byte hex value".to_string()))?;
+    let history = decode_hex32(&public_inputs.history)
+        .ok_or_else(|| VerificationFailure::BadEncoding("history is not a 32 byte hex value".to_string()))?;
+
+    // The public inputs must describe the submitted ballot before the proof is looked at.
+    if public_inputs.height != ballot.block_proof_height {
+        return Err(VerificationFailure::PublicInputMismatch(format!(
+            "height {} does not match the ballot's block_proof_height {}",
+            public_inputs.height, ballot.block_proof_height
+        )));
+    }
+    if commitment != ballot_commitment(&ballot.proposal, &ballot.vote, &blinding) {
+        return Err(VerificationFailure::PublicInputMismatch(
+            "commitment does not open to the ballot's proposal and vote".to_string(),
+        ));
+    }
+    check_history(state, &ballot.proposal, &nullifier, &history)?;
+
+    let ballot_inputs = BallotInputs::new(ballot, &blinding, &nullifier, &block_hash);
+    let verified = match public_inputs.claim {
+        ClaimKind::Balance => StacksVotingProofVerifier::verify_balance_proof(public_inputs.value, &history, ballot_inputs, &proof),
+        ClaimKind::Threshold => StacksVotingProofVerifier::verify_threshold_proof(public_inputs.value, &history, ballot_inputs, &proof),
+        ClaimKind::Locked => {
+            let unlock_height = public_inputs.unlock_height.ok_or_else(|| {
+                VerificationFailure::PublicInputMismatch("locked claims need an unlock_height".to_string())
+            })?;
+            StacksVotingProofVerifier::verify_locked_proof(public_inputs.value, unlock_height, &history, ballot_inputs, &proof)
+        }
+        ClaimKind::Token => {
+            let asset_identifier = public_inputs.asset_identifier.as_deref().ok_or_else(|| {
+                VerificationFailure::PublicInputMismatch("token claims need an asset_identifier".to_string())
+            })?;
+            StacksVotingProofVerifier::verify_token_proof(public_inputs.value, asset_identifier, &history, ballot_inputs, &proof)
+        }
+        ClaimKind::Nft => {
+            let asset_identifier = public_inputs.asset_identifier.as_deref().ok_or_else(|| {
+                VerificationFailure::PublicInputMismatch("NFT claims need an asset_identifier".to_string())
+            })?;
+            let min_count = u64::try_from(public_inputs.value)
+                .map_err(|_| VerificationFailure::BadEncoding("NFT counts fit in 64 bits".to_string()))?;
+            StacksVotingProofVerifier::verify_nft_proof(min_count, asset_identifier, &history, ballot_inputs, &proof)
+        }
+        ClaimKind::Quadratic => StacksVotingProofVerifier::verify_quadratic_proof(public_inputs.value, &history, ballot_inputs, &proof),
     };
-    let application_response = ApplicationResponseMessage::ProofGenerationResponse(response);
-    println!("Message: {}", stacks_address);
-    Ok(application_response)
+    verified.map(|()| block_hash)
 }
 
-async fn validate_proof(body: SignatureData) -> Result<impl warp::Reply, warp::Rejection> {
-    println!("Validating proof with public key: {:?}", body);
-    Ok(warp::reply::json(&serde_json::json!({"status": "proof validated"})))
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proofs::ProofResponse,
+        stacks::source::BlockInfo,
+        tests::{ballot, server, SNAPSHOT},
+    };
+
+    // Casts a ballot for "for" and returns the validation request its proof makes.
+    async fn proven(state: &AppState) -> ValidateProofRequest {
+        let signature_data = ballot(7, "for", SNAPSHOT);
+        let response = match generate_proof(state, signature_data.clone()).await.unwrap() {
+            ApplicationResponseMessage::ProofGenerationResponse(response) => response,
+            other => panic!("{:?}", other),
+        };
+        let ProofResponse::StacksVotingProof { result, block_hash, history, commitment, blinding, nullifier, proof } = response else {
+            panic!("{:?}", response);
+        };
+        ValidateProofRequest {
+            proof,
+            public_inputs: ProofPublicInputs {
+                claim: ClaimKind::Balance,
+                value: result.parse().unwrap(),
+                height: SNAPSHOT,
+                block_hash,
+                history,
+                unlock_height: None,
+                asset_identifier: None,
+                commitment,
+                blinding,
+                nullifier,
+            },
+            ballot: signature_data.message_inputs,
+        }
+    }
+
+    async fn reason(state: &AppState, request: &ValidateProofRequest) -> Option<String> {
+        validate_proof(state, request).await.reason
+    }
+
+    #[tokio::test]
+    async fn cast_proofs_are_valid() {
+        let (state, _) = server(55);
+        let request = proven(&state).await;
+        let verdict = validate_proof(&state, &request).await;
+        assert!(verdict.valid, "{:?}", verdict);
+    }
+
+    #[tokio::test]
+    async fn forged_proofs_are_rejected() {
+        let (state, _) = server(55);
+        let request = proven(&state).await;
+
+        let mut forged = request.clone();
+        let mut proof = general_purpose::STANDARD.decode(&forged.proof).unwrap();
+        let last = proof.len() - 1;
+        proof[last] ^= 1;
+        forged.proof = general_purpose::STANDARD.encode(proof);
+        assert!(!validate_proof(&state, &forged).await.valid);
+
+        let mut forged = request.clone();
+        forged.proof = "not base64!".to_string();
+        assert_eq!(reason(&state, &forged).await.as_deref(), Some("bad_encoding"));
+
+        // the proof doesn't hold for another balance
+        let mut forged = request.clone();
+        forged.public_inputs.value += 1;
+        assert_eq!(reason(&state, &forged).await.as_deref(), Some("constraint_failure"));
+    }
+
+    #[tokio::test]
+    async fn public_inputs_must_describe_the_cast_ballot() {
+        let (state, _) = server(55);
+        let request = proven(&state).await;
+
+        let mut other_vote = request.clone();
+        other_vote.ballot.vote = "against".to_string();
+        assert_eq!(reason(&state, &other_vote).await.as_deref(), Some("public_input_mismatch"));
+
+        // the commitment only opens with the voter's blinding
+        let mut other_blinding = request.clone();
+        other_blinding.public_inputs.blinding = hex::encode([0; 32]);
+        assert_eq!(reason(&state, &other_blinding).await.as_deref(), Some("public_input_mismatch"));
+
+        let mut other_height = request.clone();
+        other_height.public_inputs.height = SNAPSHOT - 1;
+        assert_eq!(reason(&state, &other_height).await.as_deref(), Some("public_input_mismatch"));
+
+        let mut other_history = request.clone();
+        other_history.public_inputs.history = hex::encode([0; 32]);
+        assert_eq!(reason(&state, &other_history).await.as_deref(), Some("public_input_mismatch"));
+
+        // no ballot was cast under this nullifier
+        let mut other_nullifier = request.clone();
+        other_nullifier.public_inputs.nullifier = hex::encode([0; 32]);
+        assert_eq!(reason(&state, &other_nullifier).await.as_deref(), Some("public_input_mismatch"));
+    }
+
+    #[tokio::test]
+    async fn proofs_anchored_to_forked_blocks_are_rejected() {
+        let (state, source) = server(55);
+        let request = proven(&state).await;
+
+        // the snapshot block was reorganized away
+        source.insert_block(BlockInfo {
+            height: SNAPSHOT,
+            index_block_hash: format!("0x{}", request.public_inputs.block_hash),
+            burn_block_height: 850,
+            canonical: false,
+        });
+        assert_eq!(reason(&state, &request).await.as_deref(), Some("not_canonical"));
+
+        // and another block took its height
+        source.insert_block(BlockInfo {
+            height: SNAPSHOT,
+            index_block_hash: format!("0x{}", "cd".repeat(32)),
+            burn_block_height: 850,
+            canonical: true,
+        });
+        assert_eq!(reason(&state, &request).await.as_deref(), Some("not_canonical"));
+    }
+
+    #[tokio::test]
+    async fn websocket_verification_names_the_balance_and_height() {
+        let (state, _) = server(55);
+        let request = proven(&state).await;
+        let message = |balance: u128, height: u64| serde_json::json!({
+            "message_type": "ProofVerification",
+            "proof_type": "StacksVotingProof",
+            "balance": balance.to_string(),
+            "height": height.to_string(),
+            "block_hash": request.public_inputs.block_hash,
+            "history": request.public_inputs.history,
+            "proposal": request.ballot.proposal,
+            "vote": request.ballot.vote,
+            "blinding": request.public_inputs.blinding,
+            "nullifier": request.public_inputs.nullifier,
+            "proof": general_purpose::STANDARD.decode(&request.proof).unwrap(),
+        });
+        let verify = |message: serde_json::Value| {
+            let state = state.clone();
+            async move {
+                let envelope = crate::proofs::handle_request(&message.to_string(), &state, None).await.unwrap();
+                serde_json::to_value(&envelope).unwrap()["ProofVerificationResponse"]["ok"].clone()
+            }
+        };
+
+        assert_eq!(verify(message(750, SNAPSHOT)).await, true);
+        assert_eq!(verify(message(751, SNAPSHOT)).await, false);
+        assert_eq!(verify(message(750, SNAPSHOT - 1)).await, false);
+    }
 }
@@ -0,0 +1,425 @@
+use std::{fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use warp::{http::StatusCode, reject::Reject, Filter};
+
+use crate::state::AppState;
+
+use super::{
+    ballots::{Ballot, BallotError, BallotFormat},
+    jobs::ProofJobRequest,
+    storage::{Storage, StorageError},
+    tally::Weighting,
+};
+
+// Proposals
+//
+// Ballots name the proposal they are cast on, the height their balance is taken at and the
+// end of the voting window. Those are only signed by the voter, so the server keeps the
+// proposals it accepts ballots for and checks each ballot against its proposal: the snapshot
+// height and the end of the window have to be the proposal's, the vote a ballot of its format
+// over its options, the claim the one its eligibility rule asks for, and the chain tip inside the window.
+
+// The claim voters have to prove to take part.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "claim", rename_all = "lowercase")]
+pub enum Eligibility {
+    // any STX balance, revealed by the proof
+    Balance,
+    // at least `threshold` micro-STX, without revealing the balance
+    Threshold {
+        #[serde_as(as = "DisplayFromStr")]
+        threshold: u128,
+    },
+    // STX locked by stacking
+    Locked,
+    // a balance in the configured governance token
+    Token,
+    // at least `min_count` tokens of the configured NFT collection
+    Nft { min_count: u64 },
+    // the quadratic weight of the STX balance, which ballots are counted with under
+    // voting power weighting
+    Quadratic,
+}
+
+impl Eligibility {
+    // Whether the claim of `request` satisfies the rule. Proving a higher threshold or count
+    // than the rule asks for is fine.
+    fn admits(&self, request: &ProofJobRequest) -> bool {
+        match (self, request) {
+            (Eligibility::Balance, ProofJobRequest::Balance { .. })
+            | (Eligibility::Locked, ProofJobRequest::Locked { .. })
+            | (Eligibility::Token, ProofJobRequest::Token { .. })
+            | (Eligibility::Quadratic, ProofJobRequest::Quadratic { .. }) => true,
+            (Eligibility::Threshold { threshold }, ProofJobRequest::Threshold { threshold: claimed, .. }) => claimed >= threshold,
+            (Eligibility::Nft { min_count }, ProofJobRequest::Nft { min_count: claimed, .. }) => claimed >= min_count,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Eligibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Eligibility::Balance => write!(f, "a balance proof"),
+            Eligibility::Threshold { threshold } => write!(f, "a threshold proof of at least {} micro-STX", threshold),
+            Eligibility::Locked => write!(f, "a locked STX proof"),
+            Eligibility::Token => write!(f, "a governance token proof"),
+            Eligibility::Nft { min_count } => write!(f, "an NFT proof of at least {} tokens", min_count),
+            Eligibility::Quadratic => write!(f, "a quadratic weight proof"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Proposal {
+    // the `proposal` ballots name
+    pub id: String,
+    // the height balances are proven at, which ballots sign as `block_proof_height`
+    pub snapshot_height: u64,
+    // the chain tip has to be within the window, both ends included, for ballots to be taken
+    pub start_height: u64,
+    pub end_height: u64,
+    // the options ballots choose from
+    pub options: Vec<String>,
+    // how ballots choose among the options; one option unless set
+    #[serde(default)]
+    pub ballot_format: BallotFormat,
+    pub eligibility: Eligibility,
+    // how ballots are counted; by the voting power they prove unless set
+    #[serde(default)]
+    pub weighting: Weighting,
+}
+
+impl Proposal {
+    fn validate(&self) -> Result<(), ProposalError> {
+        if self.id.is_empty() {
+            return Err(ProposalError::Invalid("the id is empty"));
+        }
+        if self.start_height > self.end_height {
+            return Err(ProposalError::Invalid("the voting window ends before it starts"));
+        }
+        // balances have to be final by the time voting starts
+        if self.snapshot_height > self.start_height {
+            return Err(ProposalError::Invalid("the snapshot is taken after voting starts"));
+        }
+        if self.options.is_empty() {
+            return Err(ProposalError::Invalid("there are no vote options"));
+        }
+        if self.options.iter().enumerate().any(|(i, option)| self.options[..i].contains(option)) {
+            return Err(ProposalError::Invalid("the vote options are not distinct"));
+        }
+        Ok(())
+    }
+
+    // Checks the parts of a ballot that don't depend on the chain tip.
+    pub fn check_ballot(&self, request: &ProofJobRequest) -> Result<(), ProposalError> {
+        let inputs = &request.signature_data().message_inputs;
+        if inputs.block_proof_height != self.snapshot_height {
+            return Err(ProposalError::SnapshotMismatch { expected: self.snapshot_height, actual: inputs.block_proof_height });
+        }
+        if inputs.voting_end_height != self.end_height {
+            return Err(ProposalError::VotingEndMismatch { expected: self.end_height, actual: inputs.voting_end_height });
+        }
+        Ballot::parse(self.ballot_format, &inputs.vote, &self.options).map_err(ProposalError::InvalidVote)?;
+        if !self.eligibility.admits(request) {
+            return Err(ProposalError::Ineligible(self.eligibility.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn is_open(&self, tip_height: u64) -> bool {
+        (self.start_height..=self.end_height).contains(&tip_height)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProposalError {
+    // The proposal can't be registered as given.
+    Invalid(&'static str),
+    AlreadyExists(String),
+    // The ballot names a proposal that isn't registered.
+    Unknown(String),
+    SnapshotMismatch { expected: u64, actual: u64 },
+    VotingEndMismatch { expected: u64, actual: u64 },
+    InvalidVote(BallotError),
+    // The ballot's claim doesn't satisfy the proposal's eligibility rule.
+    Ineligible(Eligibility),
+    // The chain tip is outside the voting window.
+    Closed { start_height: u64, end_height: u64, tip_height: u64 },
+    // The chain tip couldn't be determined, so the window can't be checked.
+    UnknownTip(String),
+    // The request to register a proposal doesn't carry the admin key.
+    Unauthorized,
+    Storage(StorageError),
+}
+
+impl fmt::Display for ProposalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposalError::Invalid(reason) => write!(f, "Invalid proposal: {}", reason),
+            ProposalError::AlreadyExists(id) => write!(f, "Proposal {} already exists", id),
+            ProposalError::Unknown(id) => write!(f, "Unknown proposal {}", id),
+            ProposalError::SnapshotMismatch { expected, actual } => {
+                write!(f, "Ballot is for snapshot height {}, the proposal's is {}", actual, expected)
+            }
+            ProposalError::VotingEndMismatch { expected, actual } => {
+                write!(f, "Ballot is for voting end height {}, the proposal's is {}", actual, expected)
+            }
+            ProposalError::InvalidVote(e) => write!(f, "{}", e),
+            ProposalError::Ineligible(rule) => write!(f, "The proposal requires {}", rule),
+            ProposalError::Closed { start_height, end_height, tip_height } => write!(
+                f,
+                "Voting is open from height {} to {}, the chain is at {}",
+                start_height, end_height, tip_height
+            ),
+            ProposalError::UnknownTip(reason) => write!(f, "Chain tip unknown: {}", reason),
+            ProposalError::Unauthorized => write!(f, "Registering proposals requires the admin key"),
+            ProposalError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Reject for ProposalError {}
+
+impl From<StorageError> for ProposalError {
+    fn from(err: StorageError) -> Self {
+        ProposalError::Storage(err)
+    }
+}
+
+pub struct ProposalRegistry {
+    storage: Arc<dyn Storage>,
+}
+
+impl ProposalRegistry {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        ProposalRegistry { storage }
+    }
+
+    pub fn create(&self, proposal: Proposal) -> Result<(), ProposalError> {
+        proposal.validate()?;
+        if !self.storage.insert_proposal(&proposal)? {
+            return Err(ProposalError::AlreadyExists(proposal.id));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<Proposal>, StorageError> {
+        self.storage.proposal(id)
+    }
+
+    // Every proposal, ordered by id.
+    pub fn list(&self) -> Result<Vec<Proposal>, StorageError> {
+        self.storage.proposals()
+    }
+}
+
+// Checks a ballot against its proposal, including that voting is open at the current tip.
+pub async fn check_ballot(state: &AppState, request: &ProofJobRequest) -> Result<(), ProposalError> {
+    let id = &request.signature_data().message_inputs.proposal;
+    let proposal = state.proposals.get(id)?.ok_or_else(|| ProposalError::Unknown(id.clone()))?;
+    proposal.check_ballot(request)?;
+    let tip_height = state.transactions.fetch_tip_height().await
+        .map_err(|e| ProposalError::UnknownTip(e.to_string()))?
+        .ok_or_else(|| ProposalError::UnknownTip("the transaction source doesn't report it".to_string()))?;
+    if !proposal.is_open(tip_height) {
+        return Err(ProposalError::Closed {
+            start_height: proposal.start_height,
+            end_height: proposal.end_height,
+            tip_height,
+        });
+    }
+    Ok(())
+}
+
+// Whether the `Authorization` header carries the admin key as a bearer token. Without a
+// configured key nobody is authorized. The comparison takes as long wherever the key differs.
+fn is_admin(state: &AppState, authorization: Option<&str>) -> bool {
+    let (Some(admin_key), Some(token)) = (&state.admin_key, authorization.and_then(|value| value.strip_prefix("Bearer "))) else {
+        return false;
+    };
+    admin_key.len() == token.len()
+        && admin_key.bytes().zip(token.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+// Proposal ids are free text, so a single proposal is fetched by query parameter.
+#[derive(Deserialize, Debug)]
+pub struct ProposalQuery {
+    pub id: Option<String>,
+}
+
+pub fn proposals_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let state = warp::any().map(move || state.clone());
+    warp::path!("proposals")
+        .and(warp::post())
+        .and(state.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json::<Proposal>())
+        .and_then(|state: AppState, authorization: Option<String>, proposal: Proposal| async move {
+            if !is_admin(&state, authorization.as_deref()) {
+                return Err(warp::reject::custom(ProposalError::Unauthorized));
+            }
+            state.proposals.create(proposal.clone()).map_err(warp::reject::custom)?;
+            Ok::<_, warp::Rejection>(warp::reply::with_status(warp::reply::json(&proposal), StatusCode::CREATED))
+        })
+        .or(
+            warp::path!("proposals")
+                .and(warp::get())
+                .and(state)
+                .and(warp::query::<ProposalQuery>())
+                .and_then(|state: AppState, query: ProposalQuery| async move {
+                    match query.id {
+                        None => Ok(warp::reply::json(&state.proposals.list().map_err(warp::reject::custom)?)),
+                        Some(id) => state.proposals.get(&id)
+                            .map_err(warp::reject::custom)?
+                            .map(|proposal| warp::reply::json(&proposal))
+                            .ok_or_else(|| warp::reject::custom(ProposalError::Unknown(id))),
+                    }
+                })
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::SecretKey;
+
+    use super::*;
+    use crate::{
+        proofs::stacks_voting::MessageInputs,
+        stacks::{
+            network::{Network, NetworkConfig},
+            nullifiers::NullifierRegistry,
+            source::MemorySource,
+            storage::MemoryStorage,
+        },
+        tests::sign_ballot,
+    };
+
+    fn proposal() -> Proposal {
+        Proposal {
+            id: "SIP-028".to_string(),
+            snapshot_height: 50,
+            start_height: 50,
+            end_height: 60,
+            options: vec!["for".to_string(), "against".to_string()],
+            ballot_format: BallotFormat::default(),
+            eligibility: Eligibility::Threshold { threshold: 1000 },
+            weighting: Weighting::default(),
+        }
+    }
+
+    fn threshold_ballot(vote: &str, block_proof_height: u64, voting_end_height: u64, threshold: u128) -> ProofJobRequest {
+        let message_inputs = MessageInputs {
+            message: "I vote".to_string(),
+            vote: vote.to_string(),
+            proposal: "SIP-028".to_string(),
+            balance_at_height: 0,
+            block_proof_height,
+            voting_end_height,
+        };
+        let signature_data = sign_ballot(message_inputs, &SecretKey::from_slice(&[7; 32]).unwrap());
+        ProofJobRequest::Threshold { signature_data, threshold }
+    }
+
+    #[test]
+    fn proposals_are_validated() {
+        assert!(proposal().validate().is_ok());
+        type Change = fn(&mut Proposal);
+        let cases: [(Change, &str); 5] = [
+            (|p| p.id.clear(), "the id is empty"),
+            (|p| p.start_height = 61, "the voting window ends before it starts"),
+            (|p| p.snapshot_height = 51, "the snapshot is taken after voting starts"),
+            (|p| p.options.clear(), "there are no vote options"),
+            (|p| p.options.push("for".to_string()), "the vote options are not distinct"),
+        ];
+        for (change, reason) in cases {
+            let mut proposal = proposal();
+            change(&mut proposal);
+            assert!(matches!(proposal.validate(), Err(ProposalError::Invalid(r)) if r == reason), "{}", reason);
+        }
+    }
+
+    #[test]
+    fn the_window_includes_both_ends() {
+        let proposal = proposal();
+        assert!(!proposal.is_open(49));
+        assert!(proposal.is_open(50));
+        assert!(proposal.is_open(60));
+        assert!(!proposal.is_open(61));
+    }
+
+    #[test]
+    fn ballots_are_checked_against_the_proposal() {
+        let proposal = proposal();
+        assert!(proposal.check_ballot(&threshold_ballot("for", 50, 60, 1000)).is_ok());
+        // proving more than the rule asks for is fine
+        assert!(proposal.check_ballot(&threshold_ballot("against", 50, 60, 5000)).is_ok());
+
+        assert!(matches!(
+            proposal.check_ballot(&threshold_ballot("for", 49, 60, 1000)),
+            Err(ProposalError::SnapshotMismatch { expected: 50, actual: 49 })
+        ));
+        assert!(matches!(
+            proposal.check_ballot(&threshold_ballot("for", 50, 70, 1000)),
+            Err(ProposalError::VotingEndMismatch { expected: 60, actual: 70 })
+        ));
+        assert!(matches!(
+            proposal.check_ballot(&threshold_ballot("abstain", 50, 60, 1000)),
+            Err(ProposalError::InvalidVote(BallotError::UnknownOption(_)))
+        ));
+        assert!(matches!(
+            proposal.check_ballot(&threshold_ballot("for", 50, 60, 999)),
+            Err(ProposalError::Ineligible(Eligibility::Threshold { threshold: 1000 }))
+        ));
+        let ProofJobRequest::Threshold { signature_data, .. } = threshold_ballot("for", 50, 60, 1000) else { unreachable!() };
+        assert!(matches!(
+            proposal.check_ballot(&ProofJobRequest::Balance { signature_data }),
+            Err(ProposalError::Ineligible(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn registered_proposals_are_served() {
+        let state = AppState::new(
+            NetworkConfig::new(Network::Mainnet),
+            NullifierRegistry::new([1; 32]),
+            Arc::new(MemorySource::new()),
+            Arc::new(MemoryStorage::new()),
+            1,
+            Default::default(),
+        ).with_admin_key("admin");
+        let routes = proposals_routes(state).recover(super::super::handle_rejection);
+        let register = |authorization: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/proposals")
+                .header("authorization", authorization)
+                .json(&proposal())
+        };
+
+        let error = |response: warp::http::Response<warp::hyper::body::Bytes>| {
+            (response.status(), serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()["error"].clone())
+        };
+        assert_eq!(
+            error(register("Bearer nimda").reply(&routes).await),
+            (StatusCode::UNAUTHORIZED, "Registering proposals requires the admin key".into())
+        );
+        assert_eq!(register("Bearer admin").reply(&routes).await.status(), StatusCode::CREATED);
+        assert_eq!(
+            error(register("Bearer admin").reply(&routes).await),
+            (StatusCode::CONFLICT, "Proposal SIP-028 already exists".into())
+        );
+
+        let response = warp::test::request().path("/proposals?id=SIP-028").reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<Proposal>(response.body()).unwrap(), proposal());
+        let response = warp::test::request().path("/proposals").reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<Vec<Proposal>>(response.body()).unwrap(), vec![proposal()]);
+        assert_eq!(
+            error(warp::test::request().path("/proposals?id=SIP-029").reply(&routes).await),
+            (StatusCode::NOT_FOUND, "Unknown proposal SIP-029".into())
+        );
+    }
+}
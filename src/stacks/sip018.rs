@@ -0,0 +1,185 @@
+use std::fmt;
+
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, PublicKey, Secp256k1,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::reject::Reject;
+
+use crate::proofs::stacks_voting::{Domain, MessageInputs, SignatureData};
+
+use super::clarity::{ClarityError, ClarityValue};
+
+// SIP-018 structured data signing, see
+// https://github.com/stacksgov/sips/blob/main/sips/sip-018/sip-018-signed-structured-data.md
+//
+// The signed hash is sha256(SIP018_PREFIX || sha256(domain) || sha256(message)) where both
+// the domain and the message are consensus-serialized Clarity values.
+
+const SIP018_PREFIX: &[u8] = b"SIP018";
+
+// Domain used when the client doesn't send one, for the network's chain id.
+pub fn default_domain(chain_id: u32) -> Domain {
+    Domain {
+        name: "stxeco-zk-voting".to_string(),
+        version: "1.0.0".to_string(),
+        chain_id,
+    }
+}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    // A hex encoded field could not be decoded, or has the wrong length.
+    InvalidEncoding(&'static str),
+    // The message inputs or domain can't be encoded as Clarity values.
+    Encoding(ClarityError),
+    // The client supplied hash doesn't match the one recomputed from the message inputs.
+    HashMismatch { expected: String, actual: String },
+    // The signature is malformed or no key can be recovered from it.
+    InvalidSignature,
+    // The signature is valid, but for another key than the one in the request.
+    PublicKeyMismatch,
+    // The ballot was signed for another network.
+    ChainIdMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidEncoding(field) => write!(f, "Invalid encoding for {}", field),
+            SignatureError::Encoding(e) => write!(f, "{}", e),
+            SignatureError::HashMismatch { expected, actual } => {
+                write!(f, "Message hash mismatch: expected {}, got {}", expected, actual)
+            }
+            SignatureError::InvalidSignature => write!(f, "Invalid signature"),
+            SignatureError::PublicKeyMismatch => write!(f, "Signature was not made by the given public key"),
+            SignatureError::ChainIdMismatch { expected, actual } => {
+                write!(f, "Ballot was signed for chain id {}, expected {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl Reject for SignatureError {}
+
+impl From<ClarityError> for SignatureError {
+    fn from(err: ClarityError) -> Self {
+        SignatureError::Encoding(err)
+    }
+}
+
+// The canonical Clarity encoding of the ballot a voter signs:
+//
+//   { block-proof-height: uint, proposal: (string-ascii), vote: (string-ascii),
+//     voting-end-height: uint }
+//
+// The free-form `message` and the claimed `balance_at_height` are not part of the signed
+// payload; the balance is established by the proof itself.
+pub fn encode_message_inputs(inputs: &MessageInputs) -> Result<ClarityValue, ClarityError> {
+    ClarityValue::tuple(vec![
+        ("vote", ClarityValue::string_ascii("message_inputs.vote", &inputs.vote)?),
+        ("proposal", ClarityValue::string_ascii("message_inputs.proposal", &inputs.proposal)?),
+        ("block-proof-height", ClarityValue::UInt(inputs.block_proof_height.into())),
+        ("voting-end-height", ClarityValue::UInt(inputs.voting_end_height.into())),
+    ])
+}
+
+// The SIP-018 domain tuple: { chain-id: uint, name: (string-ascii), version: (string-ascii) }
+pub fn encode_domain(domain: &Domain) -> Result<ClarityValue, ClarityError> {
+    ClarityValue::tuple(vec![
+        ("name", ClarityValue::string_ascii("domain.name", &domain.name)?),
+        ("version", ClarityValue::string_ascii("domain.version", &domain.version)?),
+        ("chain-id", ClarityValue::UInt(domain.chain_id.into())),
+    ])
+}
+
+pub fn domain_hash(domain: &Domain) -> Result<[u8; 32], SignatureError> {
+    Ok(Sha256::digest(encode_domain(domain)?.serialize()).into())
+}
+
+pub fn message_hash(inputs: &MessageInputs) -> Result<[u8; 32], SignatureError> {
+    Ok(Sha256::digest(encode_message_inputs(inputs)?.serialize()).into())
+}
+
+// The hash a wallet signs for the given domain and message inputs.
+pub fn structured_data_hash(domain: &Domain, inputs: &MessageInputs) -> Result<[u8; 32], SignatureError> {
+    let mut hasher = Sha256::new();
+    hasher.update(SIP018_PREFIX);
+    hasher.update(domain_hash(domain)?);
+    hasher.update(message_hash(inputs)?);
+    Ok(hasher.finalize().into())
+}
+
+// Everything a front-end needs to check its own encoding against the server's, hex encoded.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncodedMessage {
+    pub domain: String,
+    pub message: String,
+    pub domain_hash: String,
+    pub message_hash: String,
+    pub hash: String,
+}
+
+pub fn encode_message(domain: &Domain, inputs: &MessageInputs) -> Result<EncodedMessage, SignatureError> {
+    Ok(EncodedMessage {
+        domain: hex::encode(encode_domain(domain)?.serialize()),
+        message: hex::encode(encode_message_inputs(inputs)?.serialize()),
+        domain_hash: hex::encode(domain_hash(domain)?),
+        message_hash: hex::encode(message_hash(inputs)?),
+        hash: hex::encode(structured_data_hash(domain, inputs)?),
+    })
+}
+
+// Recomputes the SIP-018 hash from the message inputs and checks that the signature over it
+// was made by the public key in the request. The client doesn't have to send the hash, but if
+// it does it must match the one computed here. Ballots signed for another chain are refused,
+// so a testnet signature can't be replayed on mainnet and vice versa.
+pub fn verify_signature_data(signature_data: &SignatureData, chain_id: u32) -> Result<(), SignatureError> {
+    let domain = signature_data.domain.clone().unwrap_or_else(|| default_domain(chain_id));
+    if domain.chain_id != chain_id {
+        return Err(SignatureError::ChainIdMismatch { expected: chain_id, actual: domain.chain_id });
+    }
+    let expected = structured_data_hash(&domain, &signature_data.message_inputs)?;
+
+    if !signature_data.hash.is_empty() {
+        let actual = hex::decode(signature_data.hash.trim_start_matches("0x"))
+            .map_err(|_| SignatureError::InvalidEncoding("hash"))?;
+        if actual != expected {
+            return Err(SignatureError::HashMismatch {
+                expected: hex::encode(expected),
+                actual: hex::encode(actual),
+            });
+        }
+    }
+
+    // Stacks wallets return 65 byte signatures in RSV order.
+    let signature = hex::decode(signature_data.signature.trim_start_matches("0x"))
+        .map_err(|_| SignatureError::InvalidEncoding("signature"))?;
+    if signature.len() != 65 {
+        return Err(SignatureError::InvalidEncoding("signature"));
+    }
+    let recovery_byte = match signature[64] {
+        v @ 27..=30 => v - 27,
+        v => v,
+    };
+    let recovery_id = RecoveryId::from_i32(recovery_byte.into())
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)
+        .map_err(|_| SignatureError::InvalidSignature)?;
+
+    let message = Message::from_digest(expected);
+    let recovered = Secp256k1::verification_only()
+        .recover_ecdsa(&message, &signature)
+        .map_err(|_| SignatureError::InvalidSignature)?;
+
+    let public_key = hex::decode(signature_data.public_key.trim_start_matches("0x"))
+        .map_err(|_| SignatureError::InvalidEncoding("public_key"))?;
+    let public_key = PublicKey::from_slice(&public_key)
+        .map_err(|_| SignatureError::InvalidEncoding("public_key"))?;
+    if recovered != public_key {
+        return Err(SignatureError::PublicKeyMismatch);
+    }
+    Ok(())
+}
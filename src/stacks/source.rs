@@ -0,0 +1,721 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use warp::reject::Reject;
+
+use super::{
+    network::NetworkConfig,
+    utils::{FtBalanceEvent, MinerReward, NftOwnershipEvent, StxBalanceEvent, Transaction},
+};
+
+// Transaction sources
+//
+// The proof pipeline reads account histories through `TransactionSource`, so it can run
+// against the Hiro API, a directory of JSON fixtures or an in-memory map. The last two let
+// tests and air-gapped provers generate proofs without network access.
+
+// Environment variable pointing at a fixture directory; when set it replaces the Hiro API.
+pub const TRANSACTION_FIXTURES_ENV: &str = "TRANSACTION_FIXTURES";
+
+const ADDRESSES_PATH: &str = "extended/v2/addresses";
+const EVENTS_PATH: &str = "extended/v1/tx/events";
+const BALANCES_PATH: &str = "extended/v1/address";
+const BLOCKS_PATH: &str = "extended/v2/blocks";
+
+// Most balance requests spent looking for the miner rewards of one address. Each reward block
+// takes about log2(height) of them, so only addresses that mined a handful of blocks can be
+// proven against the Hiro API.
+const MAX_MINER_REWARD_REQUESTS: usize = 256;
+
+#[derive(Debug)]
+pub enum SourceError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    // Answering would take more requests than the source allows.
+    TooManyRequests(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Http(e) => write!(f, "{}", e),
+            SourceError::Io(e) => write!(f, "{}", e),
+            SourceError::Parse(e) => write!(f, "{}", e),
+            SourceError::TooManyRequests(what) => write!(f, "Too many requests: {}", what),
+        }
+    }
+}
+
+impl Reject for SourceError {}
+
+impl From<reqwest::Error> for SourceError {
+    fn from(err: reqwest::Error) -> Self {
+        SourceError::Http(err)
+    }
+}
+
+impl From<std::io::Error> for SourceError {
+    fn from(err: std::io::Error) -> Self {
+        SourceError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SourceError {
+    fn from(err: serde_json::Error) -> Self {
+        SourceError::Parse(err)
+    }
+}
+
+// The balance a node reports for an address as of a block, used to reconcile the balance we
+// compute from the address's history. Amounts are in micro-STX.
+#[serde_as]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReportedBalance {
+    // includes the locked STX
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub locked: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub total_sent: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub total_received: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub total_fees_sent: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub total_miner_rewards_received: u128,
+}
+
+// A Stacks block, as far as proofs are concerned: proofs are anchored to the index block hash,
+// which commits to the whole chain state up to the block, and stacking locks expire at a
+// burnchain height.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub height: u64,
+    // hex encoded, 0x prefixed
+    pub index_block_hash: String,
+    pub burn_block_height: u64,
+    #[serde(default = "default_canonical")]
+    pub canonical: bool,
+}
+
+fn default_canonical() -> bool {
+    true
+}
+
+impl BlockInfo {
+    pub fn index_block_hash_bytes(&self) -> Option<[u8; 32]> {
+        hex::decode(self.index_block_hash.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+    }
+}
+
+pub trait TransactionSource: Send + Sync {
+    // Returns every transaction the address took part in.
+    fn fetch_transactions<'a>(&'a self, address: &'a str) -> BoxFuture<'a, Result<Vec<Transaction>, SourceError>>;
+
+    // Returns the coinbase rewards credited to the address at or below `height`, for sources
+    // that know about them.
+    fn fetch_miner_rewards<'a>(&'a self, _address: &'a str, _height: u64) -> BoxFuture<'a, Result<Vec<MinerReward>, SourceError>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    // Returns the balance of the address as of `height`, or `None` if the source can't tell.
+    fn fetch_balance<'a>(&'a self, _address: &'a str, _height: u64) -> BoxFuture<'a, Result<Option<ReportedBalance>, SourceError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    // Returns the canonical Stacks block at `height`, if the source knows it.
+    fn fetch_block(&self, _height: u64) -> BoxFuture<'_, Result<Option<BlockInfo>, SourceError>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    // Returns the height of the canonical chain tip, if the source knows it.
+    fn fetch_tip_height(&self) -> BoxFuture<'_, Result<Option<u64>, SourceError>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+// Picks the configured network's API, unless a fixture directory is configured.
+pub fn source_from_env(network: &NetworkConfig) -> Box<dyn TransactionSource> {
+    match std::env::var(TRANSACTION_FIXTURES_ENV) {
+        Ok(dir) => {
+            println!("Reading transactions from fixtures in {}", dir);
+            Box::new(FixtureSource::new(dir))
+        }
+        Err(_) => Box::new(HiroSource::new(&network.api_url)),
+    }
+}
+
+// Hiro API
+// ===========================================================================================
+
+#[derive(Deserialize, Serialize, Debug)]
+struct ApiResponse {
+    results: Vec<Transaction>,
+    total: u64,
+    limit: u64,
+    offset: u64,
+}
+
+// A page of `/extended/v2/blocks`, newest first.
+#[derive(Deserialize, Debug)]
+struct HiroBlockPage {
+    results: Vec<BlockInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EventsResponse {
+    events: Vec<HiroTxEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HiroTxEvent {
+    tx_id: String,
+    #[serde(flatten)]
+    event: HiroEvent,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "event_type")]
+enum HiroEvent {
+    #[serde(rename = "stx_asset")]
+    StxAsset { asset: HiroAsset },
+    #[serde(rename = "stx_lock")]
+    StxLock { stx_lock_event: HiroLock },
+    #[serde(rename = "fungible_token_asset")]
+    FungibleTokenAsset { asset: HiroAsset },
+    #[serde(rename = "non_fungible_token_asset")]
+    NonFungibleTokenAsset { asset: HiroNftAsset },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct HiroAsset {
+    asset_event_type: String,
+    // set for token events
+    #[serde(default)]
+    asset_id: String,
+    #[serde(default)]
+    sender: String,
+    #[serde(default)]
+    recipient: String,
+    amount: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HiroNftAsset {
+    asset_event_type: String,
+    asset_id: String,
+    #[serde(default)]
+    sender: String,
+    #[serde(default)]
+    recipient: String,
+    value: HiroValue,
+}
+
+#[derive(Deserialize, Debug)]
+struct HiroValue {
+    repr: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HiroLock {
+    locked_amount: String,
+    unlock_height: u64,
+    locked_address: String,
+}
+
+// The events of a transaction that move balances, split by asset.
+#[derive(Default)]
+struct BalanceEvents {
+    stx: Vec<StxBalanceEvent>,
+    ft: Vec<FtBalanceEvent>,
+    nft: Vec<NftOwnershipEvent>,
+}
+
+impl HiroEvent {
+    fn collect_into(self, events: &mut BalanceEvents) {
+        match self {
+            HiroEvent::FungibleTokenAsset { asset } => events.ft.extend(asset.into_ft_event()),
+            HiroEvent::NonFungibleTokenAsset { asset } => events.nft.extend(asset.into_nft_event()),
+            other => events.stx.extend(other.into_balance_event()),
+        }
+    }
+
+    fn into_balance_event(self) -> Option<StxBalanceEvent> {
+        match self {
+            HiroEvent::StxAsset { asset } => match asset.asset_event_type.as_str() {
+                "transfer" => Some(StxBalanceEvent::Transfer { sender: asset.sender, recipient: asset.recipient, amount: asset.amount }),
+                "mint" => Some(StxBalanceEvent::Mint { recipient: asset.recipient, amount: asset.amount }),
+                "burn" => Some(StxBalanceEvent::Burn { sender: asset.sender, amount: asset.amount }),
+                _ => None,
+            },
+            HiroEvent::StxLock { stx_lock_event: lock } => Some(StxBalanceEvent::Lock {
+                locked_address: lock.locked_address,
+                locked_amount: lock.locked_amount,
+                unlock_height: lock.unlock_height,
+            }),
+            HiroEvent::FungibleTokenAsset { .. } | HiroEvent::NonFungibleTokenAsset { .. } | HiroEvent::Other => None,
+        }
+    }
+}
+
+impl HiroAsset {
+    fn into_ft_event(self) -> Option<FtBalanceEvent> {
+        let asset_identifier = self.asset_id;
+        match self.asset_event_type.as_str() {
+            "transfer" => Some(FtBalanceEvent::Transfer { asset_identifier, sender: self.sender, recipient: self.recipient, amount: self.amount }),
+            "mint" => Some(FtBalanceEvent::Mint { asset_identifier, recipient: self.recipient, amount: self.amount }),
+            "burn" => Some(FtBalanceEvent::Burn { asset_identifier, sender: self.sender, amount: self.amount }),
+            _ => None,
+        }
+    }
+}
+
+impl HiroNftAsset {
+    fn into_nft_event(self) -> Option<NftOwnershipEvent> {
+        let (asset_identifier, value) = (self.asset_id, self.value.repr);
+        match self.asset_event_type.as_str() {
+            "transfer" => Some(NftOwnershipEvent::Transfer { asset_identifier, sender: self.sender, recipient: self.recipient, value }),
+            "mint" => Some(NftOwnershipEvent::Mint { asset_identifier, recipient: self.recipient, value }),
+            "burn" => Some(NftOwnershipEvent::Burn { asset_identifier, sender: self.sender, value }),
+            _ => None,
+        }
+    }
+}
+
+pub struct HiroSource {
+    api_url: String,
+    client: reqwest::Client,
+}
+
+impl HiroSource {
+    // `api_url` is the root of a Stacks API, e.g. https://api.hiro.so
+    pub fn new(api_url: &str) -> Self {
+        HiroSource {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl TransactionSource for HiroSource {
+    fn fetch_transactions<'a>(&'a self, address: &'a str) -> BoxFuture<'a, Result<Vec<Transaction>, SourceError>> {
+        Box::pin(async move {
+            let mut all_transactions: Vec<Transaction> = Vec::new();
+            let mut offset: u64 = 0;
+            let limit = 20;
+
+            loop {
+                // Construct the URL with the current offset and limit
+                let url = format!("{}/{}/{}/transactions?limit={}&offset={}", self.api_url, ADDRESSES_PATH, address, limit, offset);
+
+                // A page that can't be fetched fails the whole request: a proof over part of
+                // the history would attest to the wrong balance.
+                let response: ApiResponse = self.client.get(&url).send().await?
+                    .error_for_status()?
+                    .json().await?;
+
+                if response.results.is_empty() {
+                    break;
+                }
+
+                all_transactions.extend(response.results);
+
+                // Update offset for the next page
+                offset += limit;
+                if offset >= response.total {
+                    break;
+                }
+            }
+
+            let mut events = self.fetch_balance_events(address).await?;
+            for transaction in all_transactions.iter_mut() {
+                let events = events.remove(&transaction.tx.tx_id).unwrap_or_default();
+                transaction.stx_events = Some(events.stx);
+                transaction.ft_events = Some(events.ft);
+                transaction.nft_events = Some(events.nft);
+            }
+
+            Ok(all_transactions)
+        })
+    }
+
+    // The API doesn't list miner rewards, only their running total in the address's balance. A
+    // reward was credited at the block where that total grows, so the blocks are found by
+    // bisecting the heights up to `height` over the totals; an address that never mined takes
+    // a single request. Addresses that mined more blocks than MAX_MINER_REWARD_REQUESTS allows
+    // to find are refused.
+    fn fetch_miner_rewards<'a>(&'a self, address: &'a str, height: u64) -> BoxFuture<'a, Result<Vec<MinerReward>, SourceError>> {
+        Box::pin(async move {
+            let mut rewards = Vec::new();
+            // ranges of heights (low, high] whose totals differ, the lowest on top
+            let mut ranges = vec![(0, 0, height, self.miner_rewards_until(address, height).await?)];
+            let mut requests = 1;
+            while let Some((low, low_total, high, high_total)) = ranges.pop() {
+                if low_total == high_total {
+                    continue;
+                }
+                if high == low + 1 {
+                    rewards.push(MinerReward { block_height: high, amount: (high_total - low_total).to_string() });
+                    continue;
+                }
+                if requests == MAX_MINER_REWARD_REQUESTS {
+                    return Err(SourceError::TooManyRequests(format!(
+                        "the miner rewards of {} can't be found in {} balance requests",
+                        address, MAX_MINER_REWARD_REQUESTS
+                    )));
+                }
+                requests += 1;
+                let middle = low + (high - low) / 2;
+                let middle_total = self.miner_rewards_until(address, middle).await?;
+                ranges.push((middle, middle_total, high, high_total));
+                ranges.push((low, low_total, middle, middle_total));
+            }
+            Ok(rewards)
+        })
+    }
+
+    fn fetch_balance<'a>(&'a self, address: &'a str, height: u64) -> BoxFuture<'a, Result<Option<ReportedBalance>, SourceError>> {
+        Box::pin(async move {
+            let url = format!("{}/{}/{}/stx?until_block={}", self.api_url, BALANCES_PATH, address, height);
+            let balance = self.client.get(&url).send().await?
+                .error_for_status()?
+                .json().await?;
+            Ok(Some(balance))
+        })
+    }
+
+    fn fetch_block(&self, height: u64) -> BoxFuture<'_, Result<Option<BlockInfo>, SourceError>> {
+        Box::pin(async move {
+            let url = format!("{}/{}/{}", self.api_url, BLOCKS_PATH, height);
+            let response = self.client.get(&url).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok(Some(response.error_for_status()?.json().await?))
+        })
+    }
+
+    fn fetch_tip_height(&self) -> BoxFuture<'_, Result<Option<u64>, SourceError>> {
+        Box::pin(async move {
+            let url = format!("{}/{}?limit=1", self.api_url, BLOCKS_PATH);
+            let page: HiroBlockPage = self.client.get(&url).send().await?
+                .error_for_status()?
+                .json().await?;
+            Ok(page.results.first().map(|block| block.height))
+        })
+    }
+}
+
+impl HiroSource {
+    // The miner rewards credited to the address at or below `height`.
+    async fn miner_rewards_until(&self, address: &str, height: u64) -> Result<u128, SourceError> {
+        Ok(self.fetch_balance(address, height).await?
+            .map_or(0, |balance| balance.total_miner_rewards_received))
+    }
+
+    // The STX, STX lock, token and NFT events that involve the address, by transaction. They
+    // are paged through once for the whole history rather than fetched per transaction; only
+    // successful transactions emit events.
+    async fn fetch_balance_events(&self, address: &str) -> Result<HashMap<String, BalanceEvents>, SourceError> {
+        let mut events: HashMap<String, BalanceEvents> = HashMap::new();
+        let mut offset: u64 = 0;
+        let limit = 100;
+
+        loop {
+            let url = format!(
+                "{}/{}?address={}&type=stx_asset&type=stx_lock&type=fungible_token_asset&type=non_fungible_token_asset&limit={}&offset={}",
+                self.api_url, EVENTS_PATH, address, limit, offset
+            );
+            let response: EventsResponse = self.client.get(&url).send().await?
+                .error_for_status()?
+                .json().await?;
+
+            let page_size = response.events.len() as u64;
+            for HiroTxEvent { tx_id, event } in response.events {
+                event.collect_into(events.entry(tx_id).or_default());
+            }
+
+            offset += limit;
+            if page_size < limit {
+                break;
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+// JSON fixtures
+// ===========================================================================================
+
+// Reads `<dir>/<address>.json`, holding the transactions as returned by
+// `/stacks/transactions/<address>`, and `<dir>/<address>.rewards.json` holding the miner
+// rewards of the address, if any. Unknown addresses have no transactions.
+// `<dir>/blocks.json` lists the blocks proofs can be anchored to, as returned by
+// `/extended/v2/blocks/<height>`; the highest canonical one is the chain tip.
+pub struct FixtureSource {
+    dir: PathBuf,
+}
+
+impl FixtureSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FixtureSource { dir: dir.into() }
+    }
+}
+
+impl FixtureSource {
+    async fn read<T: serde::de::DeserializeOwned + Default>(&self, file_name: String) -> Result<T, SourceError> {
+        match tokio::fs::read(self.dir.join(file_name)).await {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl TransactionSource for FixtureSource {
+    fn fetch_transactions<'a>(&'a self, address: &'a str) -> BoxFuture<'a, Result<Vec<Transaction>, SourceError>> {
+        Box::pin(self.read(format!("{}.json", address)))
+    }
+
+    fn fetch_miner_rewards<'a>(&'a self, address: &'a str, height: u64) -> BoxFuture<'a, Result<Vec<MinerReward>, SourceError>> {
+        Box::pin(async move {
+            let miner_rewards: Vec<MinerReward> = self.read(format!("{}.rewards.json", address)).await?;
+            Ok(miner_rewards.into_iter().filter(|reward| reward.block_height <= height).collect())
+        })
+    }
+
+    fn fetch_block(&self, height: u64) -> BoxFuture<'_, Result<Option<BlockInfo>, SourceError>> {
+        Box::pin(async move {
+            let blocks: Vec<BlockInfo> = self.read("blocks.json".to_string()).await?;
+            Ok(blocks.into_iter().find(|block| block.height == height && block.canonical))
+        })
+    }
+
+    fn fetch_tip_height(&self) -> BoxFuture<'_, Result<Option<u64>, SourceError>> {
+        Box::pin(async move {
+            let blocks: Vec<BlockInfo> = self.read("blocks.json".to_string()).await?;
+            Ok(blocks.into_iter().filter(|block| block.canonical).map(|block| block.height).max())
+        })
+    }
+}
+
+// In memory
+// ===========================================================================================
+
+#[derive(Default)]
+pub struct MemorySource {
+    transactions: RwLock<HashMap<String, Vec<Transaction>>>,
+    miner_rewards: RwLock<HashMap<String, Vec<MinerReward>>>,
+    balances: RwLock<HashMap<(String, u64), ReportedBalance>>,
+    blocks: RwLock<HashMap<u64, BlockInfo>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        MemorySource::default()
+    }
+
+    // Replaces the history of `address`.
+    pub fn insert(&self, address: &str, transactions: Vec<Transaction>) {
+        self.transactions.write().unwrap().insert(address.to_string(), transactions);
+    }
+
+    // Replaces the miner rewards of `address`.
+    pub fn insert_miner_rewards(&self, address: &str, miner_rewards: Vec<MinerReward>) {
+        self.miner_rewards.write().unwrap().insert(address.to_string(), miner_rewards);
+    }
+
+    // Sets the balance reported for `address` at `height`.
+    pub fn insert_balance(&self, address: &str, height: u64, balance: ReportedBalance) {
+        self.balances.write().unwrap().insert((address.to_string(), height), balance);
+    }
+
+    // Sets the canonical block at the block's height. The highest block is the chain tip.
+    pub fn insert_block(&self, block: BlockInfo) {
+        self.blocks.write().unwrap().insert(block.height, block);
+    }
+}
+
+impl TransactionSource for MemorySource {
+    fn fetch_transactions<'a>(&'a self, address: &'a str) -> BoxFuture<'a, Result<Vec<Transaction>, SourceError>> {
+        let transactions = self.transactions.read().unwrap().get(address).cloned().unwrap_or_default();
+        Box::pin(async move { Ok(transactions) })
+    }
+
+    fn fetch_miner_rewards<'a>(&'a self, address: &'a str, height: u64) -> BoxFuture<'a, Result<Vec<MinerReward>, SourceError>> {
+        let miner_rewards: Vec<MinerReward> = self.miner_rewards.read().unwrap().get(address).into_iter().flatten()
+            .filter(|reward| reward.block_height <= height)
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(miner_rewards) })
+    }
+
+    fn fetch_balance<'a>(&'a self, address: &'a str, height: u64) -> BoxFuture<'a, Result<Option<ReportedBalance>, SourceError>> {
+        let balance = self.balances.read().unwrap().get(&(address.to_string(), height)).cloned();
+        Box::pin(async move { Ok(balance) })
+    }
+
+    fn fetch_block(&self, height: u64) -> BoxFuture<'_, Result<Option<BlockInfo>, SourceError>> {
+        let block = self.blocks.read().unwrap().get(&height).cloned();
+        Box::pin(async move { Ok(block) })
+    }
+
+    fn fetch_tip_height(&self) -> BoxFuture<'_, Result<Option<u64>, SourceError>> {
+        let tip = self.blocks.read().unwrap().keys().max().copied();
+        Box::pin(async move { Ok(tip) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc},
+    };
+
+    use warp::Filter;
+
+    use super::*;
+    use crate::tests::transaction;
+
+    const MINER: &str = "SP3FBR2AGK5H9QBDH3EEN6DF8EK8JY7RX8QJ5SVTE";
+
+    // Serves the balances of `/extended/v1/address`, with the given miner rewards credited;
+    // counts the balance requests and keeps the highest height asked for.
+    async fn hiro_api(rewards: &[(u64, u128)], requests: Arc<AtomicUsize>, highest: Arc<AtomicU64>) -> String {
+        let rewards: BTreeMap<u64, u128> = rewards.iter().copied().collect();
+        let balances = warp::path!("extended" / "v1" / "address" / String / "stx")
+            .and(warp::query::<HashMap<String, u64>>())
+            .map(move |_address: String, query: HashMap<String, u64>| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                highest.fetch_max(query["until_block"], Ordering::SeqCst);
+                let received: u128 = rewards.range(..=query["until_block"]).map(|(_, amount)| amount).sum();
+                warp::reply::json(&ReportedBalance {
+                    balance: received,
+                    locked: 0,
+                    total_sent: 0,
+                    total_received: 0,
+                    total_fees_sent: 0,
+                    total_miner_rewards_received: received,
+                })
+            });
+        let (address, server) = warp::serve(balances).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}", address)
+    }
+
+    async fn miner_rewards(source: &HiroSource, height: u64) -> Vec<(u64, String)> {
+        source.fetch_miner_rewards(MINER, height).await.unwrap()
+            .into_iter()
+            .map(|reward| (reward.block_height, reward.amount))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn hiro_miner_rewards_are_found_at_their_blocks() {
+        let rewards = [(1, 500), (17, 1_000), (18, 2_000), (640, 3_000), (1_000, 4_000)];
+        let source = HiroSource::new(&hiro_api(&rewards, Default::default(), Default::default()).await);
+
+        assert_eq!(miner_rewards(&source, 1_000).await, rewards.map(|(height, amount)| (height, amount.to_string())));
+    }
+
+    #[tokio::test]
+    async fn hiro_miner_rewards_are_only_looked_for_up_to_the_height() {
+        let rewards = [(17, 1_000), (640, 3_000)];
+        let highest = Arc::new(AtomicU64::new(0));
+        let source = HiroSource::new(&hiro_api(&rewards, Default::default(), highest.clone()).await);
+
+        assert_eq!(miner_rewards(&source, 100).await, vec![(17, "1000".to_string())]);
+        assert_eq!(highest.load(Ordering::SeqCst), 100);
+    }
+
+    #[tokio::test]
+    async fn hiro_addresses_that_never_mined_take_one_request() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let source = HiroSource::new(&hiro_api(&[], requests.clone(), Default::default()).await);
+
+        assert!(miner_rewards(&source, 1_000).await.is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn hiro_miner_rewards_give_up_after_too_many_requests() {
+        let rewards: Vec<(u64, u128)> = (1..=1_000).map(|height| (height, 1_000)).collect();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let source = HiroSource::new(&hiro_api(&rewards, requests.clone(), Default::default()).await);
+
+        let error = source.fetch_miner_rewards(MINER, 1_000).await.unwrap_err();
+        assert!(matches!(error, SourceError::TooManyRequests(_)), "{}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), MAX_MINER_REWARD_REQUESTS);
+    }
+
+    // Serves the transactions of `/extended/v2/addresses` and pages through the events of
+    // `/extended/v1/tx/events`; counts the event requests.
+    async fn hiro_history_api(transactions: Vec<Transaction>, events: Vec<serde_json::Value>, requests: Arc<AtomicUsize>) -> String {
+        let total = transactions.len() as u64;
+        let transactions = Arc::new(transactions);
+        let history = warp::path!("extended" / "v2" / "addresses" / String / "transactions")
+            .and(warp::query::<HashMap<String, u64>>())
+            .map(move |_address: String, query: HashMap<String, u64>| {
+                let page = transactions.iter().skip(query["offset"] as usize).take(query["limit"] as usize);
+                warp::reply::json(&serde_json::json!({ "results": page.collect::<Vec<_>>(), "total": total, "limit": query["limit"], "offset": query["offset"] }))
+            });
+        let events = warp::path!("extended" / "v1" / "tx" / "events")
+            .and(warp::query::raw())
+            .map(move |query: String| {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let parameter = |name: &str| -> usize {
+                    query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('=')?.parse().ok()).unwrap()
+                };
+                assert!(!query.contains("tx_id"), "{}", query);
+                let page: Vec<_> = events.iter().skip(parameter("offset")).take(parameter("limit")).collect();
+                warp::reply::json(&serde_json::json!({ "events": page }))
+            });
+        let (address, server) = warp::serve(history.or(events)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}", address)
+    }
+
+    fn received(tx_id: &str, amount: u128) -> serde_json::Value {
+        serde_json::json!({
+            "tx_id": tx_id,
+            "event_type": "stx_asset",
+            "asset": { "asset_event_type": "transfer", "sender": "SP000", "recipient": MINER, "amount": amount.to_string() },
+        })
+    }
+
+    #[tokio::test]
+    async fn hiro_events_are_paged_for_the_address_rather_than_fetched_per_transaction() {
+        let transactions: Vec<Transaction> = (1..=30).map(|i| transaction(&format!("0x{:02x}", i), 0, 0, i)).collect();
+        // four events for each transaction, more than a page
+        let events: Vec<_> = (1..=30).flat_map(|i| (0..4).map(move |_| received(&format!("0x{:02x}", i), i as u128))).collect();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let source = HiroSource::new(&hiro_history_api(transactions, events, requests.clone()).await);
+
+        let fetched = source.fetch_transactions(MINER).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(fetched.len(), 30);
+        for (i, transaction) in fetched.iter().enumerate() {
+            let stx_events = transaction.stx_events.as_ref().unwrap();
+            assert_eq!(stx_events.len(), 4, "{}", transaction.tx.tx_id);
+            assert!(stx_events.iter().all(|event| matches!(
+                event,
+                StxBalanceEvent::Transfer { amount, .. } if *amount == (i + 1).to_string()
+            )));
+        }
+    }
+}
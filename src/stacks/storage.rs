@@ -0,0 +1,597 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+    sync::Mutex,
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use warp::reject::Reject;
+
+use super::{batches::BallotRecord, nullifiers::Nullifier, proposals::Proposal, tally::Tally};
+
+// Storage
+//
+// Proposals, spent nullifiers, accepted ballots with their raw proofs, and tallies are kept
+// behind `Storage`, so a restart doesn't lose the votes cast so far, nor let voters cast them
+// again. The key nullifiers are derived with is kept alongside them, since spent nullifiers
+// only match new ones derived with the same key. The server stores them in an embedded SQLite database; tests and throwaway servers
+// keep them in memory.
+
+// Environment variable with the path of the SQLite database. Without it nothing outlives the
+// process.
+pub const STORAGE_PATH_ENV: &str = "STORAGE_PATH";
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+    Serialization(serde_json::Error),
+    // A stored value doesn't decode, e.g. a hash of the wrong length.
+    Corrupt(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Sqlite(e) => write!(f, "Storage error: {}", e),
+            StorageError::Serialization(e) => write!(f, "Storage serialization error: {}", e),
+            StorageError::Corrupt(what) => write!(f, "Corrupt storage: {}", what),
+        }
+    }
+}
+
+impl Reject for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        StorageError::Serialization(err)
+    }
+}
+
+// A ballot whose proof was generated, as it is stored and counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredBallot {
+    pub proposal: String,
+    pub vote: String,
+    // the voting power the proof discloses, or its lower bound
+    pub power: u128,
+    // the ballot's record in the proposal's batch
+    pub record: BallotRecord,
+    // the commitment to the ledger the proof was computed over; it is blinded with a salt
+    // nobody else has, so it can't be recomputed when the proof is validated
+    pub history: [u8; 32],
+    pub proof: Vec<u8>,
+}
+
+// Reads the vote and voting power of every ballot cast on the proposal, the one being cast
+// included.
+pub type Votes<'a> = dyn Fn() -> Result<Vec<(String, u128)>, StorageError> + 'a;
+
+// Counts the ballot being cast into the tally saved so far, if there is one.
+pub type Count<'a> = dyn FnMut(Option<Tally>, &Votes) -> Result<Tally, StorageError> + 'a;
+
+pub trait Storage: Send + Sync {
+    // The key nullifiers are derived with. The first call stores `generated` and returns it;
+    // every later one returns the stored key, including after a restart.
+    fn nullifier_key(&self, generated: [u8; 32]) -> Result<[u8; 32], StorageError>;
+
+    // Stores a new proposal; returns false if one with the same id exists.
+    fn insert_proposal(&self, proposal: &Proposal) -> Result<bool, StorageError>;
+
+    fn proposal(&self, id: &str) -> Result<Option<Proposal>, StorageError>;
+
+    // Every proposal, ordered by id.
+    fn proposals(&self) -> Result<Vec<Proposal>, StorageError>;
+
+    fn is_spent(&self, proposal: &str, nullifier: &Nullifier) -> Result<bool, StorageError>;
+
+    // Spends the ballot's nullifier, stores the ballot and saves the tally `count` returns as
+    // one change: when a step fails none of them is kept, so a ballot can't be spent without
+    // being stored, nor stored without being counted. Returns false, changing nothing, if the
+    // nullifier was already spent.
+    fn cast(&self, ballot: &StoredBallot, count: Option<&mut Count>) -> Result<bool, StorageError>;
+
+    // The records of the proposal's ballots, in the order they were cast.
+    fn ballots(&self, proposal: &str) -> Result<Vec<BallotRecord>, StorageError>;
+
+    // The vote and voting power of the proposal's ballots, in the order they were cast.
+    fn votes(&self, proposal: &str) -> Result<Vec<(String, u128)>, StorageError>;
+
+    // The history commitment of the ballot cast under `nullifier`.
+    fn history(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<[u8; 32]>, StorageError>;
+
+    // The vote and raw proof of the ballot cast under `nullifier`.
+    fn proof(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<(String, Vec<u8>)>, StorageError>;
+
+    fn tally(&self, proposal: &str) -> Result<Option<Tally>, StorageError>;
+}
+
+// Uses the SQLite database configured in the environment, or memory.
+pub fn storage_from_env() -> Result<Box<dyn Storage>, StorageError> {
+    match std::env::var(STORAGE_PATH_ENV) {
+        Ok(path) => {
+            println!("Storing votes in {}", path);
+            Ok(Box::new(SqliteStorage::open(path)?))
+        }
+        Err(_) => {
+            eprintln!("{} is not set, votes are only kept in memory", STORAGE_PATH_ENV);
+            Ok(Box::new(MemoryStorage::new()))
+        }
+    }
+}
+
+// SQLite
+// ===========================================================================================
+
+// Schema changes, applied in order. The database's `user_version` is the number of
+// migrations it has seen, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE proposals (
+        id TEXT PRIMARY KEY,
+        proposal TEXT NOT NULL
+    );
+    CREATE TABLE nullifiers (
+        proposal TEXT NOT NULL,
+        nullifier BLOB NOT NULL,
+        PRIMARY KEY (proposal, nullifier)
+    );
+    CREATE TABLE ballots (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        proposal TEXT NOT NULL,
+        nullifier BLOB NOT NULL,
+        proof_hash BLOB NOT NULL,
+        commitment BLOB NOT NULL,
+        vote TEXT NOT NULL,
+        power TEXT NOT NULL,
+        history BLOB NOT NULL,
+        proof BLOB NOT NULL,
+        UNIQUE (proposal, nullifier)
+    );
+    CREATE TABLE tallies (
+        proposal TEXT PRIMARY KEY,
+        tally TEXT NOT NULL
+    );
+    CREATE TABLE nullifier_key (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        key BLOB NOT NULL
+    );",
+];
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        SqliteStorage::migrated(Connection::open(path)?)
+    }
+
+    // A database that only lives as long as the storage.
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        SqliteStorage::migrated(Connection::open_in_memory()?)
+    }
+
+    fn migrated(mut connection: Connection) -> Result<Self, StorageError> {
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(StorageError::Corrupt(format!("schema version {} is newer than this server", version)));
+        }
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", index + 1)?;
+            transaction.commit()?;
+        }
+        Ok(SqliteStorage { connection: Mutex::new(connection) })
+    }
+}
+
+fn hash(bytes: Vec<u8>) -> Result<[u8; 32], StorageError> {
+    <[u8; 32]>::try_from(bytes).map_err(|bytes| StorageError::Corrupt(format!("{} byte hash", bytes.len())))
+}
+
+// Takes a connection rather than the storage, so they can also be read inside a transaction.
+fn votes(connection: &Connection, proposal: &str) -> Result<Vec<(String, u128)>, StorageError> {
+    let mut statement = connection.prepare("SELECT vote, power FROM ballots WHERE proposal = ?1 ORDER BY id")?;
+    let rows = statement.query_map([proposal], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    rows.map(|row| {
+        let (vote, power) = row?;
+        let power = power.parse().map_err(|_| StorageError::Corrupt(format!("voting power {}", power)))?;
+        Ok((vote, power))
+    })
+    .collect()
+}
+
+fn tally(connection: &Connection, proposal: &str) -> Result<Option<Tally>, StorageError> {
+    let tally: Option<String> = connection
+        .query_row("SELECT tally FROM tallies WHERE proposal = ?1", [proposal], |row| row.get(0))
+        .optional()?;
+    Ok(tally.map(|tally| serde_json::from_str(&tally)).transpose()?)
+}
+
+impl Storage for SqliteStorage {
+    fn nullifier_key(&self, generated: [u8; 32]) -> Result<[u8; 32], StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("INSERT OR IGNORE INTO nullifier_key (id, key) VALUES (0, ?1)", [generated])?;
+        hash(connection.query_row("SELECT key FROM nullifier_key WHERE id = 0", [], |row| row.get(0))?)
+    }
+
+    fn insert_proposal(&self, proposal: &Proposal) -> Result<bool, StorageError> {
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO proposals (id, proposal) VALUES (?1, ?2)",
+            params![proposal.id, serde_json::to_string(proposal)?],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn proposal(&self, id: &str) -> Result<Option<Proposal>, StorageError> {
+        let proposal: Option<String> = self.connection.lock().unwrap()
+            .query_row("SELECT proposal FROM proposals WHERE id = ?1", [id], |row| row.get(0))
+            .optional()?;
+        Ok(proposal.map(|proposal| serde_json::from_str(&proposal)).transpose()?)
+    }
+
+    fn proposals(&self) -> Result<Vec<Proposal>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT proposal FROM proposals ORDER BY id")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        rows.map(|proposal| Ok(serde_json::from_str(&proposal?)?)).collect()
+    }
+
+    fn is_spent(&self, proposal: &str, nullifier: &Nullifier) -> Result<bool, StorageError> {
+        let spent = self.connection.lock().unwrap()
+            .query_row(
+                "SELECT 1 FROM nullifiers WHERE proposal = ?1 AND nullifier = ?2",
+                params![proposal, nullifier],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(spent.is_some())
+    }
+
+    fn cast(&self, ballot: &StoredBallot, count: Option<&mut Count>) -> Result<bool, StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        // dropping the transaction before it commits rolls it back
+        let transaction = connection.transaction()?;
+        let spent = transaction.execute(
+            "INSERT OR IGNORE INTO nullifiers (proposal, nullifier) VALUES (?1, ?2)",
+            params![ballot.proposal, ballot.record.nullifier],
+        )?;
+        if spent == 0 {
+            return Ok(false);
+        }
+        transaction.execute(
+            "INSERT INTO ballots (proposal, nullifier, proof_hash, commitment, vote, power, history, proof)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                ballot.proposal,
+                ballot.record.nullifier,
+                ballot.record.proof_hash,
+                ballot.record.commitment,
+                ballot.vote,
+                ballot.power.to_string(),
+                ballot.history,
+                ballot.proof,
+            ],
+        )?;
+        if let Some(count) = count {
+            let saved = tally(&transaction, &ballot.proposal)?;
+            let counted = count(saved, &|| votes(&transaction, &ballot.proposal))?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO tallies (proposal, tally) VALUES (?1, ?2)",
+                params![counted.proposal, serde_json::to_string(&counted)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn ballots(&self, proposal: &str) -> Result<Vec<BallotRecord>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT proof_hash, nullifier, commitment FROM ballots WHERE proposal = ?1 ORDER BY id",
+        )?;
+        let rows = statement.query_map([proposal], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?))
+        })?;
+        rows.map(|row| {
+            let (proof_hash, nullifier, commitment) = row?;
+            Ok(BallotRecord {
+                proof_hash: hash(proof_hash)?,
+                nullifier: hash(nullifier)?,
+                commitment: hash(commitment)?,
+            })
+        })
+        .collect()
+    }
+
+    fn votes(&self, proposal: &str) -> Result<Vec<(String, u128)>, StorageError> {
+        votes(&self.connection.lock().unwrap(), proposal)
+    }
+
+    fn history(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<[u8; 32]>, StorageError> {
+        let history: Option<Vec<u8>> = self.connection.lock().unwrap()
+            .query_row(
+                "SELECT history FROM ballots WHERE proposal = ?1 AND nullifier = ?2",
+                params![proposal, nullifier],
+                |row| row.get(0),
+            )
+            .optional()?;
+        history.map(hash).transpose()
+    }
+
+    fn proof(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<(String, Vec<u8>)>, StorageError> {
+        Ok(self.connection.lock().unwrap()
+            .query_row(
+                "SELECT vote, proof FROM ballots WHERE proposal = ?1 AND nullifier = ?2",
+                params![proposal, nullifier],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    fn tally(&self, proposal: &str) -> Result<Option<Tally>, StorageError> {
+        tally(&self.connection.lock().unwrap(), proposal)
+    }
+}
+
+// In memory
+// ===========================================================================================
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    nullifier_key: Mutex<Option<[u8; 32]>>,
+    proposals: Mutex<HashMap<String, Proposal>>,
+    nullifiers: Mutex<HashMap<String, HashSet<Nullifier>>>,
+    ballots: Mutex<HashMap<String, Vec<StoredBallot>>>,
+    tallies: Mutex<HashMap<String, Tally>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn nullifier_key(&self, generated: [u8; 32]) -> Result<[u8; 32], StorageError> {
+        Ok(*self.nullifier_key.lock().unwrap().get_or_insert(generated))
+    }
+
+    fn insert_proposal(&self, proposal: &Proposal) -> Result<bool, StorageError> {
+        let mut proposals = self.proposals.lock().unwrap();
+        if proposals.contains_key(&proposal.id) {
+            return Ok(false);
+        }
+        proposals.insert(proposal.id.clone(), proposal.clone());
+        Ok(true)
+    }
+
+    fn proposal(&self, id: &str) -> Result<Option<Proposal>, StorageError> {
+        Ok(self.proposals.lock().unwrap().get(id).cloned())
+    }
+
+    fn proposals(&self) -> Result<Vec<Proposal>, StorageError> {
+        let mut proposals: Vec<Proposal> = self.proposals.lock().unwrap().values().cloned().collect();
+        proposals.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(proposals)
+    }
+
+    fn is_spent(&self, proposal: &str, nullifier: &Nullifier) -> Result<bool, StorageError> {
+        let nullifiers = self.nullifiers.lock().unwrap();
+        Ok(nullifiers.get(proposal).is_some_and(|set| set.contains(nullifier)))
+    }
+
+    fn cast(&self, ballot: &StoredBallot, count: Option<&mut Count>) -> Result<bool, StorageError> {
+        // all three are held until the ballot is cast, in the order of the fields
+        let mut nullifiers = self.nullifiers.lock().unwrap();
+        let mut ballots = self.ballots.lock().unwrap();
+        let mut tallies = self.tallies.lock().unwrap();
+        if nullifiers.get(&ballot.proposal).is_some_and(|set| set.contains(&ballot.record.nullifier)) {
+            return Ok(false);
+        }
+        let counted = match count {
+            Some(count) => {
+                let votes = || {
+                    let cast = ballots.get(&ballot.proposal).into_iter().flatten().chain([ballot]);
+                    Ok(cast.map(|ballot| (ballot.vote.clone(), ballot.power)).collect())
+                };
+                Some(count(tallies.get(&ballot.proposal).cloned(), &votes)?)
+            }
+            None => None,
+        };
+        nullifiers.entry(ballot.proposal.clone()).or_default().insert(ballot.record.nullifier);
+        ballots.entry(ballot.proposal.clone()).or_default().push(ballot.clone());
+        if let Some(tally) = counted {
+            tallies.insert(tally.proposal.clone(), tally);
+        }
+        Ok(true)
+    }
+
+    fn ballots(&self, proposal: &str) -> Result<Vec<BallotRecord>, StorageError> {
+        let ballots = self.ballots.lock().unwrap();
+        Ok(ballots.get(proposal).map(|ballots| ballots.iter().map(|ballot| ballot.record).collect()).unwrap_or_default())
+    }
+
+    fn votes(&self, proposal: &str) -> Result<Vec<(String, u128)>, StorageError> {
+        let ballots = self.ballots.lock().unwrap();
+        Ok(ballots.get(proposal).map(|ballots| ballots.iter().map(|ballot| (ballot.vote.clone(), ballot.power)).collect()).unwrap_or_default())
+    }
+
+    fn history(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<[u8; 32]>, StorageError> {
+        let ballots = self.ballots.lock().unwrap();
+        Ok(ballots.get(proposal)
+            .and_then(|ballots| ballots.iter().find(|ballot| ballot.record.nullifier == *nullifier))
+            .map(|ballot| ballot.history))
+    }
+
+    fn proof(&self, proposal: &str, nullifier: &Nullifier) -> Result<Option<(String, Vec<u8>)>, StorageError> {
+        let ballots = self.ballots.lock().unwrap();
+        Ok(ballots.get(proposal)
+            .and_then(|ballots| ballots.iter().find(|ballot| ballot.record.nullifier == *nullifier))
+            .map(|ballot| (ballot.vote.clone(), ballot.proof.clone())))
+    }
+
+    fn tally(&self, proposal: &str) -> Result<Option<Tally>, StorageError> {
+        Ok(self.tallies.lock().unwrap().get(proposal).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stacks::{proposals::Eligibility, tally::Weighting};
+
+    fn proposal() -> Proposal {
+        Proposal {
+            id: "p".to_string(),
+            snapshot_height: 1,
+            start_height: 1,
+            end_height: 2,
+            options: vec!["for".to_string(), "against".to_string()],
+            ballot_format: Default::default(),
+            eligibility: Eligibility::Balance,
+            weighting: Weighting::VotingPower,
+        }
+    }
+
+    fn ballot(nullifier: u8, power: u128) -> StoredBallot {
+        let proof = vec![nullifier; 4];
+        StoredBallot {
+            proposal: "p".to_string(),
+            vote: "for".to_string(),
+            power,
+            record: BallotRecord::new(&proof, [nullifier; 32], [0; 32]),
+            history: [nullifier; 32],
+            proof,
+        }
+    }
+
+    // Adds the ballot's power to the first option's total.
+    fn count(ballot: &StoredBallot) -> impl FnMut(Option<Tally>, &Votes) -> Result<Tally, StorageError> + '_ {
+        |saved, votes| {
+            let mut tally = saved.unwrap_or_else(|| Tally::new(&proposal()));
+            tally.ballots = votes()?.len() as u64;
+            tally.totals[0].total += ballot.power;
+            Ok(tally)
+        }
+    }
+
+    fn storages() -> Vec<Box<dyn Storage>> {
+        vec![Box::new(MemoryStorage::new()), Box::new(SqliteStorage::open_in_memory().unwrap())]
+    }
+
+    #[test]
+    fn cast_spends_stores_and_counts() {
+        for storage in storages() {
+            let first = ballot(1, 10);
+            assert!(storage.cast(&first, Some(&mut count(&first))).unwrap());
+            let second = ballot(2, 5);
+            assert!(storage.cast(&second, Some(&mut count(&second))).unwrap());
+
+            assert!(storage.is_spent("p", &[1; 32]).unwrap());
+            assert!(!storage.is_spent("q", &[1; 32]).unwrap());
+            assert_eq!(storage.ballots("p").unwrap(), vec![first.record, second.record]);
+            assert_eq!(storage.votes("p").unwrap(), vec![("for".to_string(), 10), ("for".to_string(), 5)]);
+            assert_eq!(storage.history("p", &[2; 32]).unwrap(), Some([2; 32]));
+            assert_eq!(storage.proof("p", &[2; 32]).unwrap(), Some((second.vote.clone(), second.proof.clone())));
+            let tally = storage.tally("p").unwrap().unwrap();
+            assert_eq!((tally.ballots, tally.totals[0].total), (2, 15));
+        }
+    }
+
+    #[test]
+    fn the_first_nullifier_key_is_kept() {
+        for storage in storages() {
+            assert_eq!(storage.nullifier_key([1; 32]).unwrap(), [1; 32]);
+            assert_eq!(storage.nullifier_key([2; 32]).unwrap(), [1; 32]);
+        }
+    }
+
+    #[test]
+    fn cast_rejects_a_spent_nullifier() {
+        for storage in storages() {
+            let first = ballot(1, 10);
+            assert!(storage.cast(&first, Some(&mut count(&first))).unwrap());
+            let again = ballot(1, 7);
+            assert!(!storage.cast(&again, Some(&mut count(&again))).unwrap());
+
+            assert_eq!(storage.ballots("p").unwrap(), vec![first.record]);
+            assert_eq!(storage.tally("p").unwrap().unwrap().totals[0].total, 10);
+        }
+    }
+
+    #[test]
+    fn cast_keeps_nothing_when_counting_fails() {
+        for storage in storages() {
+            let failing = ballot(1, 10);
+            let result = storage.cast(&failing, Some(&mut |_, _: &Votes| Err(StorageError::Corrupt("vote".to_string()))));
+            assert!(matches!(result, Err(StorageError::Corrupt(_))));
+
+            assert!(!storage.is_spent("p", &[1; 32]).unwrap());
+            assert!(storage.ballots("p").unwrap().is_empty());
+            assert!(storage.tally("p").unwrap().is_none());
+            // the voter can cast the ballot again
+            assert!(storage.cast(&failing, Some(&mut count(&failing))).unwrap());
+        }
+    }
+
+    #[test]
+    fn sqlite_storage_survives_reopening() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("votes.db");
+        let first = ballot(1, 10);
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            assert!(storage.insert_proposal(&proposal()).unwrap());
+            assert!(storage.cast(&first, Some(&mut count(&first))).unwrap());
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.proposals().unwrap(), vec![proposal()]);
+        assert!(!storage.insert_proposal(&proposal()).unwrap());
+        assert!(storage.is_spent("p", &[1; 32]).unwrap());
+        assert_eq!(storage.ballots("p").unwrap(), vec![first.record]);
+        assert_eq!(storage.history("p", &[1; 32]).unwrap(), Some([1; 32]));
+        assert_eq!(storage.proof("p", &[1; 32]).unwrap(), Some((first.vote.clone(), first.proof.clone())));
+        assert_eq!(storage.tally("p").unwrap().unwrap().totals[0].total, 10);
+        // a restart doesn't let the voter cast the ballot again
+        assert!(!storage.cast(&first, Some(&mut count(&first))).unwrap());
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("votes.db");
+        drop(SqliteStorage::open(&path).unwrap());
+
+        // reopening doesn't run the migrations again, which would fail on the existing tables
+        let storage = SqliteStorage::open(&path).unwrap();
+        let connection = storage.connection.lock().unwrap();
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        // every ballot has a history commitment
+        let missing_history = connection.execute(
+            "INSERT INTO ballots (proposal, nullifier, proof_hash, commitment, vote, power, proof)
+             VALUES ('p', ?1, ?2, ?3, 'for', '10', ?4)",
+            params![[1u8; 32], [2u8; 32], [3u8; 32], vec![4u8]],
+        );
+        assert!(missing_history.is_err());
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("votes.db");
+        Connection::open(&path).unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(matches!(SqliteStorage::open(&path), Err(StorageError::Corrupt(_))));
+    }
+}
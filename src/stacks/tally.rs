@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use warp::Filter;
+
+use crate::{
+    proofs::{ApplicationResponseMessage, ResponseEnvelope},
+    state::{AppState, PeerMap},
+};
+
+use super::{
+    ballots::{Ballot, BallotFormat},
+    proposals::{Proposal, ProposalError},
+    storage::{Storage, StorageError, StoredBallot},
+};
+
+// Tallies
+//
+// Every ballot whose proof a job generated, and whose nullifier was spent, is counted towards
+// its vote. Proposals either weigh ballots by the voting power they prove, or count one per
+// nullifier. Threshold and NFT proofs only reveal a lower bound, so that bound is their voting
+// power; quadratic proofs count with the square root of the balance they prove. The totals
+// can be polled over HTTP, and every WebSocket peer is sent the new tally of a proposal each
+// time a ballot is counted. Tallies are kept in storage next to the ballots.
+//
+// Single choice ballots count towards their option and approval ballots towards each option
+// they approve. Ranked choice ballots count towards their first preference in the totals, and
+// are also counted by instant-runoff: each round, every ballot counts towards its most
+// preferred option still standing, and the option with the lowest total is eliminated until
+// one holds a majority of the ballots still counting. Ties for the lowest total eliminate the
+// option listed last in the proposal.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Weighting {
+    #[default]
+    VotingPower,
+    OnePerBallot,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OptionTotal {
+    pub option: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub total: u128,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RunoffRound {
+    // the options still standing, in the order of the proposal's options
+    pub totals: Vec<OptionTotal>,
+    // voting power of the ballots ranking none of the options still standing
+    #[serde_as(as = "DisplayFromStr")]
+    pub exhausted: u128,
+    // the option eliminated after the round; the last round has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eliminated: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tally {
+    pub proposal: String,
+    pub weighting: Weighting,
+    #[serde(default)]
+    pub ballot_format: BallotFormat,
+    // ballots counted so far
+    pub ballots: u64,
+    // in the order of the proposal's options; first preferences for ranked choice ballots
+    pub totals: Vec<OptionTotal>,
+    // the instant-runoff rounds and their winner, for ranked choice proposals
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rounds: Vec<RunoffRound>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<String>,
+}
+
+impl Tally {
+    // The tally of a proposal no ballot was counted for yet.
+    pub fn new(proposal: &Proposal) -> Self {
+        Tally {
+            proposal: proposal.id.clone(),
+            weighting: proposal.weighting,
+            ballot_format: proposal.ballot_format,
+            ballots: 0,
+            totals: proposal.options.iter()
+                .map(|option| OptionTotal { option: option.clone(), total: 0 })
+                .collect(),
+            rounds: Vec::new(),
+            winner: None,
+        }
+    }
+
+    fn weight(&self, power: u128) -> u128 {
+        match self.weighting {
+            Weighting::VotingPower => power,
+            Weighting::OnePerBallot => 1,
+        }
+    }
+
+    fn count(&mut self, ballot: &Ballot, power: u128) {
+        let weight = self.weight(power);
+        let options = match ballot {
+            Ballot::RankedChoice(ranking) => &ranking[..1],
+            _ => ballot.options(),
+        };
+        for &option in options {
+            self.totals[option].total = self.totals[option].total.saturating_add(weight);
+        }
+        self.ballots += 1;
+    }
+
+    // Recounts the rounds over every ranked choice ballot cast on the proposal.
+    fn runoff(&mut self, ballots: &[(Ballot, u128)]) {
+        self.rounds.clear();
+        self.winner = None;
+        if ballots.is_empty() {
+            return;
+        }
+        let mut standing = vec![true; self.totals.len()];
+        loop {
+            let mut totals = vec![0u128; standing.len()];
+            let mut exhausted = 0u128;
+            for (ballot, power) in ballots {
+                let weight = self.weight(*power);
+                match ballot.options().iter().find(|&&option| standing[option]) {
+                    Some(&option) => totals[option] = totals[option].saturating_add(weight),
+                    None => exhausted = exhausted.saturating_add(weight),
+                }
+            }
+            let remaining: Vec<usize> = (0..standing.len()).filter(|&option| standing[option]).collect();
+            let counting = remaining.iter().fold(0u128, |sum, &option| sum.saturating_add(totals[option]));
+            // the leader never loses its ballots, so some are always counting; ties for the
+            // lead go to the option listed first, ties for the lowest total to the one listed last
+            let leader = *remaining.iter().rev().max_by_key(|&&option| totals[option]).unwrap();
+            let eliminated = (totals[leader] <= counting - totals[leader])
+                .then(|| *remaining.iter().rev().min_by_key(|&&option| totals[option]).unwrap())
+                .filter(|&option| option != leader);
+            self.rounds.push(RunoffRound {
+                totals: remaining.iter()
+                    .map(|&option| OptionTotal { option: self.totals[option].option.clone(), total: totals[option] })
+                    .collect(),
+                exhausted,
+                eliminated: eliminated.map(|option| self.totals[option].option.clone()),
+            });
+            match eliminated {
+                Some(option) => standing[option] = false,
+                None => return self.winner = Some(self.totals[leader].option.clone()),
+            }
+        }
+    }
+}
+
+pub struct Tallies {
+    storage: Arc<dyn Storage>,
+    peers: PeerMap,
+}
+
+impl Tallies {
+    pub fn new(storage: Arc<dyn Storage>, peers: PeerMap) -> Self {
+        Tallies {
+            storage,
+            peers,
+        }
+    }
+
+    // Casts a proven ballot, counting it when its proposal is registered, and pushes the
+    // proposal's new tally to every peer. Returns false if the ballot's nullifier was already
+    // spent.
+    pub fn cast(&self, proposal: Option<&Proposal>, ballot: &StoredBallot) -> Result<bool, StorageError> {
+        let proposal = match proposal {
+            Some(proposal) => proposal,
+            None => return self.storage.cast(ballot, None),
+        };
+        let mut counted = None;
+        let cast = self.storage.cast(ballot, Some(&mut |saved, votes| {
+            let mut tally = saved.unwrap_or_else(|| Tally::new(proposal));
+            tally.count(&parse_vote(proposal, &ballot.vote)?, ballot.power);
+            if proposal.ballot_format == BallotFormat::RankedChoice {
+                let ballots = votes()?.into_iter()
+                    .map(|(vote, power)| Ok((parse_vote(proposal, &vote)?, power)))
+                    .collect::<Result<Vec<_>, StorageError>>()?;
+                tally.runoff(&ballots);
+            }
+            counted = Some(tally.clone());
+            Ok(tally)
+        }))?;
+        if let Some(tally) = counted.filter(|_| cast) {
+            self.broadcast(tally);
+        }
+        Ok(cast)
+    }
+
+    pub fn tally(&self, proposal: &Proposal) -> Result<Tally, StorageError> {
+        Ok(self.storage.tally(&proposal.id)?.unwrap_or_else(|| Tally::new(proposal)))
+    }
+
+    fn broadcast(&self, tally: Tally) {
+        let envelope = ResponseEnvelope {
+            request_id: None,
+            response: ApplicationResponseMessage::TallyUpdate(tally),
+        };
+        let message = match serde_json::to_string(&envelope) {
+            Ok(message) => message,
+            Err(e) => return eprintln!("Error serializing tally update: {}", e),
+        };
+        for peer in self.peers.lock().unwrap().values() {
+            let _ = peer.unbounded_send(Message::Text(message.clone()));
+        }
+    }
+}
+
+// Votes were checked against the proposal when their ballots were submitted.
+fn parse_vote(proposal: &Proposal, vote: &str) -> Result<Ballot, StorageError> {
+    Ballot::parse(proposal.ballot_format, vote, &proposal.options)
+        .map_err(|e| StorageError::Corrupt(format!("ballot on {}: {}", proposal.id, e)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TallyQuery {
+    pub proposal: String,
+}
+
+pub fn tally_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("tally")
+        .and(warp::get())
+        .and(warp::query::<TallyQuery>())
+        .and_then(move |query: TallyQuery| {
+            let state = state.clone();
+            async move {
+                let proposal = state.proposals.get(&query.proposal)
+                    .map_err(warp::reject::custom)?
+                    .ok_or_else(|| warp::reject::custom(ProposalError::Unknown(query.proposal.clone())))?;
+                let tally = state.tallies.tally(&proposal).map_err(warp::reject::custom)?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&tally))
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stacks::{batches::BallotRecord, proposals::Eligibility, storage::MemoryStorage};
+
+    fn proposal(options: &[&str], ballot_format: BallotFormat, weighting: Weighting) -> Proposal {
+        Proposal {
+            id: "p".to_string(),
+            snapshot_height: 1,
+            start_height: 1,
+            end_height: 2,
+            options: options.iter().map(|option| option.to_string()).collect(),
+            ballot_format,
+            eligibility: Eligibility::Balance,
+            weighting,
+        }
+    }
+
+    // Counts the votes, each with its voting power, as the tallies do when they are cast.
+    fn counted(proposal: &Proposal, votes: &[(&str, u128)]) -> Tally {
+        let mut tally = Tally::new(proposal);
+        let ballots: Vec<(Ballot, u128)> = votes.iter()
+            .map(|&(vote, power)| (parse_vote(proposal, vote).unwrap(), power))
+            .collect();
+        for (ballot, power) in &ballots {
+            tally.count(ballot, *power);
+        }
+        if proposal.ballot_format == BallotFormat::RankedChoice {
+            tally.runoff(&ballots);
+        }
+        tally
+    }
+
+    fn totals(totals: &[OptionTotal]) -> Vec<(&str, u128)> {
+        totals.iter().map(|total| (total.option.as_str(), total.total)).collect()
+    }
+
+    fn repeat(vote: &str, times: usize) -> Vec<(&str, u128)> {
+        vec![(vote, 1); times]
+    }
+
+    #[test]
+    fn instant_runoff_eliminates_until_a_majority() {
+        let proposal = proposal(&["a", "b", "c", "d"], BallotFormat::RankedChoice, Weighting::OnePerBallot);
+        let votes = [repeat("0", 4), repeat("1,2", 3), repeat("2,1", 2), repeat("3", 1)].concat();
+        let tally = counted(&proposal, &votes);
+
+        assert_eq!(tally.ballots, 10);
+        assert_eq!(totals(&tally.totals), vec![("a", 4), ("b", 3), ("c", 2), ("d", 1)]);
+        let rounds: Vec<_> = tally.rounds.iter()
+            .map(|round| (totals(&round.totals), round.exhausted, round.eliminated.as_deref()))
+            .collect();
+        assert_eq!(rounds, vec![
+            (vec![("a", 4), ("b", 3), ("c", 2), ("d", 1)], 0, Some("d")),
+            // the ballot ranking only d is exhausted
+            (vec![("a", 4), ("b", 3), ("c", 2)], 1, Some("c")),
+            // c's ballots move to their second preference
+            (vec![("a", 4), ("b", 5)], 1, None),
+        ]);
+        assert_eq!(tally.winner.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn instant_runoff_weighs_ballots_by_voting_power() {
+        let proposal = proposal(&["a", "b", "c"], BallotFormat::RankedChoice, Weighting::VotingPower);
+        let tally = counted(&proposal, &[("0", 40), ("1,0", 35), ("2,1", 30)]);
+
+        assert_eq!(totals(&tally.totals), vec![("a", 40), ("b", 35), ("c", 30)]);
+        assert_eq!(tally.rounds.len(), 2);
+        assert_eq!(totals(&tally.rounds[1].totals), vec![("a", 40), ("b", 65)]);
+        assert_eq!(tally.winner.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn instant_runoff_breaks_ties_by_option_order() {
+        // b and c tie for the lowest total: the one listed last goes
+        let three = proposal(&["a", "b", "c"], BallotFormat::RankedChoice, Weighting::OnePerBallot);
+        let tally = counted(&three, &[("0", 1), ("0", 1), ("1", 1), ("2", 1)]);
+        assert_eq!(tally.rounds[0].eliminated.as_deref(), Some("c"));
+        assert_eq!(totals(&tally.rounds[1].totals), vec![("a", 2), ("b", 1)]);
+        assert_eq!(tally.rounds[1].exhausted, 1);
+        assert_eq!(tally.winner.as_deref(), Some("a"));
+
+        // a tie for the lead is no majority, and goes to the option listed first
+        let two = proposal(&["a", "b"], BallotFormat::RankedChoice, Weighting::OnePerBallot);
+        let tally = counted(&two, &[("1", 1), ("0", 1)]);
+        assert_eq!(tally.rounds[0].eliminated.as_deref(), Some("b"));
+        assert_eq!(tally.winner.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn instant_runoff_without_ballots_has_no_winner() {
+        let proposal = proposal(&["a", "b"], BallotFormat::RankedChoice, Weighting::OnePerBallot);
+        let tally = counted(&proposal, &[]);
+        assert!(tally.rounds.is_empty());
+        assert_eq!(tally.winner, None);
+    }
+
+    #[test]
+    fn approval_ballots_count_for_every_approved_option() {
+        let proposal = proposal(&["a", "b", "c"], BallotFormat::Approval, Weighting::VotingPower);
+        let tally = counted(&proposal, &[("0,2", 10), ("2", 5), ("0,1,2", 1)]);
+
+        assert_eq!(tally.ballots, 3);
+        assert_eq!(totals(&tally.totals), vec![("a", 11), ("b", 1), ("c", 16)]);
+        assert!(tally.rounds.is_empty());
+        assert_eq!(tally.winner, None);
+
+        let proposal = Proposal { weighting: Weighting::OnePerBallot, ..proposal };
+        let tally = counted(&proposal, &[("0,2", 10), ("2", 5), ("0,1,2", 1)]);
+        assert_eq!(totals(&tally.totals), vec![("a", 2), ("b", 1), ("c", 3)]);
+    }
+
+    #[test]
+    fn cast_ballots_are_counted_and_pushed_to_peers() {
+        let peers: PeerMap = Default::default();
+        let (sender, mut receiver) = futures_channel::mpsc::unbounded();
+        peers.lock().unwrap().insert("127.0.0.1:1".parse().unwrap(), sender);
+        let tallies = Tallies::new(Arc::new(MemoryStorage::new()), peers);
+        let proposal = proposal(&["for", "against"], BallotFormat::SingleChoice, Weighting::VotingPower);
+        let ballot = |nullifier: u8, vote: &str, power: u128| StoredBallot {
+            proposal: "p".to_string(),
+            vote: vote.to_string(),
+            power,
+            record: BallotRecord::new(&[nullifier], [nullifier; 32], [0; 32]),
+            history: [0; 32],
+            proof: vec![nullifier],
+        };
+
+        assert!(tallies.cast(Some(&proposal), &ballot(1, "for", 750)).unwrap());
+        assert!(tallies.cast(Some(&proposal), &ballot(2, "against", 5)).unwrap());
+        assert!(!tallies.cast(Some(&proposal), &ballot(1, "against", 750)).unwrap());
+        let tally = tallies.tally(&proposal).unwrap();
+        assert_eq!((tally.ballots, totals(&tally.totals)), (2, vec![("for", 750), ("against", 5)]));
+
+        // one update per counted ballot
+        let mut updates = Vec::new();
+        while let Ok(Some(message)) = receiver.try_next() {
+            updates.push(message.into_text().unwrap());
+        }
+        assert_eq!(updates.len(), 2);
+        assert!(updates.iter().all(|update| update.contains("TallyUpdate")));
+
+        // a vote the proposal doesn't take isn't cast at all
+        assert!(tallies.cast(Some(&proposal), &ballot(3, "abstain", 1)).is_err());
+        assert_eq!(tallies.tally(&proposal).unwrap().ballots, 2);
+    }
+}
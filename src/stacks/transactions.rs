@@ -1,27 +1,76 @@
-use warp::{reject::Reject, Filter};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use warp::Filter;
 
-use super::utils::fetch_all_transactions;
+use crate::state::AppState;
 
-#[derive(Debug)]
-pub struct TransactionFetchError {
-    pub message: String,
-}
-
-// Implement Reject for TransactionFetchError
-impl Reject for TransactionFetchError {}
+use super::{history::BalanceHistory, source::ReportedBalance};
 
-pub fn transactions_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("transactions")
+pub fn transactions_routes(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let transactions = warp::path("transactions")
         .and(warp::path::param::<String>())
-        .and_then(get_transactions)
+        .and(warp::any().map({
+            let state = state.clone();
+            move || state.clone()
+        }))
+        .and_then(get_transactions);
+
+    let balance = warp::path!("balance" / String / u64)
+        .and(warp::get())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(get_balance);
+
+    transactions.or(balance)
 }
 
-pub async fn get_transactions(address: String) -> Result<impl warp::Reply, warp::Rejection> {
-    match fetch_all_transactions(&address).await {
+pub async fn get_transactions(address: String, state: AppState) -> Result<impl warp::Reply, warp::Rejection> {
+    match state.transactions.fetch_transactions(&address).await {
         Ok(transactions) => Ok(warp::reply::json(&transactions)),
         Err(e) => {
             eprintln!("Error fetching or parsing response from : {:?}", e);
-            Err(warp::reject::not_found())
+            Err(warp::reject::custom(e))
         }
     }
-}
\ No newline at end of file
+}
+
+// The balance computed from an address's history next to the one the node reports.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceReport {
+    pub address: String,
+    pub height: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported: Option<ReportedBalance>,
+    // unset when the source doesn't report balances
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reconciled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn get_balance(address: String, height: u64, state: AppState) -> Result<impl warp::Reply, warp::Rejection> {
+    let transactions = state.transactions.fetch_transactions(&address).await;
+    let miner_rewards = state.transactions.fetch_miner_rewards(&address, height).await;
+    let reported = state.transactions.fetch_balance(&address, height).await;
+    let (transactions, miner_rewards, reported) = match (transactions, miner_rewards, reported) {
+        (Ok(transactions), Ok(miner_rewards), Ok(reported)) => (transactions, miner_rewards, reported),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            eprintln!("Error fetching the history of {}: {}", address, e);
+            return Err(warp::reject::custom(e));
+        }
+    };
+
+    let history = BalanceHistory::at_height(&address, &transactions, &miner_rewards, height)
+        .map_err(|e| warp::reject::custom(super::ProofError::new(&e.to_string())))?;
+    let reconciliation = reported.as_ref().map(|reported| history.reconcile(reported));
+    Ok(warp::reply::json(&BalanceReport {
+        address,
+        height,
+        balance: history.balance(),
+        reconciled: reconciliation.as_ref().map(Result::is_ok),
+        error: reconciliation.and_then(Result::err).map(|e| e.to_string()),
+        reported,
+    }))
+}
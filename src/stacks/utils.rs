@@ -1,4 +1,3 @@
-use reqwest::Error;
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use ripemd::Ripemd160;
@@ -7,17 +6,36 @@ use stacks_rs::crypto::c32_address;
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Transaction {
     pub tx: TransactionDetails,
+    // totals over the transaction, fee included; only used when `stx_events` wasn't fetched
     pub stx_sent: String,
     pub stx_received: String,
+    // how many events of each kind the transaction emitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events: Option<Event>,
+    // the STX events involving the address, when the source fetched them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stx_events: Option<Vec<StxBalanceEvent>>,
+    // the fungible token events involving the address, when the source fetched them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ft_events: Option<Vec<FtBalanceEvent>>,
+    // the non-fungible token events involving the address, when the source fetched them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nft_events: Option<Vec<NftOwnershipEvent>>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TransactionDetails {
     pub tx_id: String,
     pub nonce: u64,
-    // fee_rate: String,
-    // sender_address: String,
-    // sponsored: bool,
+    // fee paid by the sender, or by the sponsor of a sponsored transaction
+    #[serde(default = "default_zero")]
+    pub fee_rate: String,
+    #[serde(default)]
+    pub sender_address: String,
+    #[serde(default)]
+    pub sponsored: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sponsor_address: Option<String>,
     // block_hash: String,
     pub block_height: u64,
     // block_time: u64,
@@ -27,42 +45,127 @@ pub struct TransactionDetails {
     // burn_block_time_iso: String,
     // parent_burn_block_time: u64,
     // parent_burn_block_time_iso: String,
-    // canonical: bool,
+    // false once the block was orphaned by a fork
+    #[serde(default = "default_true")]
+    pub canonical: bool,
     pub tx_index: u64,
     pub tx_status: String,
     // tx_result: TxResult,
     // event_count: u64,
     pub parent_block_hash: String,
-    // is_unanchored: bool,
+    // true for microblock transactions not yet confirmed by an anchor block
+    #[serde(default)]
+    pub is_unanchored: bool,
     // microblock_hash: String,
     // microblock_sequence: u64,
     // microblock_canonical: bool,
     pub tx_type: String,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_zero() -> String {
+    "0".to_string()
+}
+
+impl TransactionDetails {
+    // Mined in a block, whether it succeeded or aborted. Aborted transactions still pay their fee.
+    pub fn is_mined(&self) -> bool {
+        matches!(self.tx_status.as_str(), "success" | "abort_by_response" | "abort_by_post_condition")
+    }
+
+    // The account the fee is charged to.
+    pub fn fee_payer(&self) -> &str {
+        match (&self.sponsor_address, self.sponsored) {
+            (Some(sponsor), true) => sponsor,
+            _ => &self.sender_address,
+        }
+    }
+}
+
+// An STX movement emitted by a transaction. Amounts are in micro-STX.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StxBalanceEvent {
+    Transfer { sender: String, recipient: String, amount: String },
+    Mint { recipient: String, amount: String },
+    Burn { sender: String, amount: String },
+    // Stacking locks part of the balance until `unlock_height` (a burnchain height) without
+    // changing the balance itself; the STX unlock on their own at that height.
+    Lock { locked_address: String, locked_amount: String, unlock_height: u64 },
+}
+
+// A SIP-010 token movement emitted by a transaction. `asset_identifier` is the fully
+// qualified token name, `<contract principal>::<token name>`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FtBalanceEvent {
+    Transfer { asset_identifier: String, sender: String, recipient: String, amount: String },
+    Mint { asset_identifier: String, recipient: String, amount: String },
+    Burn { asset_identifier: String, sender: String, amount: String },
+}
+
+impl FtBalanceEvent {
+    pub fn asset_identifier(&self) -> &str {
+        match self {
+            FtBalanceEvent::Transfer { asset_identifier, .. }
+            | FtBalanceEvent::Mint { asset_identifier, .. }
+            | FtBalanceEvent::Burn { asset_identifier, .. } => asset_identifier,
+        }
+    }
+}
+
+// A SIP-009 token changing hands. `value` is the Clarity representation of the token id.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NftOwnershipEvent {
+    Transfer { asset_identifier: String, sender: String, recipient: String, value: String },
+    Mint { asset_identifier: String, recipient: String, value: String },
+    Burn { asset_identifier: String, sender: String, value: String },
+}
+
+impl NftOwnershipEvent {
+    pub fn asset_identifier(&self) -> &str {
+        match self {
+            NftOwnershipEvent::Transfer { asset_identifier, .. }
+            | NftOwnershipEvent::Mint { asset_identifier, .. }
+            | NftOwnershipEvent::Burn { asset_identifier, .. } => asset_identifier,
+        }
+    }
+}
+
+// A matured coinbase reward credited to a miner. These aren't transaction events.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MinerReward {
+    pub block_height: u64,
+    pub amount: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TxResult {
     hex: String,
     repr: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Event {
-    stx: StxEvent,
-    ft: FtEvent,
-    nft: FtEvent
+    pub stx: StxEvent,
+    pub ft: FtEvent,
+    pub nft: FtEvent
 }
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct StxEvent {
-    transfer: u16,
-    mint: u16,
-    burn: u16
+    pub transfer: u16,
+    pub mint: u16,
+    pub burn: u16
 }
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct FtEvent {
-    transfer: u16,
-    mint: u16,
-    burn: u16
+    pub transfer: u16,
+    pub mint: u16,
+    pub burn: u16
 }
 #[derive(Deserialize, Serialize, Debug)]
 pub struct NftEvent {
@@ -79,54 +182,15 @@ pub struct TokenTransfer {
     memo: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct ApiResponse {
-    results: Vec<Transaction>,
-    total: u64,
-    limit: u64,
-    offset: u64,
-}
-
-const BASE_URL: &str = "https://api.hiro.so/extended/v2/addresses";
-
-/// Fetches all transactions for a given Stacks address
-pub async fn fetch_all_transactions(address: &str) -> Result<Vec<Transaction>, Error> {
-    let mut all_transactions: Vec<Transaction> = Vec::new();
-    let mut offset: u64 = 0;
-    let limit = 20;
-
-    loop {
-        // Construct the URL with the current offset and limit
-        let url = format!("{}/{}/transactions?limit={}&offset={}", BASE_URL, address, limit, offset);
-
-        // Send the request
-        // let response: ApiResponse = reqwest::get(&url).await?.json().await?;
-        let response = match reqwest::get(&url).await?.json::<ApiResponse>().await {
-            Ok(api_response) => api_response,
-            Err(e) => {
-                eprintln!("Error fetching or parsing response from {}: {:?}", url, e);
-                break; // Stop the loop if there's an error
-            }
-        };
-
-        // Add the fetched transactions to the total list
-
-        if response.results.is_empty() {
-            break;
-        }
-
-        all_transactions.extend(response.results);
-
-        // Update offset for the next page
-        offset += limit;
-
-    }
-
-    Ok(all_transactions)
+// Decodes a hex encoded 32 byte value, such as a hash or a nullifier; a 0x prefix is allowed.
+pub fn decode_hex32(value: &str) -> Option<[u8; 32]> {
+    hex::decode(value.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
 }
 
-
-pub fn public_key_to_stacks_address(public_key_hex: String) -> Result<String, Box<dyn std::error::Error>> {
+// `version` is the c32 address version of the network, see Network::single_sig_version.
+pub fn public_key_to_stacks_address(public_key_hex: String, version: u8) -> Result<String, Box<dyn std::error::Error>> {
     // Step 1: Decode the hex string of the public key
     let public_key_bytes = hex::decode(public_key_hex)?;
     // Step 2: Hash the public key with SHA-256
@@ -135,11 +199,9 @@ pub fn public_key_to_stacks_address(public_key_hex: String) -> Result<String, Bo
     let mut hasher = Ripemd160::new();
     hasher.update(sha256_hash);
     let ripemd160_hash = hasher.finalize();
-    // Step 4: Convert the hash to a C32Check Stacks address (version 22 for mainnet)
-    let version_byte = 22; // 22 is the mainnet version byte for Stacks addresses
+    // Step 4: Convert the hash to a C32Check Stacks address
     let ripemd160_hash_array: [u8; 20] = ripemd160_hash.into();
 
-    let stacks_address = c32_address(ripemd160_hash_array, version_byte)?;
-    println!("Stacks address: {}", stacks_address);
+    let stacks_address = c32_address(ripemd160_hash_array, version)?;
     Ok(stacks_address)
 }
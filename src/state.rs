@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures_channel::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::stacks::{
+    batches::BallotBatches,
+    jobs::JobQueue,
+    nullifiers::NullifierRegistry,
+    proposals::ProposalRegistry,
+    network::NetworkConfig,
+    source::{source_from_env, TransactionSource},
+    storage::{storage_from_env, Storage},
+    tally::Tallies,
+};
+
+pub type PeerMap = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>;
+
+// Environment variable setting how many proofs can be generated at the same time.
+pub const PROOF_WORKERS_ENV: &str = "PROOF_WORKERS";
+
+// Environment variable naming the SIP-010 token votes can be weighted by, as a fully
+// qualified asset identifier (`<contract principal>::<token name>`).
+pub const GOVERNANCE_ASSET_ENV: &str = "GOVERNANCE_ASSET";
+
+// Environment variable naming the SIP-009 collection for one-NFT-one-vote governance, as a
+// fully qualified asset identifier.
+pub const GOVERNANCE_COLLECTION_ENV: &str = "GOVERNANCE_COLLECTION";
+
+// Environment variable with the key `POST /stacks/proposals` requires, as a bearer token.
+// Without it proposals can't be registered.
+pub const ADMIN_KEY_ENV: &str = "ADMIN_KEY";
+
+// State shared by the HTTP routes and the WebSocket handlers.
+#[derive(Clone)]
+pub struct AppState {
+    pub network: NetworkConfig,
+    pub nullifiers: Arc<NullifierRegistry>,
+    pub jobs: Arc<JobQueue>,
+    // the ballots cast on each proposal, committed to by Merkle trees
+    pub batches: Arc<BallotBatches>,
+    // the proposals ballots are accepted for
+    pub proposals: Arc<ProposalRegistry>,
+    // running totals of each proposal's votes
+    pub tallies: Arc<Tallies>,
+    pub transactions: Arc<dyn TransactionSource>,
+    // proposals, spent nullifiers, ballots and tallies
+    pub storage: Arc<dyn Storage>,
+    // the token balance proofs are generated for, if any
+    pub governance_asset: Option<String>,
+    // the NFT collection ownership proofs are generated for, if any
+    pub governance_collection: Option<String>,
+    // the key proposals are registered with, if any
+    pub admin_key: Option<String>,
+}
+
+impl AppState {
+    pub fn new(
+        network: NetworkConfig,
+        nullifiers: NullifierRegistry,
+        transactions: Arc<dyn TransactionSource>,
+        storage: Arc<dyn Storage>,
+        workers: usize,
+        peers: PeerMap,
+    ) -> Self {
+        AppState {
+            network,
+            nullifiers: Arc::new(nullifiers),
+            jobs: Arc::new(JobQueue::new(workers, peers.clone())),
+            batches: Arc::new(BallotBatches::new(storage.clone())),
+            proposals: Arc::new(ProposalRegistry::new(storage.clone())),
+            tallies: Arc::new(Tallies::new(storage.clone(), peers)),
+            transactions,
+            storage,
+            governance_asset: None,
+            governance_collection: None,
+            admin_key: None,
+        }
+    }
+
+    pub fn with_governance_asset(mut self, asset_identifier: &str) -> Self {
+        self.governance_asset = Some(asset_identifier.to_string());
+        self
+    }
+
+    pub fn with_governance_collection(mut self, asset_identifier: &str) -> Self {
+        self.governance_collection = Some(asset_identifier.to_string());
+        self
+    }
+
+    pub fn with_admin_key(mut self, admin_key: &str) -> Self {
+        self.admin_key = Some(admin_key.to_string());
+        self
+    }
+
+    pub fn from_env(peers: PeerMap) -> Self {
+        // proving is CPU bound, so by default there is one worker per core
+        let workers = std::env::var(PROOF_WORKERS_ENV)
+            .ok()
+            .and_then(|workers| workers.parse().ok())
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        let network = NetworkConfig::from_env();
+        println!("Using the Stacks {} network at {}", network.network, network.api_url);
+        let transactions = source_from_env(&network).into();
+        let storage: Arc<dyn Storage> = storage_from_env().expect("Failed to open storage").into();
+        let nullifiers = NullifierRegistry::from_env(storage.as_ref()).expect("Failed to load the nullifier key");
+        let mut state = AppState::new(network, nullifiers, transactions, storage, workers, peers);
+        if let Ok(asset_identifier) = std::env::var(GOVERNANCE_ASSET_ENV) {
+            println!("Proving balances of {}", asset_identifier);
+            state = state.with_governance_asset(&asset_identifier);
+        }
+        if let Ok(asset_identifier) = std::env::var(GOVERNANCE_COLLECTION_ENV) {
+            println!("Proving ownership of {}", asset_identifier);
+            state = state.with_governance_collection(&asset_identifier);
+        }
+        match std::env::var(ADMIN_KEY_ENV) {
+            Ok(admin_key) => state = state.with_admin_key(&admin_key),
+            Err(_) => eprintln!("{} is not set, proposals can't be registered", ADMIN_KEY_ENV),
+        }
+        state
+    }
+}
@@ -1,54 +1,168 @@
 use reqwest::Client;
+use secp256k1::SecretKey;
 use tokio::task;
-use warp::Filter;
 use serde_json::json;
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
 
-// Re-import your app's main route setup here
+use crate::{
+    proofs::stacks_voting::MessageInputs,
+    stacks::{
+        batches::{verify_inclusion, ReceiptResponse},
+        network::{Network, NetworkConfig},
+        nullifiers::NullifierRegistry,
+        source::{BlockInfo, MemorySource},
+        storage::SqliteStorage,
+        stacks_routes,
+        utils::public_key_to_stacks_address,
+    },
+    state::AppState,
+};
+
+use super::{sign_ballot, transaction};
+
+// Starts the HTTP routes on a free port, reading transactions from memory and storing votes
+// in a throwaway SQLite database.
+async fn start_server(transactions: Arc<MemorySource>) -> SocketAddr {
+    let state = AppState::new(NetworkConfig::new(Network::Mainnet), NullifierRegistry::new([1; 32]), transactions, Arc::new(SqliteStorage::open_in_memory().unwrap()), 1, Arc::new(Mutex::new(HashMap::new())))
+        .with_admin_key("admin");
+    let (addr, server) = warp::serve(stacks_routes(state)).bind_ephemeral(([127, 0, 0, 1], 0));
+    task::spawn(server);
+    addr
+}
 
 #[tokio::test]
 async fn test_generate_proof_endpoint() {
-    // Define the address for the server
-    let addr: SocketAddr = "127.0.0.1:3030".parse().unwrap();
+    let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+    let signature_data = sign_ballot(
+        MessageInputs {
+            message: "I vote in favor".to_string(),
+            vote: "for".to_string(),
+            proposal: "SIP-028".to_string(),
+            balance_at_height: 100,
+            block_proof_height: 50,
+            voting_end_height: 60,
+        },
+        &secret_key,
+    );
 
-    // Start the Warp server in a background task
-    let routes = stacks_routes();  // Load the stacks routes
-    task::spawn(async move {
-        warp::serve(routes).run(addr).await;
+    // The voter's history lives in memory, so no network access is needed.
+    let transactions = Arc::new(MemorySource::new());
+    let address = public_key_to_stacks_address(signature_data.public_key.clone(), Network::Mainnet.single_sig_version()).unwrap();
+    let history = vec![
+        transaction("0x01", 0, 1000, 10),
+        transaction("0x02", 300, 0, 20),
+        transaction("0x03", 0, 50, 30),
+    ];
+    transactions.insert(&address, history);
+    transactions.insert_block(BlockInfo {
+        height: 50,
+        index_block_hash: format!("0x{}", "ab".repeat(32)),
+        burn_block_height: 850,
+        canonical: true,
     });
+    let addr = start_server(transactions).await;
 
-    // Wait briefly for the server to start up
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-
-    // Define the client and request data
+    // Ballots are only taken for registered proposals, while voting is open.
     let client = Client::new();
-    let payload = json!({
-        "message_inputs": {
-            "message": "I vote in favor",
-            "vote": "for",
-            "proposal": "SIP-028",
-            "balance_at_height": 100,
-            "block_proof_height": 50,
-            "voting_end_height": 60
-        },
-        "public_key": "02abcd...",
-        "hash": "245172c...",
-        "signature": "db6eac1...",
-        "message": "Some signed message"
+    let proposal = json!({
+        "id": "SIP-028",
+        "snapshot_height": 50,
+        "start_height": 50,
+        "end_height": 60,
+        "options": ["for", "against"],
+        "ballot_format": "single_choice",
+        "eligibility": { "claim": "balance" },
+        "weighting": "voting_power",
     });
+    let res = client
+        .post(format!("http://{}/stacks/proposals", addr))
+        .bearer_auth("admin")
+        .json(&proposal)
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(res.status(), 201);
 
     // Make the POST request to the `/stacks/proof/generate` endpoint
     let res = client
-        .post("http://127.0.0.1:3030/stacks/proof/generate")
-        .json(&payload)
+        .post(format!("http://{}/stacks/proof/generate", addr))
+        .json(&signature_data)
         .send()
         .await
         .expect("Failed to send request");
-
-    // Check that the response is successful and contains the expected status
     assert_eq!(res.status(), 200);
 
-    // Parse the JSON response
     let response_json: serde_json::Value = res.json().await.expect("Invalid JSON response");
-    assert_eq!(response_json["status"], "proof generated");
+    let response = &response_json["ProofGenerationResponse"];
+    assert_eq!(response["proof_type"], "StacksVotingProof");
+    assert_eq!(response["result"], "750");
+    assert_eq!(response["block_hash"], "ab".repeat(32));
+
+    // The proof checks out against the ballot it was generated for.
+    let res = client
+        .post(format!("http://{}/stacks/proof/validate", addr))
+        .json(&json!({
+            "proof": response["proof"],
+            "public_inputs": {
+                "claim": "balance",
+                "value": "750",
+                "height": 50,
+                "block_hash": response["block_hash"],
+                "history": response["history"],
+                "commitment": response["commitment"],
+                "blinding": response["blinding"],
+                "nullifier": response["nullifier"],
+            },
+            "ballot": signature_data.message_inputs,
+        }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    let verdict: serde_json::Value = res.json().await.expect("Invalid JSON response");
+    assert_eq!(verdict["valid"], true, "{}", verdict);
+
+    // The ballot is in the proposal's batch, and its receipt leads to the batch root.
+    let nullifier = response["nullifier"].as_str().unwrap();
+    let res = client
+        .get(format!("http://{}/stacks/batch", addr))
+        .query(&[("proposal", "SIP-028"), ("nullifier", nullifier)])
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(res.status(), 200);
+    let receipt: ReceiptResponse = res.json().await.expect("Invalid JSON response");
+    assert_eq!(receipt.size, 1);
+    assert_eq!(receipt.receipt.commitment, response["commitment"]);
+    // the proof is only handed to the voter, who holds the commitment's blinding
+    assert_eq!(receipt.proof, None);
+    let res = client
+        .get(format!("http://{}/stacks/batch", addr))
+        .query(&[("proposal", "SIP-028"), ("nullifier", nullifier), ("blinding", &"00".repeat(32))])
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(res.status(), 403);
+    let res = client
+        .get(format!("http://{}/stacks/batch", addr))
+        .query(&[("proposal", "SIP-028"), ("nullifier", nullifier), ("blinding", response["blinding"].as_str().unwrap())])
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(res.status(), 200);
+    let receipt: ReceiptResponse = res.json().await.expect("Invalid JSON response");
+    assert_eq!(receipt.proof.as_deref(), response["proof"].as_str());
+    let root = <[u8; 32]>::try_from(hex::decode(&receipt.root).unwrap()).unwrap();
+    let leaf = <[u8; 32]>::try_from(hex::decode(&receipt.receipt.leaf).unwrap()).unwrap();
+    assert!(verify_inclusion(&root, &leaf, &receipt.receipt.path));
+
+    // The ballot counts towards its vote with the balance it proved.
+    let res = client
+        .get(format!("http://{}/stacks/tally", addr))
+        .query(&[("proposal", "SIP-028")])
+        .send()
+        .await
+        .expect("Failed to send request");
+    let tally: serde_json::Value = res.json().await.expect("Invalid JSON response");
+    assert_eq!(tally["ballots"], 1);
+    assert_eq!(tally["totals"], json!([{ "option": "for", "total": "750" }, { "option": "against", "total": "0" }]));
 }
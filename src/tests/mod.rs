@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+use crate::{
+    proofs::stacks_voting::{MessageInputs, SignatureData},
+    stacks::{
+        network::{Network, NetworkConfig},
+        nullifiers::NullifierRegistry,
+        proposals::{Eligibility, Proposal},
+        sip018::{default_domain, structured_data_hash},
+        source::{BlockInfo, MemorySource},
+        storage::MemoryStorage,
+        utils::{public_key_to_stacks_address, Transaction, TransactionDetails},
+    },
+    state::AppState,
+};
+
+mod integration_tests;
+
+// Helpers shared by the tests of the modules and the integration tests.
+
+// A successful STX transfer moving the given amounts, mined at `block_height`.
+pub fn transaction(tx_id: &str, stx_sent: u128, stx_received: u128, block_height: u64) -> Transaction {
+    Transaction {
+        stx_sent: stx_sent.to_string(),
+        stx_received: stx_received.to_string(),
+        tx: TransactionDetails {
+            tx_id: tx_id.to_string(),
+            nonce: 0,
+            block_height,
+            burn_block_height: 0,
+            tx_index: 0,
+            tx_status: "success".to_string(),
+            fee_rate: "0".to_string(),
+            sender_address: "".to_string(),
+            sponsored: false,
+            sponsor_address: None,
+            parent_block_hash: "".to_string(),
+            tx_type: "token_transfer".to_string(),
+            canonical: true,
+            is_unanchored: false,
+        },
+        events: None,
+        stx_events: None,
+        ft_events: None,
+        nft_events: None,
+    }
+}
+
+// Signs the ballot the way a wallet would, over its SIP-018 hash.
+pub fn sign_ballot(message_inputs: MessageInputs, secret_key: &SecretKey) -> SignatureData {
+    let secp = Secp256k1::new();
+    let hash = structured_data_hash(&default_domain(Network::Mainnet.chain_id()), &message_inputs).unwrap();
+    let (recovery_id, signature) = secp
+        .sign_ecdsa_recoverable(&Message::from_digest(hash), secret_key)
+        .serialize_compact();
+    let mut signature = signature.to_vec();
+    signature.push(recovery_id.to_i32() as u8);
+
+    SignatureData {
+        message_inputs,
+        public_key: hex::encode(PublicKey::from_secret_key(&secp, secret_key).serialize()),
+        hash: hex::encode(hash),
+        signature: hex::encode(signature),
+        message: "Some signed message".to_string(),
+        domain: None,
+    }
+}
+
+// The snapshot and the end of the voting window of the proposal `server` registers.
+pub const SNAPSHOT: u64 = 50;
+pub const END: u64 = 60;
+
+// A ballot on SIP-028 signed with the key [secret; 32].
+pub fn ballot(secret: u8, vote: &str, snapshot: u64) -> SignatureData {
+    let message_inputs = MessageInputs {
+        message: "I vote in favor".to_string(),
+        vote: vote.to_string(),
+        proposal: "SIP-028".to_string(),
+        balance_at_height: 750,
+        block_proof_height: snapshot,
+        voting_end_height: END,
+    };
+    sign_ballot(message_inputs, &SecretKey::from_slice(&[secret; 32]).unwrap())
+}
+
+// The mainnet address of the ballot's signer.
+pub fn address(signature_data: &SignatureData) -> String {
+    public_key_to_stacks_address(signature_data.public_key.clone(), Network::Mainnet.single_sig_version()).unwrap()
+}
+
+// A server taking ballots on SIP-028 with the chain tip at `tip`, where voter 7 holds 750
+// micro-STX at the snapshot.
+pub fn server(tip: u64) -> (AppState, Arc<MemorySource>) {
+    let source = Arc::new(MemorySource::new());
+    source.insert(&address(&ballot(7, "for", SNAPSHOT)), vec![
+        transaction("0x01", 0, 1000, 10),
+        transaction("0x02", 250, 0, 20),
+    ]);
+    for height in [SNAPSHOT, tip] {
+        source.insert_block(BlockInfo {
+            height,
+            index_block_hash: format!("0x{}", hex::encode([height as u8; 32])),
+            burn_block_height: 800 + height,
+            canonical: true,
+        });
+    }
+    let state = AppState::new(
+        NetworkConfig::new(Network::Mainnet),
+        NullifierRegistry::new([1; 32]),
+        source.clone(),
+        Arc::new(MemoryStorage::new()),
+        2,
+        Default::default(),
+    );
+    state.proposals.create(Proposal {
+        id: "SIP-028".to_string(),
+        snapshot_height: SNAPSHOT,
+        start_height: SNAPSHOT,
+        end_height: END,
+        options: vec!["for".to_string(), "against".to_string()],
+        ballot_format: Default::default(),
+        eligibility: Eligibility::Balance,
+        weighting: Default::default(),
+    }).unwrap();
+    (state, source)
+}